@@ -2,6 +2,8 @@ extern crate byteorder;
 extern crate log;
 extern crate uuid;
 
+pub mod mux;
+
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::Read;
 use std::io::{self, Write};
@@ -11,18 +13,37 @@ use uuid::Uuid;
 
 pub const JET_MSG_SIGNATURE: u32 = 0x0054_454A;
 pub const JET_MSG_HEADER_SIZE: u32 = 8;
-pub const JET_VERSION: u8 = 1;
+pub const JET_VERSION: u8 = 3;
+/// First version where the server accepts notifications (Jet-Method: PoolLow) on its connections.
+pub const JET_VERSION_NOTIFICATIONS: u8 = 2;
+/// First version where the server is told that a client took its connection (Jet-Method: Connected).
+pub const JET_VERSION_CONNECTED_NOTIFICATION: u8 = 3;
 
 const JET_HEADER_VERSION: &str = "Jet-Version";
 const JET_HEADER_METHOD: &str = "Jet-Method";
 const JET_HEADER_ASSOCIATION: &str = "Jet-Association";
 const JET_HEADER_TIMEOUT: &str = "Jet-Timeout";
 const JET_HEADER_INSTANCE: &str = "Jet-Instance";
-
+const JET_HEADER_POOL_REMAINING: &str = "Jet-Pool-Remaining";
+const JET_HEADER_POOL_TOKEN: &str = "Jet-Pool-Token";
+const JET_HEADER_RESUMABLE: &str = "Jet-Resumable";
+const JET_HEADER_RESUME_TOKEN: &str = "Jet-Resume-Token";
+const JET_HEADER_SEQUENCE: &str = "Jet-Sequence";
+const JET_HEADER_MULTIPLEXED: &str = "Jet-Multiplexed";
+const JET_HEADER_KEEPALIVE: &str = "Jet-Keepalive";
+const JET_HEADER_KEEPALIVE_INTERVAL: &str = "Jet-Keepalive-Interval";
+const JET_HEADER_CLIENT_ADDRESS: &str = "Jet-Client-Address";
+
+#[allow(non_camel_case_types)]
 #[derive(Debug, PartialEq, Clone)]
 pub enum JetMethod {
     ACCEPT,
     CONNECT,
+    POOL_LOW,
+    RESUME,
+    PING,
+    PONG,
+    CONNECTED,
 }
 
 impl FromStr for JetMethod {
@@ -32,8 +53,13 @@ impl FromStr for JetMethod {
         match s {
             "Accept" => Ok(JetMethod::ACCEPT),
             "Connect" => Ok(JetMethod::CONNECT),
+            "PoolLow" => Ok(JetMethod::POOL_LOW),
+            "Resume" => Ok(JetMethod::RESUME),
+            "Ping" => Ok(JetMethod::PING),
+            "Pong" => Ok(JetMethod::PONG),
+            "Connected" => Ok(JetMethod::CONNECTED),
             _ => Err(error_other(&format!(
-                "JetMethod: Wrong value ({}). Only Accept and Connect are accepted",
+                "JetMethod: Wrong value ({}). Only Accept, Connect, PoolLow, Resume, Ping, Pong and Connected are accepted",
                 s
             ))),
         }
@@ -45,14 +71,20 @@ impl ToString for JetMethod {
         match self {
             JetMethod::ACCEPT => "Accept".to_string(),
             JetMethod::CONNECT => "Connect".to_string(),
+            JetMethod::POOL_LOW => "PoolLow".to_string(),
+            JetMethod::RESUME => "Resume".to_string(),
+            JetMethod::PING => "Ping".to_string(),
+            JetMethod::PONG => "Pong".to_string(),
+            JetMethod::CONNECTED => "Connected".to_string(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ResponseStatusCode {
     StatusCode200,
     StatusCode400,
+    StatusCode408,
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +97,26 @@ pub struct JetPacket {
     association: Option<Uuid>,
     timeout: Option<u32>,
     instance: Option<String>,
+    pool_remaining: Option<u32>,
+    pool_token: Option<Uuid>,
+    resumable: Option<bool>,
+    resume_token: Option<Uuid>,
+    sequence: Option<u64>,
+    multiplexed: Option<bool>,
+    keepalive: Option<bool>,
+    keepalive_interval: Option<u32>,
+    client_address: Option<String>,
+}
+
+impl ResponseStatusCode {
+    fn from_status_line(line: &str) -> Option<Self> {
+        match line.split(' ').nth(1) {
+            Some("200") => Some(ResponseStatusCode::StatusCode200),
+            Some("400") => Some(ResponseStatusCode::StatusCode400),
+            Some("408") => Some(ResponseStatusCode::StatusCode408),
+            _ => None,
+        }
+    }
 }
 
 impl JetPacket {
@@ -78,6 +130,15 @@ impl JetPacket {
             timeout: None,
             response_status_code: None,
             instance: None,
+            pool_remaining: None,
+            pool_token: None,
+            resumable: None,
+            resume_token: None,
+            sequence: None,
+            multiplexed: None,
+            keepalive: None,
+            keepalive_interval: None,
+            client_address: None,
         }
     }
 
@@ -91,6 +152,15 @@ impl JetPacket {
             association: None,
             timeout: None,
             instance: None,
+            pool_remaining: None,
+            pool_token: None,
+            resumable: None,
+            resume_token: None,
+            sequence: None,
+            multiplexed: None,
+            keepalive: None,
+            keepalive_interval: None,
+            client_address: None,
         }
     }
 
@@ -102,6 +172,18 @@ impl JetPacket {
         self.mask
     }
 
+    pub fn response_status_code(&self) -> Option<&ResponseStatusCode> {
+        self.response_status_code.as_ref()
+    }
+
+    pub fn version(&self) -> Option<u8> {
+        self.version
+    }
+
+    pub fn method(&self) -> Option<&JetMethod> {
+        self.method.as_ref()
+    }
+
     pub fn is_accept(&self) -> bool {
         self.method == Some(JetMethod::ACCEPT)
     }
@@ -110,14 +192,34 @@ impl JetPacket {
         self.method == Some(JetMethod::CONNECT)
     }
 
+    pub fn is_resume(&self) -> bool {
+        self.method == Some(JetMethod::RESUME)
+    }
+
+    pub fn is_ping(&self) -> bool {
+        self.method == Some(JetMethod::PING)
+    }
+
+    pub fn is_pong(&self) -> bool {
+        self.method == Some(JetMethod::PONG)
+    }
+
     pub fn association(&self) -> Option<Uuid> {
         self.association
     }
 
+    pub fn timeout(&self) -> Option<u32> {
+        self.timeout
+    }
+
     pub fn set_association(&mut self, association: Option<Uuid>) {
         self.association = association;
     }
 
+    pub fn jet_instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
     pub fn set_jet_instance(&mut self, instance: Option<String>) {
         self.instance = instance;
     }
@@ -134,6 +236,84 @@ impl JetPacket {
         self.version = version;
     }
 
+    pub fn pool_remaining(&self) -> Option<u32> {
+        self.pool_remaining
+    }
+
+    pub fn set_pool_remaining(&mut self, pool_remaining: Option<u32>) {
+        self.pool_remaining = pool_remaining;
+    }
+
+    /// Token given to the server that created an association, required to add connections to its pool.
+    pub fn pool_token(&self) -> Option<Uuid> {
+        self.pool_token
+    }
+
+    pub fn set_pool_token(&mut self, pool_token: Option<Uuid>) {
+        self.pool_token = pool_token;
+    }
+
+    pub fn resumable(&self) -> bool {
+        self.resumable.unwrap_or(false)
+    }
+
+    pub fn set_resumable(&mut self, resumable: Option<bool>) {
+        self.resumable = resumable;
+    }
+
+    pub fn resume_token(&self) -> Option<Uuid> {
+        self.resume_token
+    }
+
+    pub fn set_resume_token(&mut self, resume_token: Option<Uuid>) {
+        self.resume_token = resume_token;
+    }
+
+    /// Number of bytes received by the sender of the packet on the session being resumed.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    pub fn set_sequence(&mut self, sequence: Option<u64>) {
+        self.sequence = sequence;
+    }
+
+    /// The connection carries logical channels framed with `mux::MuxFrame`.
+    pub fn multiplexed(&self) -> bool {
+        self.multiplexed.unwrap_or(false)
+    }
+
+    pub fn set_multiplexed(&mut self, multiplexed: Option<bool>) {
+        self.multiplexed = multiplexed;
+    }
+
+    /// The sender answers the Ping messages received on the connection with a Pong.
+    pub fn keepalive(&self) -> bool {
+        self.keepalive.unwrap_or(false)
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Option<bool>) {
+        self.keepalive = keepalive;
+    }
+
+    /// Number of seconds between the Ping messages the sender will send on the connection.
+    pub fn keepalive_interval(&self) -> Option<u32> {
+        self.keepalive_interval
+    }
+
+    pub fn set_keepalive_interval(&mut self, keepalive_interval: Option<u32>) {
+        self.keepalive_interval = keepalive_interval;
+    }
+
+    /// Address of the client that took the connection of the server (ip:port).
+    pub fn client_address(&self) -> Option<&str> {
+        self.client_address.as_deref()
+    }
+
+    pub fn set_client_address(&mut self, client_address: Option<String>) {
+        self.client_address = client_address;
+    }
+
     pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
         let signature = reader.read_u32::<LittleEndian>()?;
         if signature != JET_MSG_SIGNATURE {
@@ -155,15 +335,22 @@ impl JetPacket {
                 e
             ))
         })?;
-        let lines = payload.lines();
+        let mut lines = payload.lines();
 
-        // First line is ignored (GET / HTTP/1.1)
-        for line in lines.skip(1) {
+        // First line is the request line (GET / HTTP/1.1) or the status line of a response (HTTP/1.1 200 OK)
+        if let Some(first_line) = lines.next() {
+            if first_line.starts_with("HTTP/") {
+                jet_packet.response_status_code = ResponseStatusCode::from_status_line(first_line);
+            }
+        }
+
+        for line in lines {
             if line.is_empty() {
                 break;
             }
 
-            let fields: Vec<&str> = line.split(':').collect();
+            // Only the first ':' separates the name from the value, an address can contain others
+            let fields: Vec<&str> = line.splitn(2, ':').collect();
 
             if fields.len() != 2 {
                 return Err(error_other(&format!(
@@ -189,6 +376,80 @@ impl JetPacket {
                             .map_err(|e| error_other(&format!("Invalid association: {}", e)))?,
                     );
                 }
+                JET_HEADER_TIMEOUT => {
+                    jet_packet.timeout = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<u32>()
+                            .map_err(|e| error_other(&format!("Invalid timeout: {}", e)))?,
+                    );
+                }
+                JET_HEADER_INSTANCE => {
+                    jet_packet.instance = Some(fields[1].trim().to_string());
+                }
+                JET_HEADER_POOL_REMAINING => {
+                    jet_packet.pool_remaining = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<u32>()
+                            .map_err(|e| error_other(&format!("Invalid pool remaining: {}", e)))?,
+                    );
+                }
+                JET_HEADER_RESUMABLE => {
+                    jet_packet.resumable = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<bool>()
+                            .map_err(|e| error_other(&format!("Invalid resumable: {}", e)))?,
+                    );
+                }
+                JET_HEADER_POOL_TOKEN => {
+                    jet_packet.pool_token = Some(
+                        Uuid::from_str(fields[1].trim())
+                            .map_err(|e| error_other(&format!("Invalid pool token: {}", e)))?,
+                    );
+                }
+                JET_HEADER_RESUME_TOKEN => {
+                    jet_packet.resume_token = Some(
+                        Uuid::from_str(fields[1].trim())
+                            .map_err(|e| error_other(&format!("Invalid resume token: {}", e)))?,
+                    );
+                }
+                JET_HEADER_SEQUENCE => {
+                    jet_packet.sequence = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<u64>()
+                            .map_err(|e| error_other(&format!("Invalid sequence: {}", e)))?,
+                    );
+                }
+                JET_HEADER_MULTIPLEXED => {
+                    jet_packet.multiplexed = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<bool>()
+                            .map_err(|e| error_other(&format!("Invalid multiplexed: {}", e)))?,
+                    );
+                }
+                JET_HEADER_KEEPALIVE => {
+                    jet_packet.keepalive = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<bool>()
+                            .map_err(|e| error_other(&format!("Invalid keepalive: {}", e)))?,
+                    );
+                }
+                JET_HEADER_CLIENT_ADDRESS => {
+                    jet_packet.client_address = Some(fields[1].trim().to_string());
+                }
+                JET_HEADER_KEEPALIVE_INTERVAL => {
+                    jet_packet.keepalive_interval = Some(
+                        fields[1]
+                            .trim()
+                            .parse::<u32>()
+                            .map_err(|e| error_other(&format!("Invalid keepalive interval: {}", e)))?,
+                    );
+                }
                 _ => {
                     // ignore unknown header
                 }
@@ -207,6 +468,9 @@ impl JetPacket {
             Some(ResponseStatusCode::StatusCode400) => {
                 payload = payload.add(&format!("{} {} {}\r\n", "HTTP/1.1", "400", "Bad Request"));
             }
+            Some(ResponseStatusCode::StatusCode408) => {
+                payload = payload.add(&format!("{} {} {}\r\n", "HTTP/1.1", "408", "Request Timeout"));
+            }
             None => {
                 payload = payload.add(&format!("{} {} {}\r\n", "HTTP/1.1", "400", "Bad Request"));
             }
@@ -226,6 +490,36 @@ impl JetPacket {
         if let Some(ref instance) = self.instance {
             payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_INSTANCE, instance))
         }
+        if let Some(ref pool_remaining) = self.pool_remaining {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_POOL_REMAINING, pool_remaining))
+        }
+        if let Some(ref pool_token) = self.pool_token {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_POOL_TOKEN, pool_token))
+        }
+        if let Some(ref resumable) = self.resumable {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_RESUMABLE, resumable))
+        }
+        if let Some(ref resume_token) = self.resume_token {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_RESUME_TOKEN, resume_token))
+        }
+        if let Some(ref sequence) = self.sequence {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_SEQUENCE, sequence))
+        }
+        if let Some(ref multiplexed) = self.multiplexed {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_MULTIPLEXED, multiplexed))
+        }
+        if let Some(ref keepalive) = self.keepalive {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_KEEPALIVE, keepalive))
+        }
+        if let Some(ref client_address) = self.client_address {
+            payload = payload.add(&format!("{}: {}\r\n", JET_HEADER_CLIENT_ADDRESS, client_address))
+        }
+        if let Some(ref keepalive_interval) = self.keepalive_interval {
+            payload = payload.add(&format!(
+                "{}: {}\r\n",
+                JET_HEADER_KEEPALIVE_INTERVAL, keepalive_interval
+            ))
+        }
         payload = payload.add("\r\n");
 
         // Apply mask
@@ -254,3 +548,22 @@ fn apply_mask(mask: u8, payload: &mut [u8]) {
         *byte ^= mask;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connected_notification_carries_client_address() {
+        let mut notification = JetPacket::new_response(0, 0x80, ResponseStatusCode::StatusCode200);
+        notification.set_method(Some(JetMethod::CONNECTED));
+        notification.set_client_address(Some("[::1]:51234".to_string()));
+        let mut v = Vec::new();
+        notification.write_to(&mut v).unwrap();
+
+        let notification = JetPacket::read_from(&mut v.as_slice()).unwrap();
+        assert_eq!(notification.method(), Some(&JetMethod::CONNECTED));
+        assert_eq!(notification.client_address(), Some("[::1]:51234"));
+        assert_eq!(notification.version(), Some(JET_VERSION));
+    }
+}
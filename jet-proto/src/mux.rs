@@ -0,0 +1,189 @@
+//! Framing of the logical channels carried by a multiplexed JET connection (Jet-Multiplexed: true).
+//!
+//! Every frame starts with an 8 bytes header: the channel ID (u32, big endian), the frame type (u8), a reserved byte
+//! and the payload length (u16, big endian). Each side of a channel can send at most the number of bytes granted by
+//! the other side: the window starts at `MUX_INITIAL_WINDOW_SIZE` and grows with `WindowUpdate` frames.
+//!
+//! The server opens the channels with an even ID and the client the channels with an odd ID, so both sides can open a
+//! channel at the same time without picking the same ID.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+use crate::error_other;
+
+pub const MUX_FRAME_HEADER_SIZE: usize = 8;
+pub const MUX_MAX_PAYLOAD_SIZE: usize = 0xFFFF;
+pub const MUX_INITIAL_WINDOW_SIZE: u32 = 0x0001_0000;
+
+/// Whether a channel with this ID is opened by the server. The other channels are opened by the client.
+pub fn is_server_channel(channel_id: u32) -> bool {
+    channel_id & 1 == 0
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MuxFrameType {
+    /// Opens a channel. The payload is an optional channel name (UTF-8).
+    Open,
+    Data,
+    /// Grants more bytes to the other side. The payload is the increment (u32, big endian).
+    WindowUpdate,
+    Close,
+}
+
+impl MuxFrameType {
+    fn from_u8(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(MuxFrameType::Open),
+            1 => Ok(MuxFrameType::Data),
+            2 => Ok(MuxFrameType::WindowUpdate),
+            3 => Ok(MuxFrameType::Close),
+            _ => Err(error_other(&format!("Invalid multiplexed frame type: {}", value))),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            MuxFrameType::Open => 0,
+            MuxFrameType::Data => 1,
+            MuxFrameType::WindowUpdate => 2,
+            MuxFrameType::Close => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MuxFrame {
+    channel_id: u32,
+    frame_type: MuxFrameType,
+    payload: Vec<u8>,
+}
+
+impl MuxFrame {
+    pub fn new(channel_id: u32, frame_type: MuxFrameType, payload: Vec<u8>) -> Self {
+        MuxFrame {
+            channel_id,
+            frame_type,
+            payload,
+        }
+    }
+
+    pub fn window_update(channel_id: u32, increment: u32) -> Self {
+        let mut payload = Vec::with_capacity(4);
+        payload.write_u32::<BigEndian>(increment).unwrap();
+        MuxFrame::new(channel_id, MuxFrameType::WindowUpdate, payload)
+    }
+
+    /// Length of the frame at the beginning of `data`, if its header has been received.
+    pub fn frame_len(data: &[u8]) -> Option<usize> {
+        if data.len() < MUX_FRAME_HEADER_SIZE {
+            return None;
+        }
+        let mut length = &data[6..8];
+        length
+            .read_u16::<BigEndian>()
+            .ok()
+            .map(|length| MUX_FRAME_HEADER_SIZE + length as usize)
+    }
+
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
+    pub fn frame_type(&self) -> MuxFrameType {
+        self.frame_type
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Increment carried by a `WindowUpdate` frame.
+    pub fn window_increment(&self) -> Option<u32> {
+        if self.frame_type != MuxFrameType::WindowUpdate {
+            return None;
+        }
+        let mut payload = self.payload.as_slice();
+        payload.read_u32::<BigEndian>().ok()
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, io::Error> {
+        let channel_id = reader.read_u32::<BigEndian>()?;
+        let frame_type = MuxFrameType::from_u8(reader.read_u8()?)?;
+        let _reserved = reader.read_u8()?;
+        let length = reader.read_u16::<BigEndian>()?;
+
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+
+        Ok(MuxFrame::new(channel_id, frame_type, payload))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), io::Error> {
+        if self.payload.len() > MUX_MAX_PAYLOAD_SIZE {
+            return Err(error_other(&format!(
+                "Multiplexed frame payload too large: {} bytes",
+                self.payload.len()
+            )));
+        }
+
+        writer.write_u32::<BigEndian>(self.channel_id)?;
+        writer.write_u8(self.frame_type.to_u8())?;
+        writer.write_u8(0)?;
+        writer.write_u16::<BigEndian>(self.payload.len() as u16)?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA_FRAME: [u8; 11] = [0x00, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x03, 0x61, 0x62, 0x63];
+
+    #[test]
+    fn reads_data_frame() {
+        let frame = MuxFrame::read_from(&mut DATA_FRAME.as_ref()).unwrap();
+        assert_eq!(5, frame.channel_id());
+        assert_eq!(MuxFrameType::Data, frame.frame_type());
+        assert_eq!(b"abc", frame.payload());
+    }
+
+    #[test]
+    fn writes_data_frame() {
+        let frame = MuxFrame::new(5, MuxFrameType::Data, b"abc".to_vec());
+        let mut buffer = Vec::new();
+        frame.write_to(&mut buffer).unwrap();
+        assert_eq!(DATA_FRAME.as_ref(), buffer.as_slice());
+    }
+
+    #[test]
+    fn frame_len_requires_complete_header() {
+        assert_eq!(None, MuxFrame::frame_len(&DATA_FRAME[..7]));
+        assert_eq!(Some(DATA_FRAME.len()), MuxFrame::frame_len(&DATA_FRAME[..8]));
+    }
+
+    #[test]
+    fn window_update_carries_increment() {
+        let mut buffer = Vec::new();
+        MuxFrame::window_update(1, 4096).write_to(&mut buffer).unwrap();
+        let frame = MuxFrame::read_from(&mut buffer.as_slice()).unwrap();
+        assert_eq!(Some(4096), frame.window_increment());
+    }
+
+    #[test]
+    fn channel_ids_are_split_between_the_sides() {
+        assert!(is_server_channel(0));
+        assert!(is_server_channel(2));
+        assert!(!is_server_channel(1));
+        assert!(!is_server_channel(0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn rejects_unknown_frame_type() {
+        let mut frame = DATA_FRAME;
+        frame[4] = 0x10;
+        assert!(MuxFrame::read_from(&mut frame.as_ref()).is_err());
+    }
+}
@@ -15,6 +15,7 @@ mod tpdu;
 pub use crate::credssp::{
     ts_request::TsRequest, CredSsp, CredSspClient, CredSspResult, CredSspServer, CredentialsProxy,
 };
+pub use crate::encryption::compute_sha256;
 pub use crate::nego::*;
 pub use crate::ntlm::NTLM_VERSION_SIZE;
 pub use crate::rdp::*;
@@ -1,4 +1,20 @@
-use clap::{crate_name, crate_version, App, Arg};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{crate_name, crate_version, value_t, App, Arg};
+use url::Url;
+
+use crate::forward_proxy::{parse_user, DestinationPattern, ForwardProxyRules};
+use crate::interceptor::pcap::{PcapFormat, PcapOutput};
+use crate::interceptor::queue::{InterceptorKind, InterceptorWorker};
+use crate::interceptor::rotation::PcapRotation;
+use crate::mutual_tls::ClientAuthentication;
+use crate::outbound_proxy::OutboundProxy;
+use crate::source_filter::{IpNetwork, SourceRules};
+use crate::tls_verification::{CertificatePin, TlsVerification, TlsVerificationConfig};
 
 #[derive(Clone)]
 pub enum Protocol {
@@ -7,13 +23,45 @@ pub enum Protocol {
     UNKNOWN,
 }
 
+/// How the upstream target of a session is chosen among the routing urls
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoadBalancing {
+    RoundRobin,
+    LeastSessions,
+    FirstAvailable,
+}
+
 #[derive(Clone)]
 pub struct Config {
     listener_url: String,
-    routing_url: Option<String>,
-    pcap_filename: Option<String>,
+    http_port: u16,
+    source_rules: SourceRules,
+    routing_urls: Vec<String>,
+    load_balancing: LoadBalancing,
+    target_failure_cooldown: u32,
+    health_check_interval: u32,
+    health_check_timeout: u32,
+    upstream_tls_verification: TlsVerification,
+    upstream_proxy: Option<OutboundProxy>,
+    tls_forward_url: Option<String>,
+    tls_routes_filename: Option<String>,
+    tls_client_authentication: Option<ClientAuthentication>,
+    tls_keylog_filename: Option<String>,
+    forward_proxy_rules: ForwardProxyRules,
+    pcap_output: Option<PcapOutput>,
+    packet_interceptors: Vec<Arc<InterceptorWorker>>,
     protocol: Protocol,
     identities_filename: Option<String>,
+    association_timeout: u32,
+    association_max_timeout: u32,
+    association_pool_low_watermark: u32,
+    peers: HashMap<String, Url>,
+    peer_addresses: Vec<IpAddr>,
+    associations_filename: Option<String>,
+    resume_grace_period: u32,
+    resume_buffer_size: u32,
+    keepalive_interval: u32,
+    keepalive_max_missed_pongs: u32,
 }
 
 impl Config {
@@ -21,12 +69,82 @@ impl Config {
         self.listener_url.clone()
     }
 
+    /// Port of the http server exposing the management API
+    pub fn http_port(&self) -> u16 {
+        self.http_port
+    }
+
+    /// Networks allowed and denied to connect to the listener
+    pub fn source_rules(&self) -> &SourceRules {
+        &self.source_rules
+    }
+
     pub fn routing_url(&self) -> Option<String> {
-        self.routing_url.clone()
+        self.routing_urls.first().cloned()
+    }
+
+    pub fn routing_urls(&self) -> Vec<String> {
+        self.routing_urls.clone()
+    }
+
+    pub fn load_balancing(&self) -> LoadBalancing {
+        self.load_balancing
+    }
+
+    /// Time during which an upstream target that failed to connect is out of rotation
+    pub fn target_failure_cooldown(&self) -> u32 {
+        self.target_failure_cooldown
+    }
+
+    /// Interval between the active health checks of the targets. 0 disables the health checks.
+    pub fn health_check_interval(&self) -> u32 {
+        self.health_check_interval
+    }
+
+    pub fn health_check_timeout(&self) -> u32 {
+        self.health_check_timeout
+    }
+
+    /// Verification of the certificates of the tls:// routing urls and peers
+    pub fn upstream_tls_verification(&self) -> &TlsVerification {
+        &self.upstream_tls_verification
+    }
+
+    pub fn upstream_proxy(&self) -> Option<&OutboundProxy> {
+        self.upstream_proxy.as_ref()
+    }
+
+    pub fn tls_forward_url(&self) -> Option<String> {
+        self.tls_forward_url.clone()
+    }
+
+    pub fn tls_routes_filename(&self) -> Option<String> {
+        self.tls_routes_filename.clone()
+    }
+
+    /// Client certificates required by the TLS listeners
+    pub fn tls_client_authentication(&self) -> Option<&ClientAuthentication> {
+        self.tls_client_authentication.as_ref()
+    }
+
+    /// NSS key log file receiving the secrets of the TLS sessions of both relay legs
+    pub fn tls_keylog_filename(&self) -> Option<String> {
+        self.tls_keylog_filename.clone()
     }
 
-    pub fn pcap_filename(&self) -> Option<String> {
-        self.pcap_filename.clone()
+    /// Destinations and users allowed on the proxy listeners
+    pub fn forward_proxy_rules(&self) -> &ForwardProxyRules {
+        &self.forward_proxy_rules
+    }
+
+    /// Where the relayed sessions are captured, if a pcap file is configured
+    pub fn pcap_output(&self) -> Option<&PcapOutput> {
+        self.pcap_output.as_ref()
+    }
+
+    /// Interceptors applied to every relayed session, in the configured order
+    pub fn packet_interceptors(&self) -> &[Arc<InterceptorWorker>] {
+        &self.packet_interceptors
     }
 
     pub fn protocol(&self) -> &Protocol {
@@ -37,6 +155,48 @@ impl Config {
         self.identities_filename.clone()
     }
 
+    pub fn association_timeout(&self) -> u32 {
+        self.association_timeout
+    }
+
+    pub fn association_max_timeout(&self) -> u32 {
+        self.association_max_timeout
+    }
+
+    pub fn association_pool_low_watermark(&self) -> u32 {
+        self.association_pool_low_watermark
+    }
+
+    pub fn peer_url(&self, instance: &str) -> Option<&Url> {
+        self.peers.get(instance)
+    }
+
+    /// Whether a connection comes from one of the peers, so the headers of its forwarded requests can be trusted
+    pub fn is_peer_address(&self, addr: IpAddr) -> bool {
+        self.peer_addresses.contains(&addr)
+    }
+
+    pub fn associations_filename(&self) -> Option<String> {
+        self.associations_filename.clone()
+    }
+
+    /// Time during which a resumable JET session waits for a lost peer to come back. 0 disables resumable sessions.
+    pub fn resume_grace_period(&self) -> u32 {
+        self.resume_grace_period
+    }
+
+    pub fn resume_buffer_size(&self) -> u32 {
+        self.resume_buffer_size
+    }
+
+    pub fn keepalive_interval(&self) -> u32 {
+        self.keepalive_interval
+    }
+
+    pub fn keepalive_max_missed_pongs(&self) -> u32 {
+        self.keepalive_max_missed_pongs
+    }
+
     pub fn init() -> Self {
         let cli_app = App::new(crate_name!())
             .author("Devolutions")
@@ -49,19 +209,222 @@ impl Config {
                     .long("url")
                     .value_name("LISTENER_URL")
                     .help("An address on which the server will listen on. Format: <scheme>://<local_iface_ip>:<port>")
-                    .long_help("An address on which the server will listen on. Format: <scheme>://<local_iface_ip>:<port>")
+                    .long_help("An address on which the server will listen on. Format: <scheme>://<local_iface_ip>:<port>. Scheme supported : tcp, tls, socks5, http-connect and sniff. A socks5 listener relays the connections of SOCKS5 clients to the destinations allowed by proxy_listener_allow, and an http-connect listener does the same for HTTP CONNECT requests. A tls listener terminates TLS in front of the JET protocol. A sniff listener identifies the protocol of each connection from its first bytes: JET messages are handled as JET, RDP connection requests (TPKT header) as with an rdp routing url and TLS connections are forwarded to the tls forward url.")
                     .takes_value(true)
                     .default_value("tcp://0.0.0.0:8080")
                     .empty_values(false),
             )
+            .arg(
+                Arg::with_name("http-port")
+                    .long("http_port")
+                    .value_name("PORT")
+                    .help("Port of the http server exposing the management API")
+                    .long_help("Port of the http server exposing the management API (health, sessions, associations and targets). It listens on all the interfaces. Instances running on the same host need their own port.")
+                    .takes_value(true)
+                    .default_value("10256")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("source-allow")
+                    .long("source_allow")
+                    .value_name("CIDR")
+                    .help("Network allowed to connect to the listener. Format: <address>/<prefix length>")
+                    .long_help("Network allowed to connect to the listener. Format: <address>/<prefix length>, or an address alone for a single host. When set, connections from other addresses are closed before anything is read from them. Can be specified many times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| IpNetwork::parse(&value).map(|_| ()))
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("source-deny")
+                    .long("source_deny")
+                    .value_name("CIDR")
+                    .help("Network denied to connect to the listener, even if allowed by source_allow. Format: <address>/<prefix length>")
+                    .long_help("Network denied to connect to the listener, even if allowed by source_allow. Format: <address>/<prefix length>, or an address alone for a single host. Connections from these addresses are closed before anything is read from them. Can be specified many times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| IpNetwork::parse(&value).map(|_| ()))
+                    .empty_values(false),
+            )
             .arg(
                 Arg::with_name("routing-url")
                     .short("r")
                     .long("routing_url")
                     .value_name("ROUTING_URL")
                     .help("An address on which the server will route all packets. Format: <scheme>://<ip>:<port>.")
-                    .long_help("An address on which the server will route all packets. Format: <scheme>://<ip>:<port>. Scheme supported : tcp and tls. If it is not specified, the JET protocol will be used.")
+                    .long_help("An address on which the server will route all packets. Format: <scheme>://<ip>:<port>. Scheme supported : tcp and tls. If it is not specified, the JET protocol will be used. Can be specified many times with the same scheme: every session is relayed to one of the upstream targets, chosen with the load balancing strategy, and the next target is tried when a connection fails.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("load-balancing")
+                    .long("load_balancing")
+                    .value_name("STRATEGY")
+                    .help("How the upstream target of a session is chosen among the routing urls")
+                    .long_help("How the upstream target of a session is chosen among the routing urls. round-robin takes the targets in turn, least-sessions takes the target relaying the fewest sessions and first-available takes the first target in the order of the command line. Targets in their failure cool-down are skipped.")
+                    .takes_value(true)
+                    .possible_values(&["round-robin", "least-sessions", "first-available"])
+                    .default_value("round-robin")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("target-failure-cooldown")
+                    .long("target_failure_cooldown")
+                    .value_name("SECONDS")
+                    .help("Time during which an upstream target that failed to connect is out of rotation")
+                    .long_help("Time during which an upstream target that failed to connect is out of rotation. Targets in their cool-down are only tried when every target is in its cool-down.")
+                    .takes_value(true)
+                    .default_value("30")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("health-check-interval")
+                    .long("health_check_interval")
+                    .value_name("SECONDS")
+                    .help("Interval between the active health checks of the targets. 0 disables the health checks.")
+                    .long_help("Interval between the active health checks of the routing urls and of the destinations of the identities file. 0 disables the health checks. Routing urls are probed with a TCP connection and RDP destinations with an X.224 Connection Request and its negotiation response. The results are listed by GET /targets on the http server, and routing urls failing their probe are taken out of rotation.")
+                    .takes_value(true)
+                    .default_value("30")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("health-check-timeout")
+                    .long("health_check_timeout")
+                    .value_name("SECONDS")
+                    .help("Time after which a target that doesn't answer its health check is unreachable")
+                    .takes_value(true)
+                    .default_value("5")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("upstream-tls-ca-file")
+                    .long("upstream_tls_ca_file")
+                    .value_name("FILE")
+                    .help("PEM file with CA certificates trusted, in addition to the system roots, for the certificates of the tls:// routing urls and peers")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("upstream-tls-pin")
+                    .long("upstream_tls_pin")
+                    .value_name("PIN")
+                    .help("SHA-256 pin of the certificate of the tls:// routing urls and peers. Format: cert-sha256:<hex> or spki-sha256:<hex>")
+                    .long_help("SHA-256 pin of the certificate of the tls:// routing urls and peers. Format: cert-sha256:<hex> (digest of the DER certificate) or spki-sha256:<hex> (digest of its DER public key). The certificate must match one of the pins. Without upstream_tls_ca_file, the pins replace the verification of the certificate chain and host name. Can be specified many times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| CertificatePin::parse(&value).map(|_| ()))
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("upstream-tls-client-certificate")
+                    .long("upstream_tls_client_certificate")
+                    .value_name("FILE")
+                    .help("PKCS#12 file with the client certificate presented to the upstream TLS servers requesting one")
+                    .long_help("PKCS#12 file with the client certificate presented to the upstream TLS servers requesting one: tls:// routing urls, peers and RDP servers.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("upstream-tls-client-certificate-password")
+                    .long("upstream_tls_client_certificate_password")
+                    .value_name("PASSWORD")
+                    .help("Password of the upstream_tls_client_certificate file")
+                    .takes_value(true)
+                    .requires("upstream-tls-client-certificate"),
+            )
+            .arg(
+                Arg::with_name("upstream-tls-insecure")
+                    .long("upstream_tls_insecure")
+                    .help("Accept any certificate and host name from the tls:// routing urls and peers. The relay can then be intercepted upstream.")
+                    .conflicts_with_all(&["upstream-tls-ca-file", "upstream-tls-pin"]),
+            )
+            .arg(
+                Arg::with_name("upstream-proxy")
+                    .long("upstream_proxy")
+                    .value_name("URL")
+                    .help("Proxy through which the upstream targets are reached. Format: socks5://[<username>:<password>@]<host>:<port> or http://[<username>:<password>@]<host>:<port>")
+                    .long_help("Proxy through which the routing urls, peers, TLS routes and RDP destinations are reached. Format: socks5://[<username>:<password>@]<host>:<port> for a SOCKS5 proxy or http://[<username>:<password>@]<host>:<port> for an HTTP proxy supporting the CONNECT method. The routes of tls_routes_file and the identities of identities_file can set their own outbound_proxy. TLS and the RDP negotiation happen inside the tunnel, and the host names of the destinations are resolved by the proxy.")
+                    .takes_value(true)
+                    .validator(|value| OutboundProxy::parse(&value).map(|_| ()))
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("tls-forward-url")
+                    .long("tls_forward_url")
+                    .value_name("URL")
+                    .help("An address to which the TLS connections received on a sniff listener are forwarded. Format: tcp://<ip>:<port>")
+                    .long_help("An address to which the TLS connections received on a sniff listener are forwarded. Format: tcp://<ip>:<port>. The TLS session is not terminated, the bytes are forwarded unchanged. If not set, TLS connections are rejected.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("tls-routes-filename")
+                    .long("tls_routes_file")
+                    .value_name("FILE")
+                    .help("Path of a JSON file choosing the destination of the TLS connections from their server name (SNI)")
+                    .long_help("Path of a JSON file choosing the destination of the TLS connections from their server name (SNI). Used by tls:// routing urls and sniff listeners. Each route terminates TLS with its own certificate or forwards the TLS stream unchanged. Connections matching no route use the routing url or tls_forward_url.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("tls-client-ca-file")
+                    .long("tls_client_ca_file")
+                    .value_name("FILE")
+                    .help("PEM file with the CA certificates issuing the client certificates required by the TLS listeners")
+                    .long_help("PEM file with the CA certificates issuing the client certificates required by the TLS listeners: tls:// routing urls, tls listener urls and the routes of tls_routes_file terminating TLS. Connections without a valid client certificate are closed during the TLS handshake. Only supported on Linux.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("tls-client-subject")
+                    .long("tls_client_subject")
+                    .value_name("SUBJECT")
+                    .help("Subject of a client certificate allowed to open a session, as a common name (alice) or a whole subject (O=Example, CN=alice)")
+                    .long_help("Subject of a client certificate allowed to open a session, as a common name (alice) or a whole subject (O=Example, CN=alice). Compared without case. Any certificate issued by tls_client_ca_file is allowed if not set. Can be specified many times.")
                     .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .requires("tls-client-ca-file")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("tls-keylog-filename")
+                    .long("tls_keylog_file")
+                    .value_name("FILE")
+                    .help("Path of the file where the secrets of the TLS sessions are appended in the NSS key log format")
+                    .long_help("Path of the file where the secrets of the TLS sessions are appended in the NSS key log format, so that Wireshark can decrypt a capture of the encrypted traffic. Covers the TLS sessions terminated by the relay (tls listeners, tls:// routing urls, TLS routes and RDP clients) and the TLS sessions it opens to the servers. Anyone reading the file can decrypt the captured sessions. Only supported on Linux.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("proxy-listener-allow")
+                    .long("proxy_listener_allow")
+                    .value_name("HOST:PORT")
+                    .help("Destination that the clients of socks5 and http-connect listeners can reach. Format: <host>:<port>")
+                    .long_help("Destination that the clients of socks5 and http-connect listeners can reach. Format: <host>:<port>, where the host can be *, *.example.com, a host name or an IP address (IPv6 between brackets) and the port can be *. Host names and addresses are compared as requested by the client. Every destination is denied if not set. Can be specified many times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| DestinationPattern::parse(&value).map(|_| ()))
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("proxy-listener-user")
+                    .long("proxy_listener_user")
+                    .value_name("USERNAME:PASSWORD")
+                    .help("User allowed on socks5 and http-connect listeners. Clients don't authenticate if not set. Can be specified many times.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| parse_user(&value).map(|_| ()))
                     .empty_values(false),
             )
             .arg(
@@ -70,8 +433,90 @@ impl Config {
                     .long("pcap_file")
                     .value_name("PCAP_FILENAME")
                     .help("Path of the file where the pcap file will be saved. If not set, no pcap file will be created. WaykNow and RDP protocols can be saved.")
-                    .long_help("Path of the file where the pcap file will be saved. If not set, no pcap file will be created. WaykNow and RDP protocols can be saved.")
+                    .long_help("Path of the file where the pcap file will be saved. If not set, no pcap file will be created. WaykNow and RDP protocols can be saved. The path is a template expanded for every session: {date}, {time}, {session_id}, {client_ip}, {client_port}, {server_ip} and {server_port} are replaced by the values of the session and missing directories are created (ex: captures/{date}/{session_id}_{client_ip}.pcap). Without placeholder, every session overwrites the file of the previous one unless --pcap_shared is set.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-format")
+                    .long("pcap_format")
+                    .value_name("FORMAT")
+                    .help("Format of the pcap files.")
+                    .long_help("Format of the pcap files. pcapng files describe the client and server legs of every session as interfaces, flag the data of the client as inbound and the data of the server as outbound, and carry the session id, listener, route and authenticated client identity as section and packet comments.")
+                    .takes_value(true)
+                    .possible_values(&["pcap", "pcapng"])
+                    .default_value("pcap")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-shared")
+                    .long("pcap_shared")
+                    .help("Append every session to the single pcap file given by --pcap_file.")
+                    .long_help("Append every session to the single pcap file given by --pcap_file, created when jet starts. Every session is saved as its own TCP connection: when a client address is reused, the client port of the session is changed in the capture.")
+                    .requires("pcap-filename"),
+            )
+            .arg(
+                Arg::with_name("pcap-rotate-size")
+                    .long("pcap_rotate_size")
+                    .value_name("MEGABYTES")
+                    .help("Size in megabytes after which a pcap file is rotated.")
+                    .long_help("Size in megabytes after which a pcap file is rotated. The file is renamed with the date and time of the rotation (ex: capture.20190517-130405.pcap) and a new file is started with the same headers, interfaces and comments. Files are rotated between two messages, so a message is never split between two files.")
+                    .takes_value(true)
+                    .validator(validate_megabytes)
+                    .requires("pcap-filename")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-rotate-interval")
+                    .long("pcap_rotate_interval")
+                    .value_name("SECONDS")
+                    .help("Number of seconds after which a pcap file is rotated.")
+                    .long_help("Number of seconds after which a pcap file is rotated. The rotation happens with the first message relayed once the interval has elapsed. Can be combined with pcap_rotate_size.")
+                    .takes_value(true)
+                    .validator(validate_seconds)
+                    .requires("pcap-filename")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-max-files")
+                    .long("pcap_max_files")
+                    .value_name("COUNT")
+                    .help("Number of rotated pcap files kept for every capture file.")
+                    .long_help("Number of rotated pcap files kept for every capture file. The oldest rotated files are deleted first. The files rotated by a previous run count as well. With a file name template, the limit applies to the capture file of each session, not to all the captures. All rotated files are kept if not set.")
                     .takes_value(true)
+                    .validator(validate_count)
+                    .requires("pcap-filename")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-max-size")
+                    .long("pcap_max_size")
+                    .value_name("MEGABYTES")
+                    .help("Total size in megabytes of the rotated pcap files kept for every capture file.")
+                    .long_help("Total size in megabytes of the rotated pcap files kept for every capture file, counted before compression. The oldest rotated files are deleted first. The files rotated by a previous run count as well. With a file name template, the limit applies to the capture file of each session, not to all the captures. All rotated files are kept if not set.")
+                    .takes_value(true)
+                    .validator(validate_megabytes)
+                    .requires("pcap-filename")
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("pcap-compress")
+                    .long("pcap_compress")
+                    .help("Compress the rotated pcap files with gzip.")
+                    .long_help("Compress the rotated pcap files with gzip. The compression runs in the background and the compressed file replaces the rotated file once done (ex: capture.20190517-130405.pcap.gz).")
+                    .requires("pcap-filename"),
+            )
+            .arg(
+                Arg::with_name("packet-interceptor")
+                    .long("packet_interceptor")
+                    .value_name("NAME")
+                    .help("Interceptor applied to the relayed sessions.")
+                    .long_help("Interceptor applied to the relayed sessions. pcap saves the sessions in the files given by --pcap_file and metrics logs the packets and bytes sent by each peer when a session ends. Can be specified many times, the interceptors are then called in the order of the command line. Every interceptor runs on its own thread: the relay never waits for it, and when an interceptor can't keep up, such as a pcap writer on a stuck disk, its packets are dropped without affecting the sessions or the other interceptors. The start and the end of the sessions are never dropped, and the dropped data shows as a gap in the captured TCP streams.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .possible_values(&["pcap", "metrics"])
+                    .default_value("pcap")
                     .empty_values(false),
             )
             .arg(
@@ -85,6 +530,104 @@ impl Config {
                     .possible_values(&["wayk", "rdp"])
                     .empty_values(false)
             )
+            .arg(
+                Arg::with_name("association-timeout")
+                    .long("association_timeout")
+                    .value_name("SECONDS")
+                    .help("Time to wait for a connect request before a JET association expires.")
+                    .long_help("Time to wait for a connect request before a JET association expires. It is used when the accept request doesn't contain a Jet-Timeout header.")
+                    .takes_value(true)
+                    .default_value("300")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("association-max-timeout")
+                    .long("association_max_timeout")
+                    .value_name("SECONDS")
+                    .help("Maximum timeout a JET server can request with the Jet-Timeout header.")
+                    .long_help("Maximum timeout a JET server can request with the Jet-Timeout header. Longer requested timeouts are reduced to this value and accept requests with a timeout of 0 are rejected.")
+                    .takes_value(true)
+                    .default_value("3600")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("association-pool-low-watermark")
+                    .long("association_pool_low_watermark")
+                    .value_name("COUNT")
+                    .help("Number of parked server connections under which a JET server is notified that its pool runs low.")
+                    .long_help("Number of parked server connections under which a JET server is notified that its pool runs low. The response to the accept request creating an association contains a Jet-Pool-Token header, the server parks more connections under that association by sending accept requests with the association and this token. Only servers using JET version 2 or more that add connections to their pool are notified.")
+                    .takes_value(true)
+                    .default_value("1")
+                    .validator(validate_count)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("peer")
+                    .long("peer")
+                    .value_name("INSTANCE=URL")
+                    .help("Another devolutions-jet instance to which JET connect requests can be forwarded. Format: <instance>=<scheme>://<ip>:<port>")
+                    .long_help("Another devolutions-jet instance to which JET connect requests can be forwarded. Format: <instance>=<scheme>://<ip>:<port>. Scheme supported : tcp and tls. When an association is not known locally and the connect request (Jet-Instance header) or the association names another instance, the connect request is forwarded to that instance. Can be specified many times. Connect requests naming this instance are only handled as forwarded requests, whose Jet-Client-Address header is trusted, when they come from the address of a peer. The headers are ignored for the other clients.")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(|value| parse_peer(&value).map(|_| ()))
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("resume-grace-period")
+                    .long("resume_grace_period")
+                    .value_name("SECONDS")
+                    .help("Time during which a resumable JET session waits for a lost peer to reconnect. 0 disables resumable sessions.")
+                    .long_help("Time during which a resumable JET session waits for a lost peer to reconnect. 0 disables resumable sessions. Peers ask for a resumable session with the Jet-Resumable header and receive a Jet-Resume-Token they can present in a Resume request after a transient network loss.")
+                    .takes_value(true)
+                    .default_value("0")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("resume-buffer-size")
+                    .long("resume_buffer_size")
+                    .value_name("BYTES")
+                    .help("Number of bytes sent to a peer of a resumable JET session that are kept to be replayed after a resume.")
+                    .long_help("Number of bytes sent to a peer of a resumable JET session that are kept to be replayed after a resume. It is also the maximum number of bytes buffered for a lost peer before the other peer stops being read.")
+                    .takes_value(true)
+                    .default_value("1048576")
+                    .validator(validate_bytes)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("keepalive-interval")
+                    .long("keepalive_interval")
+                    .value_name("SECONDS")
+                    .help("Interval between the Ping messages sent to parked JET server connections. 0 disables the keepalive.")
+                    .long_help("Interval between the Ping messages sent to parked JET server connections. 0 disables the keepalive. Only servers sending the Jet-Keepalive header with their accept request are pinged, and they must answer every Ping with a Pong.")
+                    .takes_value(true)
+                    .default_value("0")
+                    .validator(validate_seconds)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("keepalive-max-missed-pongs")
+                    .long("keepalive_max_missed_pongs")
+                    .value_name("COUNT")
+                    .help("Number of Pong messages a parked JET server connection can miss before it is removed.")
+                    .long_help("Number of Pong messages a parked JET server connection can miss before it is removed. The association expires when its last server connection is removed.")
+                    .takes_value(true)
+                    .default_value("3")
+                    .validator(validate_count)
+                    .empty_values(false),
+            )
+            .arg(
+                Arg::with_name("associations-file")
+                    .long("associations_file")
+                    .value_name("ASSOCIATIONS_FILE")
+                    .help("Path of the file where the JET associations are saved. If not set, associations are only kept in memory.")
+                    .long_help("Path of the file where the JET associations are saved. If not set, associations are only kept in memory. Every change of an association (creation time, server and client addresses, instance, state) is appended to the file as a JSON line, and the associations found in the file are loaded at startup. The file is only appended to, so it keeps the whole history of the associations.")
+                    .takes_value(true)
+                    .empty_values(false),
+            )
             .arg(
                 Arg::with_name("identities-file")
                     .short("i")
@@ -134,10 +677,142 @@ identities_file example:
         let matches = cli_app.get_matches();
 
         let listener_url = String::from(matches.value_of("listener-url").expect("This should never happend"));
+        let http_port = value_t!(matches, "http-port", u16).unwrap_or_else(|e| e.exit());
+
+        let networks = |name: &str| -> Vec<IpNetwork> {
+            matches
+                .values_of(name)
+                .map(|values| {
+                    values
+                        .map(|value| IpNetwork::parse(value).expect("This should never happend"))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let source_rules = SourceRules::new(networks("source-allow"), networks("source-deny"));
+
+        let routing_urls = matches
+            .values_of("routing-url")
+            .map(|values| values.map(std::string::ToString::to_string).collect())
+            .unwrap_or_default();
+
+        let load_balancing = match matches.value_of("load-balancing") {
+            Some("least-sessions") => LoadBalancing::LeastSessions,
+            Some("first-available") => LoadBalancing::FirstAvailable,
+            _ => LoadBalancing::RoundRobin,
+        };
+        let target_failure_cooldown = value_t!(matches, "target-failure-cooldown", u32).unwrap_or_else(|e| e.exit());
+
+        let health_check_interval = value_t!(matches, "health-check-interval", u32).unwrap_or_else(|e| e.exit());
+        let health_check_timeout = value_t!(matches, "health-check-timeout", u32).unwrap_or_else(|e| e.exit());
 
-        let routing_url = matches.value_of("routing-url").map(std::string::ToString::to_string);
+        let upstream_tls_verification = TlsVerification::from_config(&TlsVerificationConfig {
+            ca_file: matches
+                .value_of("upstream-tls-ca-file")
+                .map(std::string::ToString::to_string),
+            pins: matches
+                .values_of("upstream-tls-pin")
+                .map(|values| values.map(std::string::ToString::to_string).collect())
+                .unwrap_or_default(),
+            insecure: matches.is_present("upstream-tls-insecure"),
+            client_certificate: matches
+                .value_of("upstream-tls-client-certificate")
+                .map(std::string::ToString::to_string),
+            client_certificate_password: matches
+                .value_of("upstream-tls-client-certificate-password")
+                .unwrap_or_default()
+                .to_string(),
+        })
+        .expect("upstream TLS certificates can't be loaded.");
 
-        let pcap_filename = matches.value_of("pcap-filename").map(std::string::ToString::to_string);
+        let upstream_proxy = matches
+            .value_of("upstream-proxy")
+            .map(|value| OutboundProxy::parse(value).expect("This should never happend"));
+
+        let tls_forward_url = matches
+            .value_of("tls-forward-url")
+            .map(std::string::ToString::to_string);
+
+        let tls_routes_filename = matches
+            .value_of("tls-routes-filename")
+            .map(std::string::ToString::to_string);
+
+        let tls_client_authentication = matches
+            .value_of("tls-client-ca-file")
+            .map(|ca_file| ClientAuthentication {
+                ca_file: ca_file.to_string(),
+                allowed_subjects: matches
+                    .values_of("tls-client-subject")
+                    .map(|values| values.map(std::string::ToString::to_string).collect())
+                    .unwrap_or_default(),
+            });
+
+        let tls_keylog_filename = matches
+            .value_of("tls-keylog-filename")
+            .map(std::string::ToString::to_string);
+
+        let forward_proxy_rules = ForwardProxyRules::new(
+            matches
+                .values_of("proxy-listener-allow")
+                .map(|values| {
+                    values
+                        .map(|value| DestinationPattern::parse(value).expect("This should never happend"))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            matches
+                .values_of("proxy-listener-user")
+                .map(|values| {
+                    values
+                        .map(|value| parse_user(value).expect("This should never happend"))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        );
+
+        let pcap_format = match matches.value_of("pcap-format") {
+            Some("pcapng") => PcapFormat::PcapNg,
+            _ => PcapFormat::Pcap,
+        };
+        const MEGABYTE: u64 = 1024 * 1024;
+        let pcap_rotation = PcapRotation {
+            max_file_size: value_t!(matches, "pcap-rotate-size", u64)
+                .ok()
+                .map(|size| size * MEGABYTE),
+            interval: value_t!(matches, "pcap-rotate-interval", u64)
+                .ok()
+                .map(Duration::from_secs),
+            max_files: value_t!(matches, "pcap-max-files", usize).ok(),
+            max_total_size: value_t!(matches, "pcap-max-size", u64).ok().map(|size| size * MEGABYTE),
+            compress: matches.is_present("pcap-compress"),
+        };
+        let pcap_output = matches.value_of("pcap-filename").map(|filename| {
+            PcapOutput::new(
+                filename,
+                matches.is_present("pcap-shared"),
+                pcap_format,
+                pcap_rotation,
+                &listener_url,
+            )
+            .expect("pcap file can't be created.")
+        });
+
+        let mut interceptor_kinds = Vec::new();
+        for name in matches.values_of("packet-interceptor").into_iter().flatten() {
+            let kind = match name {
+                "metrics" => InterceptorKind::Metrics,
+                _ => InterceptorKind::Pcap,
+            };
+            if !interceptor_kinds.contains(&kind) {
+                interceptor_kinds.push(kind);
+            }
+        }
+        // Nothing is captured without a pcap file
+        let packet_interceptors = interceptor_kinds
+            .into_iter()
+            .filter(|kind| *kind != InterceptorKind::Pcap || pcap_output.is_some())
+            .map(InterceptorWorker::spawn)
+            .collect();
 
         let protocol = match matches.value_of("protocol") {
             Some("wayk") => Protocol::WAYK,
@@ -149,12 +824,116 @@ identities_file example:
             .value_of("identities-file")
             .map(std::string::ToString::to_string);
 
+        let association_max_timeout = value_t!(matches, "association-max-timeout", u32).unwrap_or_else(|e| e.exit());
+        let association_timeout = value_t!(matches, "association-timeout", u32)
+            .unwrap_or_else(|e| e.exit())
+            .min(association_max_timeout);
+        let association_pool_low_watermark =
+            value_t!(matches, "association-pool-low-watermark", u32).unwrap_or_else(|e| e.exit());
+
+        let peers = matches
+            .values_of("peer")
+            .map(|values| values.filter_map(|value| parse_peer(value).ok()).collect())
+            .unwrap_or_default();
+        let peer_addresses = resolve_peer_addresses(&peers).expect("peer addresses can't be resolved.");
+
+        let associations_filename = matches
+            .value_of("associations-file")
+            .map(std::string::ToString::to_string);
+
+        let resume_grace_period = value_t!(matches, "resume-grace-period", u32).unwrap_or_else(|e| e.exit());
+        let resume_buffer_size = value_t!(matches, "resume-buffer-size", u32).unwrap_or_else(|e| e.exit());
+
+        let keepalive_interval = value_t!(matches, "keepalive-interval", u32).unwrap_or_else(|e| e.exit());
+        let keepalive_max_missed_pongs =
+            value_t!(matches, "keepalive-max-missed-pongs", u32).unwrap_or_else(|e| e.exit());
+
         Config {
             listener_url,
-            routing_url,
-            pcap_filename,
+            http_port,
+            source_rules,
+            routing_urls,
+            load_balancing,
+            target_failure_cooldown,
+            health_check_interval,
+            health_check_timeout,
+            upstream_tls_verification,
+            upstream_proxy,
+            tls_forward_url,
+            tls_routes_filename,
+            tls_client_authentication,
+            tls_keylog_filename,
+            forward_proxy_rules,
+            pcap_output,
+            packet_interceptors,
             protocol,
             identities_filename,
+            association_timeout,
+            association_max_timeout,
+            association_pool_low_watermark,
+            peers,
+            peer_addresses,
+            associations_filename,
+            resume_grace_period,
+            resume_buffer_size,
+            keepalive_interval,
+            keepalive_max_missed_pongs,
+        }
+    }
+}
+
+fn validate_seconds(value: String) -> Result<(), String> {
+    value
+        .parse::<u32>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid number of seconds ({}): {}", value, e))
+}
+
+fn validate_count(value: String) -> Result<(), String> {
+    value
+        .parse::<u32>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid count ({}): {}", value, e))
+}
+
+fn validate_bytes(value: String) -> Result<(), String> {
+    value
+        .parse::<u32>()
+        .map(|_| ())
+        .map_err(|e| format!("Invalid number of bytes ({}): {}", value, e))
+}
+
+fn validate_megabytes(value: String) -> Result<(), String> {
+    match value.parse::<u64>() {
+        Ok(0) => Err("The number of megabytes must be greater than 0".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Invalid number of megabytes ({}): {}", value, e)),
+    }
+}
+
+/// Addresses of the peers, resolved once at startup
+fn resolve_peer_addresses(peers: &HashMap<String, Url>) -> io::Result<Vec<IpAddr>> {
+    let mut addresses = Vec::new();
+    for url in peers.values() {
+        let addrs = url.with_default_port(|_| Err(()))?.to_socket_addrs()?;
+        addresses.extend(addrs.map(|addr| addr.ip()));
+    }
+    Ok(addresses)
+}
+
+fn parse_peer(value: &str) -> Result<(String, Url), String> {
+    let mut fields = value.splitn(2, '=');
+    match (fields.next(), fields.next()) {
+        (Some(instance), Some(url)) if !instance.is_empty() => {
+            let url = Url::parse(url).map_err(|e| format!("Invalid peer url ({}): {}", url, e))?;
+            match url.scheme() {
+                "tcp" | "tls" => Ok((instance.to_string(), url)),
+                scheme => Err(format!("Unsupported peer url scheme: {}", scheme)),
+            }
         }
+        _ => Err(format!(
+            "Invalid peer ({}). Format: <instance>=<scheme>://<ip>:<port>",
+            value
+        )),
     }
 }
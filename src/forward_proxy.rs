@@ -0,0 +1,331 @@
+pub mod http_connect;
+pub mod socks5;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+use futures::{future, Future};
+use tokio_tcp::TcpStream;
+use url::Url;
+
+use crate::config::Config;
+use crate::sni_routing::matches_wildcard;
+use crate::transport::tcp::TcpTransport;
+use crate::transport::JetFuture;
+use crate::Proxy;
+
+/// Destination requested by the client of a proxy listener.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Destination {
+    /// Parses a `<host>:<port>` authority, with IPv6 addresses between brackets.
+    pub fn parse(authority: &str) -> Option<Self> {
+        let separator = authority.rfind(':')?;
+        let (host, port) = (&authority[..separator], &authority[separator + 1..]);
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            &host[1..host.len() - 1]
+        } else {
+            host
+        };
+        if host.is_empty() || (host.contains(':') && host.parse::<IpAddr>().is_err()) {
+            return None;
+        }
+
+        Some(Destination {
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+
+    /// Host and port of a `<scheme>://<host>:<port>` url. The host name is kept as is, to be resolved by whoever
+    /// connects to it.
+    pub fn from_url(url: &Url) -> io::Result<Self> {
+        match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) if !host.is_empty() => Ok(Destination {
+                host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+                port,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The url {} has no host or port", url),
+            )),
+        }
+    }
+
+    /// Resolves the host name and connects to the first address accepting the connection.
+    pub fn connect(&self) -> JetFuture<TcpStream> {
+        let destination = self.to_string();
+        Box::new(self.resolve().and_then(move |addrs| {
+            future::loop_fn(
+                (addrs.into_iter(), None),
+                move |(mut addrs, last_error): (_, Option<io::Error>)| match addrs.next() {
+                    Some(addr) => future::Either::A(TcpStream::connect(&addr).then(move |result| match result {
+                        Ok(stream) => Ok(future::Loop::Break(stream)),
+                        Err(e) => Ok(future::Loop::Continue((addrs, Some(e)))),
+                    })),
+                    None => future::Either::B(future::err(last_error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, format!("No address found for {}", destination))
+                    }))),
+                },
+            )
+        }))
+    }
+
+    /// Addresses of the destination. Host names are resolved by the system resolver, which blocks for the duration of
+    /// the lookup, so the lookup runs as a blocking section of the thread pool instead of stalling a worker.
+    pub fn resolve(&self) -> JetFuture<Vec<SocketAddr>> {
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Box::new(future::ok(vec![SocketAddr::new(ip, self.port)]));
+        }
+
+        let (host, port) = (self.host.clone(), self.port);
+        Box::new(
+            future::poll_fn(move || {
+                tokio_threadpool::blocking(|| {
+                    (host.as_str(), port)
+                        .to_socket_addrs()
+                        .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+                })
+            })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .and_then(|result| result),
+        )
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Self {
+        Destination {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum HostPattern {
+    Any,
+    /// `*.example.com`, stored without its `*`
+    Wildcard(String),
+    Name(String),
+    Address(IpAddr),
+}
+
+/// Destination allowed by a proxy listener. Format: `<host>:<port>`, where the host can be `*`, `*.example.com`, a
+/// host name or an IP address (IPv6 between brackets) and the port can be `*`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DestinationPattern {
+    host: HostPattern,
+    port: Option<u16>,
+}
+
+impl DestinationPattern {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid destination ({}). Format: <host>:<port>", value);
+        let separator = value.rfind(':').ok_or_else(invalid)?;
+        let (host, port) = (&value[..separator], &value[separator + 1..]);
+
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse::<u16>().map_err(|_| invalid())?),
+        };
+        let host = match host {
+            "" => return Err(invalid()),
+            "*" => HostPattern::Any,
+            host if host.starts_with("*.") => HostPattern::Wildcard(host[1..].to_lowercase()),
+            host if host.starts_with('[') && host.ends_with(']') => {
+                HostPattern::Address(host[1..host.len() - 1].parse().map_err(|_| invalid())?)
+            }
+            host => match host.parse::<IpAddr>() {
+                Ok(addr) => HostPattern::Address(addr),
+                Err(_) => HostPattern::Name(host.to_lowercase()),
+            },
+        };
+
+        Ok(DestinationPattern { host, port })
+    }
+
+    /// Names and addresses are compared as requested by the client: a host name never matches an address pattern.
+    fn matches(&self, destination: &Destination) -> bool {
+        if self.port.map(|port| port != destination.port).unwrap_or(false) {
+            return false;
+        }
+
+        let host = destination.host.to_lowercase();
+        match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Wildcard(suffix) => matches_wildcard(suffix, &host),
+            HostPattern::Name(name) => *name == host,
+            HostPattern::Address(addr) => host.parse::<IpAddr>().map(|host| host == *addr).unwrap_or(false),
+        }
+    }
+}
+
+/// Destinations and users allowed on the proxy listeners.
+#[derive(Clone, Default)]
+pub struct ForwardProxyRules {
+    allowed_destinations: Vec<DestinationPattern>,
+    /// Password of every user. Clients don't authenticate when empty.
+    users: HashMap<String, String>,
+}
+
+impl ForwardProxyRules {
+    pub fn new(allowed_destinations: Vec<DestinationPattern>, users: HashMap<String, String>) -> Self {
+        ForwardProxyRules {
+            allowed_destinations,
+            users,
+        }
+    }
+
+    /// Every destination is denied when no destination is allowed.
+    pub fn is_allowed(&self, destination: &Destination) -> bool {
+        self.allowed_destinations
+            .iter()
+            .any(|pattern| pattern.matches(destination))
+    }
+
+    pub fn has_allowed_destinations(&self) -> bool {
+        !self.allowed_destinations.is_empty()
+    }
+
+    pub fn requires_authentication(&self) -> bool {
+        !self.users.is_empty()
+    }
+
+    pub fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .map(|expected| expected == password)
+            .unwrap_or(false)
+    }
+}
+
+/// Parses a `<username>:<password>` user of the proxy listeners.
+pub fn parse_user(value: &str) -> Result<(String, String), String> {
+    let mut fields = value.splitn(2, ':');
+    match (fields.next(), fields.next()) {
+        (Some(username), Some(password)) if !username.is_empty() => Ok((username.to_string(), password.to_string())),
+        _ => Err(format!("Invalid user ({}). Format: <username>:<password>", value)),
+    }
+}
+
+/// Relays the data between the client of a proxy listener and its destination. The route describes the destination in
+/// the captures of the session, and `client_data` is the data the client sent with its request, relayed first.
+fn relay(
+    config: Config,
+    route: String,
+    client: TcpStream,
+    client_data: Vec<u8>,
+    server: TcpStream,
+) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+    Proxy::new(config)
+        .with_route(route)
+        .with_client_data(client_data)
+        .build(TcpTransport::new(server), TcpTransport::new(client))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(host: &str, port: u16) -> Destination {
+        Destination {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    fn rules(patterns: &[&str]) -> ForwardProxyRules {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| DestinationPattern::parse(pattern).unwrap())
+            .collect();
+        ForwardProxyRules::new(patterns, HashMap::new())
+    }
+
+    #[test]
+    fn parses_destinations() {
+        assert_eq!(
+            Destination::parse("rdp.example.com:3389"),
+            Some(destination("rdp.example.com", 3389))
+        );
+        assert_eq!(Destination::parse("[::1]:22"), Some(destination("::1", 22)));
+        assert_eq!(Destination::parse("::1:22"), None);
+        assert_eq!(Destination::parse("example.com"), None);
+        assert_eq!(Destination::parse(":22"), None);
+        assert_eq!(Destination::parse("example.com:http"), None);
+    }
+
+    #[test]
+    fn keeps_the_host_names_of_urls() {
+        let url = |value: &str| Url::parse(value).unwrap();
+        assert_eq!(
+            Destination::from_url(&url("tcp://rdp.example.com:3389")).unwrap(),
+            destination("rdp.example.com", 3389)
+        );
+        assert_eq!(
+            Destination::from_url(&url("tls://[::1]:443")).unwrap(),
+            destination("::1", 443)
+        );
+        assert!(Destination::from_url(&url("tcp://rdp.example.com")).is_err());
+    }
+
+    #[test]
+    fn parses_destination_patterns() {
+        assert!(DestinationPattern::parse("rdp.example.com:3389").is_ok());
+        assert!(DestinationPattern::parse("*.example.com:*").is_ok());
+        assert!(DestinationPattern::parse("[::1]:22").is_ok());
+        assert!(DestinationPattern::parse("example.com").is_err());
+        assert!(DestinationPattern::parse(":22").is_err());
+        assert!(DestinationPattern::parse("example.com:http").is_err());
+    }
+
+    #[test]
+    fn allows_matching_destinations_only() {
+        let rules = rules(&[
+            "rdp.example.com:3389",
+            "*.apps.example.com:*",
+            "10.0.0.5:443",
+            "[::1]:22",
+        ]);
+        assert!(rules.is_allowed(&destination("RDP.example.com", 3389)));
+        assert!(!rules.is_allowed(&destination("rdp.example.com", 3390)));
+        assert!(rules.is_allowed(&destination("web.apps.example.com", 8080)));
+        assert!(!rules.is_allowed(&destination("apps.example.com", 8080)));
+        assert!(rules.is_allowed(&destination("10.0.0.5", 443)));
+        assert!(!rules.is_allowed(&destination("10.0.0.6", 443)));
+        assert!(rules.is_allowed(&destination("::1", 22)));
+    }
+
+    #[test]
+    fn denies_everything_without_patterns() {
+        assert!(!rules(&[]).is_allowed(&destination("10.0.0.5", 443)));
+        assert!(rules(&["*:*"]).is_allowed(&destination("10.0.0.5", 443)));
+    }
+
+    #[test]
+    fn parses_users() {
+        assert_eq!(
+            parse_user("alice:se:cret"),
+            Ok(("alice".to_string(), "se:cret".to_string()))
+        );
+        assert!(parse_user("alice").is_err());
+        assert!(parse_user(":secret").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+
+use futures::future::{err, loop_fn, Either, Loop};
+use futures::Future;
+use log::{info, warn};
+use tokio_io::io::{read, write_all};
+use tokio_tcp::TcpStream;
+
+use super::{relay, Destination, ForwardProxyRules};
+use crate::config::Config;
+
+/// Requests with a bigger head are refused
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+const READ_BUFFER_SIZE: usize = 1024;
+const HEAD_END: &[u8] = b"\r\n\r\n";
+
+type HttpConnectFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// Rejection of a request, with the status line sent to the client
+#[derive(Debug, PartialEq)]
+enum Rejection {
+    BadRequest,
+    MethodNotAllowed,
+    AuthenticationRequired,
+}
+
+impl Rejection {
+    fn response(&self) -> &'static [u8] {
+        match self {
+            Rejection::BadRequest => b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n",
+            Rejection::MethodNotAllowed => b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\n\r\n",
+            Rejection::AuthenticationRequired => b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"devolutions-jet\"\r\nContent-Length: 0\r\n\r\n",
+        }
+    }
+}
+
+const RESPONSE_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const RESPONSE_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+const RESPONSE_BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
+
+/// Serves a client of an http-connect listener: a `CONNECT host:port HTTP/1.1` request, authenticated with the Basic
+/// scheme when users are configured, opens a tunnel to an allowed destination.
+pub fn serve(conn: TcpStream, config: Config) -> HttpConnectFuture<()> {
+    let peer_addr = conn
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    Box::new(
+        read_request_head(conn).and_then(move |(conn, head, data)| -> HttpConnectFuture<()> {
+            let destination = match parse_request(&head, config.forward_proxy_rules()) {
+                Ok(destination) => destination,
+                Err(rejection) => {
+                    warn!("HTTP CONNECT request from {} rejected: {:?}", peer_addr, rejection);
+                    let error = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid HTTP CONNECT request: {:?}", rejection),
+                    );
+                    return reject(conn, rejection.response(), error);
+                }
+            };
+
+            if !config.forward_proxy_rules().is_allowed(&destination) {
+                warn!("HTTP CONNECT from {} to {} denied", peer_addr, destination);
+                let error = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Destination {} not allowed", destination),
+                );
+                return reject(conn, RESPONSE_FORBIDDEN, error);
+            }
+
+            info!("HTTP CONNECT from {} to {}", peer_addr, destination);
+            let route = format!("http-connect to {}", destination);
+            Box::new(destination.connect().then(move |result| {
+                match result {
+                    Ok(server) => Either::A(
+                        write_all(conn, RESPONSE_ESTABLISHED)
+                            .and_then(move |(conn, _)| relay(config, route, conn, data, server)),
+                    ),
+                    Err(e) => {
+                        let error = io::Error::new(e.kind(), format!("Failed to connect to {}: {}", destination, e));
+                        Either::B(reject(conn, RESPONSE_BAD_GATEWAY, error))
+                    }
+                }
+            }))
+        }),
+    )
+}
+
+/// Reads the request head. The data sent by the client after the head is returned with it.
+fn read_request_head(conn: TcpStream) -> HttpConnectFuture<(TcpStream, String, Vec<u8>)> {
+    Box::new(loop_fn((conn, Vec::new()), |(conn, mut received)| {
+        read(conn, vec![0u8; READ_BUFFER_SIZE]).and_then(move |(conn, buffer, len)| {
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before the end of the HTTP request",
+                ));
+            }
+            received.extend_from_slice(&buffer[..len]);
+
+            match received.windows(HEAD_END.len()).position(|window| window == HEAD_END) {
+                Some(position) => {
+                    let data = received.split_off(position + HEAD_END.len());
+                    let head = String::from_utf8(received)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "HTTP request is not UTF-8"))?;
+                    Ok(Loop::Break((conn, head, data)))
+                }
+                None if received.len() > MAX_REQUEST_HEAD_SIZE => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("HTTP request head bigger than {} bytes", MAX_REQUEST_HEAD_SIZE),
+                )),
+                None => Ok(Loop::Continue((conn, received))),
+            }
+        })
+    }))
+}
+
+/// Destination of a CONNECT request, after checking the credentials of the client
+fn parse_request(head: &str, rules: &ForwardProxyRules) -> Result<Destination, Rejection> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().ok_or(Rejection::BadRequest)?;
+    let mut fields = request_line.split(' ');
+    let (method, authority, version) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(method), Some(authority), Some(version), None) => (method, authority, version),
+        _ => return Err(Rejection::BadRequest),
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Rejection::BadRequest);
+    }
+    if method != "CONNECT" {
+        return Err(Rejection::MethodNotAllowed);
+    }
+    let destination = Destination::parse(authority).ok_or(Rejection::BadRequest)?;
+
+    if rules.requires_authentication() {
+        let credentials = lines
+            .filter_map(|line| {
+                let separator = line.find(':')?;
+                let (name, value) = (&line[..separator], line[separator + 1..].trim());
+                if name.eq_ignore_ascii_case("Proxy-Authorization") {
+                    Some(value)
+                } else {
+                    None
+                }
+            })
+            .filter_map(parse_basic_credentials)
+            .next();
+        match credentials {
+            Some((username, password)) if rules.authenticate(&username, &password) => {}
+            _ => return Err(Rejection::AuthenticationRequired),
+        }
+    }
+
+    Ok(destination)
+}
+
+/// `Basic <base64 of username:password>`
+fn parse_basic_credentials(value: &str) -> Option<(String, String)> {
+    let mut fields = value.splitn(2, ' ');
+    match (fields.next(), fields.next()) {
+        (Some(scheme), Some(credentials)) if scheme.eq_ignore_ascii_case("Basic") => {
+            let credentials = String::from_utf8(base64::decode(credentials.trim()).ok()?).ok()?;
+            let separator = credentials.find(':')?;
+            Some((
+                credentials[..separator].to_string(),
+                credentials[separator + 1..].to_string(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Sends an error response, then fails with `error`
+fn reject(conn: TcpStream, response: &'static [u8], error: io::Error) -> HttpConnectFuture<()> {
+    Box::new(write_all(conn, response).and_then(move |_| err(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn rules(users: &[(&str, &str)]) -> ForwardProxyRules {
+        let users = users
+            .iter()
+            .map(|(username, password)| (username.to_string(), password.to_string()))
+            .collect::<HashMap<_, _>>();
+        ForwardProxyRules::new(Vec::new(), users)
+    }
+
+    #[test]
+    fn parses_connect_requests() {
+        let head = "CONNECT rdp.example.com:3389 HTTP/1.1\r\nHost: rdp.example.com:3389\r\n\r\n";
+        assert_eq!(
+            parse_request(head, &rules(&[])),
+            Ok(Destination {
+                host: "rdp.example.com".to_string(),
+                port: 3389
+            })
+        );
+
+        let head = "CONNECT [::1]:22 HTTP/1.0\r\n\r\n";
+        assert_eq!(
+            parse_request(head, &rules(&[])).map(|destination| destination.host),
+            Ok("::1".to_string())
+        );
+
+        assert_eq!(
+            parse_request("GET http://example.com/ HTTP/1.1\r\n\r\n", &rules(&[])),
+            Err(Rejection::MethodNotAllowed)
+        );
+        assert_eq!(
+            parse_request("CONNECT example.com HTTP/1.1\r\n\r\n", &rules(&[])),
+            Err(Rejection::BadRequest)
+        );
+    }
+
+    #[test]
+    fn checks_basic_credentials() {
+        let rules = rules(&[("alice", "secret")]);
+        // alice:secret
+        let head = "CONNECT example.com:443 HTTP/1.1\r\nproxy-authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+        assert!(parse_request(head, &rules).is_ok());
+
+        // alice:wrong
+        let head = "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n";
+        assert_eq!(parse_request(head, &rules), Err(Rejection::AuthenticationRequired));
+
+        let head = "CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+        assert_eq!(parse_request(head, &rules), Err(Rejection::AuthenticationRequired));
+    }
+}
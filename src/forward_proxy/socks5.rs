@@ -0,0 +1,295 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::future::{err, ok, Either};
+use futures::Future;
+use log::{info, warn};
+use tokio_io::io::{read_exact, write_all};
+use tokio_tcp::TcpStream;
+
+use super::{relay, Destination};
+use crate::config::Config;
+
+pub const SOCKS_VERSION: u8 = 0x05;
+pub const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+pub const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+pub const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+pub const COMMAND_CONNECT: u8 = 0x01;
+
+pub const ADDRESS_IPV4: u8 = 0x01;
+pub const ADDRESS_DOMAIN_NAME: u8 = 0x03;
+pub const ADDRESS_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+type Socks5Future<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// Serves a client of a socks5 listener (RFC 1928), authenticated by username and password (RFC 1929) when users are
+/// configured. Only the CONNECT command is supported.
+pub fn serve(conn: TcpStream, config: Config) -> Socks5Future<()> {
+    let peer_addr = conn
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    let rules = config.forward_proxy_rules().clone();
+
+    Box::new(
+        negotiate_method(conn, rules.requires_authentication())
+            .and_then(move |(conn, method)| -> Socks5Future<TcpStream> {
+                if method == METHOD_USERNAME_PASSWORD {
+                    Box::new(authenticate(conn, move |username, password| {
+                        rules.authenticate(username, password)
+                    }))
+                } else {
+                    Box::new(ok(conn))
+                }
+            })
+            .and_then(read_request)
+            .and_then(move |(conn, request)| {
+                let config_clone = config.clone();
+                let destination = match request {
+                    Ok(destination) => destination,
+                    Err(code) => {
+                        let error = io::Error::new(io::ErrorKind::InvalidData, "Unsupported SOCKS5 request");
+                        return Either::A(reject(conn, code, error));
+                    }
+                };
+
+                if !config.forward_proxy_rules().is_allowed(&destination) {
+                    warn!("SOCKS5 connection from {} to {} denied", peer_addr, destination);
+                    let error = io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("Destination {} not allowed", destination),
+                    );
+                    return Either::A(reject(conn, REPLY_NOT_ALLOWED, error));
+                }
+
+                info!("SOCKS5 connection from {} to {}", peer_addr, destination);
+                let route = format!("socks5 to {}", destination);
+                Either::B(destination.connect().then(move |result| match result {
+                    Ok(server) => {
+                        let bound_addr = server.local_addr().ok();
+                        Either::A(
+                            reply(conn, REPLY_SUCCEEDED, bound_addr)
+                                .and_then(move |conn| relay(config_clone, route, conn, Vec::new(), server)),
+                        )
+                    }
+                    Err(e) => {
+                        let code = if e.kind() == io::ErrorKind::ConnectionRefused {
+                            REPLY_CONNECTION_REFUSED
+                        } else {
+                            REPLY_HOST_UNREACHABLE
+                        };
+                        let error = io::Error::new(e.kind(), format!("Failed to connect to {}: {}", destination, e));
+                        Either::B(reject(conn, code, error))
+                    }
+                }))
+            }),
+    )
+}
+
+/// Picks username/password authentication when users are configured, no authentication otherwise.
+fn negotiate_method(conn: TcpStream, requires_authentication: bool) -> Socks5Future<(TcpStream, u8)> {
+    Box::new(
+        read_exact(conn, [0u8; 2])
+            .and_then(|(conn, header)| {
+                if header[0] != SOCKS_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unsupported SOCKS version {}", header[0]),
+                    ));
+                }
+                Ok(read_exact(conn, vec![0u8; usize::from(header[1])]))
+            })
+            .and_then(|read_methods| read_methods)
+            .and_then(move |(conn, methods)| {
+                let expected = if requires_authentication {
+                    METHOD_USERNAME_PASSWORD
+                } else {
+                    METHOD_NO_AUTHENTICATION
+                };
+                let method = if methods.contains(&expected) {
+                    expected
+                } else {
+                    METHOD_NO_ACCEPTABLE
+                };
+
+                write_all(conn, [SOCKS_VERSION, method]).and_then(move |(conn, _)| {
+                    if method == METHOD_NO_ACCEPTABLE {
+                        Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            "No acceptable SOCKS5 authentication method",
+                        ))
+                    } else {
+                        Ok((conn, method))
+                    }
+                })
+            }),
+    )
+}
+
+/// Username/password sub-negotiation
+fn authenticate<F>(conn: TcpStream, check: F) -> Socks5Future<TcpStream>
+where
+    F: FnOnce(&str, &str) -> bool + Send + 'static,
+{
+    Box::new(
+        read_exact(conn, [0u8; 2])
+            .and_then(|(conn, header)| {
+                if header[0] != USERNAME_PASSWORD_VERSION {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unsupported SOCKS5 authentication version {}", header[0]),
+                    ));
+                }
+                Ok(read_exact(conn, vec![0u8; usize::from(header[1]) + 1]))
+            })
+            .and_then(|read_username| read_username)
+            .and_then(|(conn, mut username)| {
+                let password_len = username.pop().unwrap_or(0);
+                read_exact(conn, vec![0u8; usize::from(password_len)])
+                    .map(move |(conn, password)| (conn, username, password))
+            })
+            .and_then(move |(conn, username, password)| {
+                let username = String::from_utf8_lossy(&username).into_owned();
+                let authenticated = check(&username, &String::from_utf8_lossy(&password));
+                let status = if authenticated { 0x00 } else { 0x01 };
+
+                write_all(conn, [USERNAME_PASSWORD_VERSION, status]).and_then(move |(conn, _)| {
+                    if authenticated {
+                        Ok(conn)
+                    } else {
+                        Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("SOCKS5 authentication of {} failed", username),
+                        ))
+                    }
+                })
+            }),
+    )
+}
+
+/// Reads the request of the client. The result is the destination, or the reply code if the request is not supported.
+fn read_request(conn: TcpStream) -> Socks5Future<(TcpStream, Result<Destination, u8>)> {
+    Box::new(read_exact(conn, [0u8; 4]).and_then(
+        |(conn, header)| -> Socks5Future<(TcpStream, Result<Destination, u8>)> {
+            if header[0] != SOCKS_VERSION {
+                return Box::new(err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unsupported SOCKS version {}", header[0]),
+                )));
+            }
+
+            let command = header[1];
+            let address_len: Socks5Future<(TcpStream, usize)> = match header[3] {
+                ADDRESS_IPV4 => Box::new(ok((conn, 4))),
+                ADDRESS_IPV6 => Box::new(ok((conn, 16))),
+                ADDRESS_DOMAIN_NAME => {
+                    Box::new(read_exact(conn, [0u8; 1]).map(|(conn, len)| (conn, usize::from(len[0]))))
+                }
+                _ => return Box::new(ok((conn, Err(REPLY_ADDRESS_TYPE_NOT_SUPPORTED)))),
+            };
+            let address_type = header[3];
+
+            Box::new(
+                address_len
+                    .and_then(|(conn, len)| read_exact(conn, vec![0u8; len + 2]))
+                    .map(move |(conn, address)| {
+                        if command != COMMAND_CONNECT {
+                            return (conn, Err(REPLY_COMMAND_NOT_SUPPORTED));
+                        }
+                        (conn, parse_destination(address_type, &address))
+                    }),
+            )
+        },
+    ))
+}
+
+/// Parses the address and port of a request
+fn parse_destination(address_type: u8, address: &[u8]) -> Result<Destination, u8> {
+    let (host, port) = address.split_at(address.len() - 2);
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(host);
+            Ipv4Addr::from(octets).to_string()
+        }
+        ADDRESS_IPV6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(host);
+            Ipv6Addr::from(octets).to_string()
+        }
+        _ => String::from_utf8(host.to_vec()).map_err(|_| REPLY_HOST_UNREACHABLE)?,
+    };
+
+    Ok(Destination {
+        host,
+        port: BigEndian::read_u16(port),
+    })
+}
+
+/// Sends a reply with the address bound to reach the destination
+fn reply(conn: TcpStream, code: u8, bound_addr: Option<SocketAddr>) -> Socks5Future<TcpStream> {
+    let bound_addr = bound_addr.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut reply = vec![SOCKS_VERSION, code, 0x00];
+    match bound_addr.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(ADDRESS_IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(ADDRESS_IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&bound_addr.port().to_be_bytes());
+
+    Box::new(write_all(conn, reply).map(|(conn, _)| conn))
+}
+
+/// Sends a reply with an error code, then fails with `error`
+fn reject(conn: TcpStream, code: u8, error: io::Error) -> Socks5Future<()> {
+    Box::new(reply(conn, code, None).and_then(move |_| err(error)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_destinations() {
+        assert_eq!(
+            parse_destination(ADDRESS_IPV4, &[10, 0, 0, 5, 0x01, 0xBB]),
+            Ok(Destination {
+                host: "10.0.0.5".to_string(),
+                port: 443
+            })
+        );
+        assert_eq!(
+            parse_destination(ADDRESS_DOMAIN_NAME, b"example.com\x0D\x3D"),
+            Ok(Destination {
+                host: "example.com".to_string(),
+                port: 3389
+            })
+        );
+
+        let mut ipv6 = vec![0u8; 15];
+        ipv6.extend_from_slice(&[1, 0x00, 0x16]);
+        assert_eq!(
+            parse_destination(ADDRESS_IPV6, &ipv6),
+            Ok(Destination {
+                host: "::1".to_string(),
+                port: 22
+            })
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
+use futures::future::{self, join_all};
+use futures::{Async, Future, Sink, Stream};
+use log::{error, warn};
+use serde_derive::Serialize;
+use tokio::codec::Decoder;
+use tokio::timer::{Interval, Timeout};
+use tokio_tcp::TcpStream;
+use url::Url;
+
+use crate::config::Config;
+use crate::forward_proxy::Destination;
+use crate::outbound_proxy::{self, OutboundProxy};
+use crate::rdp::identities_proxy::RdpIdentity;
+use crate::transport::x224::X224Transport;
+use crate::transport::JetFuture;
+use crate::upstream::JetUpstreamTargets;
+
+/// Cookie of the X.224 Connection Request when the identity has no target user name
+const DEFAULT_PROBE_COOKIE: &str = "devolutions-jet";
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+    /// TCP connection to a routing url
+    Tcp,
+    /// X.224 negotiation with a destination of the identities file
+    Rdp,
+}
+
+/// Result of the last probe of a target
+#[derive(Clone, Serialize)]
+pub struct TargetHealth {
+    pub target: String,
+    pub probe: ProbeKind,
+    pub reachable: bool,
+    pub latency_ms: Option<u64>,
+    /// Security protocol selected by an RDP target
+    pub security_protocol: Option<String>,
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+impl TargetHealth {
+    fn new(target: String, probe: ProbeKind) -> Self {
+        TargetHealth {
+            target,
+            probe,
+            reachable: false,
+            latency_ms: None,
+            security_protocol: None,
+            error: None,
+            checked_at: Utc::now(),
+        }
+    }
+}
+
+pub type JetHealthChecks = Arc<HealthChecks>;
+
+/// Results of the active health checks of the upstream and RDP targets.
+pub struct HealthChecks {
+    results: Mutex<Vec<TargetHealth>>,
+}
+
+impl HealthChecks {
+    pub fn new() -> Self {
+        HealthChecks {
+            results: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn list(&self) -> Vec<TargetHealth> {
+        self.results.lock().unwrap().clone()
+    }
+
+    fn update(&self, results: Vec<TargetHealth>) {
+        *self.results.lock().unwrap() = results;
+    }
+}
+
+/// Probes the targets every `health_check_interval` seconds. The routing urls failing their probe are taken out of
+/// rotation like after a failed session.
+pub fn run(
+    config: Config,
+    upstream_targets: JetUpstreamTargets,
+    health_checks: JetHealthChecks,
+) -> impl Future<Item = (), Error = ()> + Send {
+    let period = Duration::from_secs(u64::from(config.health_check_interval()));
+    let timeout = Duration::from_secs(u64::from(config.health_check_timeout()));
+
+    Interval::new(Instant::now(), period)
+        .map_err(|e| error!("Health check timer failed: {}", e))
+        .for_each(move |_| {
+            let mut probes: Vec<Box<dyn Future<Item = TargetHealth, Error = ()> + Send>> = Vec::new();
+
+            for target in upstream_targets.targets() {
+                if target.url().scheme() != "tcp" && target.url().scheme() != "tls" {
+                    continue;
+                }
+
+                let target = target.clone();
+                let upstream_targets = upstream_targets.clone();
+                let connection = probe_tcp(target.url(), upstream_targets.proxy());
+                probes.push(Box::new(
+                    probe(target.url().to_string(), ProbeKind::Tcp, timeout, connection).map(move |health| {
+                        if health.reachable {
+                            target.mark_healthy();
+                        } else {
+                            upstream_targets.mark_failed(&target);
+                        }
+                        health
+                    }),
+                ));
+            }
+
+            let config = config.clone();
+            let health_checks = health_checks.clone();
+            load_identities(config.identities_filename()).and_then(move |identities| {
+                probes.extend(rdp_probes(&config, identities, timeout));
+                join_all(probes).map(move |results| health_checks.update(results))
+            })
+        })
+}
+
+/// Reads the identities file in a blocking section of the thread pool. It is read again for every round of checks so
+/// the changes of the file are picked up.
+fn load_identities(identities_filename: Option<String>) -> impl Future<Item = Vec<RdpIdentity>, Error = ()> {
+    future::poll_fn(move || match &identities_filename {
+        Some(identities_filename) => tokio_threadpool::blocking(|| RdpIdentity::from_file(identities_filename))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        None => Ok(Async::Ready(Ok(Vec::new()))),
+    })
+    .and_then(|result| result)
+    .or_else(|e| {
+        warn!("Identities file can't be read for the health checks: {}", e);
+        Ok(Vec::new())
+    })
+}
+
+/// Probes of the destinations of the identities, each destination once.
+fn rdp_probes(
+    config: &Config,
+    identities: Vec<RdpIdentity>,
+    timeout: Duration,
+) -> Vec<Box<dyn Future<Item = TargetHealth, Error = ()> + Send>> {
+    let mut probes: Vec<Box<dyn Future<Item = TargetHealth, Error = ()> + Send>> = Vec::new();
+    let mut destinations = HashSet::new();
+    for identity in identities {
+        if !destinations.insert(identity.destination.clone()) {
+            continue;
+        }
+
+        let proxy = identity.outbound_proxy(config.upstream_proxy());
+        let destination = identity.destination;
+        match (destination.parse::<SocketAddr>(), proxy) {
+            (Ok(addr), Ok(proxy)) => {
+                let cookie = if identity.target.username.is_empty() {
+                    DEFAULT_PROBE_COOKIE.to_string()
+                } else {
+                    identity.target.username
+                };
+                probes.push(Box::new(probe(
+                    destination,
+                    ProbeKind::Rdp,
+                    timeout,
+                    probe_rdp(addr, cookie, proxy.as_ref()),
+                )));
+            }
+            (Err(e), _) => {
+                let mut health = TargetHealth::new(destination, ProbeKind::Rdp);
+                health.error = Some(format!("Invalid destination: {}", e));
+                probes.push(Box::new(future::ok(health)));
+            }
+            (_, Err(e)) => {
+                let mut health = TargetHealth::new(destination, ProbeKind::Rdp);
+                health.error = Some(e.to_string());
+                probes.push(Box::new(future::ok(health)));
+            }
+        }
+    }
+    probes
+}
+
+/// Times a probe. Its result is the security protocol selected by the target, if any.
+fn probe<F>(
+    target: String,
+    kind: ProbeKind,
+    timeout: Duration,
+    future: F,
+) -> impl Future<Item = TargetHealth, Error = ()>
+where
+    F: Future<Item = Option<String>, Error = io::Error>,
+{
+    let start = Instant::now();
+    Timeout::new(future, timeout).then(move |result| {
+        let mut health = TargetHealth::new(target, kind);
+        match result {
+            Ok(security_protocol) => {
+                let latency = start.elapsed();
+                health.reachable = true;
+                health.latency_ms = Some(latency.as_secs() * 1000 + u64::from(latency.subsec_millis()));
+                health.security_protocol = security_protocol;
+            }
+            Err(ref e) if e.is_elapsed() => {
+                health.error = Some(format!("No answer after {} seconds", timeout.as_secs()));
+            }
+            Err(e) => {
+                health.error = Some(match e.into_inner() {
+                    Some(e) => e.to_string(),
+                    None => "Health check timer failed".to_string(),
+                });
+            }
+        }
+        Ok::<_, ()>(health)
+    })
+}
+
+/// Connects to a routing url, through its proxy when set. A host name that can't be resolved fails the probe.
+fn probe_tcp(url: &Url, proxy: Option<&OutboundProxy>) -> impl Future<Item = Option<String>, Error = io::Error> {
+    let connection: JetFuture<TcpStream> = match Destination::from_url(url) {
+        Ok(destination) => outbound_proxy::connect(destination, proxy),
+        Err(e) => Box::new(future::err(e)),
+    };
+    connection.map(|_| None)
+}
+
+/// Sends an X.224 Connection Request and reads the negotiation response of an RDP target.
+fn probe_rdp(
+    addr: SocketAddr,
+    cookie: String,
+    proxy: Option<&OutboundProxy>,
+) -> impl Future<Item = Option<String>, Error = io::Error> {
+    outbound_proxy::connect(Destination::from(addr), proxy)
+        .and_then(move |stream| {
+            let protocol = rdp_proto::SecurityProtocol::SSL
+                | rdp_proto::SecurityProtocol::HYBRID
+                | rdp_proto::SecurityProtocol::HYBRID_EX;
+            let mut request_data = BytesMut::new();
+            request_data.resize(rdp_proto::NEGOTIATION_REQUEST_LEN + cookie.len(), 0);
+            rdp_proto::write_negotiation_request(
+                request_data.as_mut(),
+                &cookie,
+                protocol,
+                rdp_proto::NegotiationRequestFlags::default(),
+            )?;
+
+            Ok(X224Transport::new()
+                .framed(stream)
+                .send((rdp_proto::X224TPDUType::ConnectionRequest, request_data)))
+        })
+        .and_then(|send| send)
+        .and_then(|transport| transport.into_future().map_err(|(e, _)| e))
+        .and_then(|(response, _)| match response {
+            Some((code, buf)) => match rdp_proto::parse_negotiation_response(code, buf.as_ref()) {
+                Ok((selected_protocol, _)) => Ok(Some(format!("{:?}", selected_protocol))),
+                Err(rdp_proto::NegotiationError::NegotiationFailure(code)) => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Negotiation failure: {:?}", code),
+                )),
+                Err(rdp_proto::NegotiationError::IOError(e)) => Err(e),
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before the negotiation response",
+            )),
+        })
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::str::FromStr;
+
+use saphir::Method;
+use saphir::*;
+use uuid::Uuid;
+
+use crate::jet_client::association::{Association, AssociationRequest};
+use crate::jet_client::association_store::JetAssociationStore;
+
+struct ControllerData {
+    association_store: JetAssociationStore,
+}
+
+pub struct AssociationsController {
+    dispatch: ControllerDispatch<ControllerData>,
+}
+
+impl AssociationsController {
+    pub fn new(association_store: JetAssociationStore) -> Self {
+        let dispatch = ControllerDispatch::new(ControllerData { association_store });
+        dispatch.add(Method::POST, "/", create_association);
+        dispatch.add(Method::GET, "/", list_associations);
+        dispatch.add(Method::GET, "/<id>", get_association);
+
+        AssociationsController { dispatch }
+    }
+}
+
+impl Controller for AssociationsController {
+    fn handle(&self, req: &mut SyncRequest, res: &mut SyncResponse) {
+        self.dispatch.dispatch(req, res);
+    }
+
+    fn base_path(&self) -> &str {
+        "/associations"
+    }
+}
+
+fn create_association(controller: &ControllerData, req: &SyncRequest, res: &mut SyncResponse) {
+    let request = if req.body().is_empty() {
+        AssociationRequest::default()
+    } else {
+        match serde_json::from_slice::<AssociationRequest>(req.body()) {
+            Ok(request) => request,
+            Err(e) => {
+                res.status(StatusCode::BAD_REQUEST)
+                    .body(format!("Invalid association request: {}", e));
+                return;
+            }
+        }
+    };
+
+    match controller.association_store.create(Association::from_request(request)) {
+        Ok(info) => send_json(res, StatusCode::CREATED, &info),
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            res.status(StatusCode::CONFLICT).body(e.to_string());
+        }
+        Err(e) => {
+            res.status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Failed to create association: {}", e));
+        }
+    }
+}
+
+fn list_associations(controller: &ControllerData, _req: &SyncRequest, res: &mut SyncResponse) {
+    send_json(res, StatusCode::OK, &controller.association_store.list());
+}
+
+fn get_association(controller: &ControllerData, req: &SyncRequest, res: &mut SyncResponse) {
+    let uuid = match req.captures().get("id").map(|id| Uuid::from_str(id)) {
+        Some(Ok(uuid)) => uuid,
+        _ => {
+            res.status(StatusCode::BAD_REQUEST).body("Invalid association ID");
+            return;
+        }
+    };
+
+    match controller.association_store.get(uuid) {
+        Some(info) => send_json(res, StatusCode::OK, &info),
+        None => {
+            res.status(StatusCode::NOT_FOUND)
+                .body(format!("Association {} not found", uuid));
+        }
+    }
+}
+
+fn send_json<T: serde::Serialize>(res: &mut SyncResponse, status: StatusCode, value: &T) {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            res.status(status).header("Content-Type", "application/json").body(body);
+        }
+        Err(e) => {
+            res.status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Failed to serialize response: {}", e));
+        }
+    }
+}
@@ -1,2 +1,4 @@
+pub mod associations;
 pub mod health;
-pub mod sessions;
\ No newline at end of file
+pub mod sessions;
+pub mod targets;
@@ -1,10 +1,9 @@
-use saphir::*;
+use crate::{REJECTED_CONNECTION_COUNT, SESSION_IN_PROGRESS_COUNT};
 use saphir::Method;
+use saphir::*;
 use std::sync::atomic::Ordering;
-use crate::SESSION_IN_PROGRESS_COUNT;
 
-struct ControllerData {
-}
+struct ControllerData {}
 
 pub struct SessionsController {
     dispatch: ControllerDispatch<ControllerData>,
@@ -12,14 +11,11 @@ pub struct SessionsController {
 
 impl SessionsController {
     pub fn new() -> Self {
-        let dispatch = ControllerDispatch::new(ControllerData{});
-        dispatch.add(Method::GET,
-                     "/count",
-                     sessions_count);
-
-        SessionsController {
-            dispatch
-        }
+        let dispatch = ControllerDispatch::new(ControllerData {});
+        dispatch.add(Method::GET, "/count", sessions_count);
+        dispatch.add(Method::GET, "/rejected", rejected_count);
+
+        SessionsController { dispatch }
     }
 }
 
@@ -34,5 +30,22 @@ impl Controller for SessionsController {
 }
 
 fn sessions_count(_: &ControllerData, _req: &SyncRequest, res: &mut SyncResponse) {
-    res.status(StatusCode::OK).body(SESSION_IN_PROGRESS_COUNT.load(Ordering::Relaxed).to_string().as_bytes().to_vec());
-}
\ No newline at end of file
+    res.status(StatusCode::OK).body(
+        SESSION_IN_PROGRESS_COUNT
+            .load(Ordering::Relaxed)
+            .to_string()
+            .as_bytes()
+            .to_vec(),
+    );
+}
+
+/// Number of connections rejected by the source address rules since the start
+fn rejected_count(_: &ControllerData, _req: &SyncRequest, res: &mut SyncResponse) {
+    res.status(StatusCode::OK).body(
+        REJECTED_CONNECTION_COUNT
+            .load(Ordering::Relaxed)
+            .to_string()
+            .as_bytes()
+            .to_vec(),
+    );
+}
@@ -0,0 +1,45 @@
+use saphir::Method;
+use saphir::*;
+
+use crate::health_check::JetHealthChecks;
+
+struct ControllerData {
+    health_checks: JetHealthChecks,
+}
+
+pub struct TargetsController {
+    dispatch: ControllerDispatch<ControllerData>,
+}
+
+impl TargetsController {
+    pub fn new(health_checks: JetHealthChecks) -> Self {
+        let dispatch = ControllerDispatch::new(ControllerData { health_checks });
+        dispatch.add(Method::GET, "/", list_targets);
+
+        TargetsController { dispatch }
+    }
+}
+
+impl Controller for TargetsController {
+    fn handle(&self, req: &mut SyncRequest, res: &mut SyncResponse) {
+        self.dispatch.dispatch(req, res);
+    }
+
+    fn base_path(&self) -> &str {
+        "/targets"
+    }
+}
+
+fn list_targets(controller: &ControllerData, _req: &SyncRequest, res: &mut SyncResponse) {
+    match serde_json::to_vec(&controller.health_checks.list()) {
+        Ok(body) => {
+            res.status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+        Err(e) => {
+            res.status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(format!("Failed to serialize response: {}", e));
+        }
+    }
+}
@@ -1,12 +1,14 @@
-use std::sync::Mutex;
-use saphir::ServerSpawn;
-use saphir::Server as SaphirServer;
-use log::info;
-use tokio::runtime::TaskExecutor;
+use crate::health_check::JetHealthChecks;
+use crate::http::controllers::associations::AssociationsController;
 use crate::http::controllers::health::HealthController;
 use crate::http::controllers::sessions::SessionsController;
-
-const HTTP_SERVER_PORT: u32 = 10256;
+use crate::http::controllers::targets::TargetsController;
+use crate::jet_client::association_store::JetAssociationStore;
+use log::info;
+use saphir::Server as SaphirServer;
+use saphir::ServerSpawn;
+use std::sync::Mutex;
+use tokio::runtime::TaskExecutor;
 
 pub struct HttpServer {
     pub server: SaphirServer,
@@ -14,7 +16,7 @@ pub struct HttpServer {
 }
 
 impl HttpServer {
-    pub fn new() -> HttpServer {
+    pub fn new(port: u16, association_store: JetAssociationStore, health_checks: JetHealthChecks) -> HttpServer {
         let http_server = SaphirServer::builder()
             .configure_middlewares(|middlewares| {
                 info!("Loading http middlewares");
@@ -24,13 +26,12 @@ impl HttpServer {
                 info!("Loading http controllers");
                 let health = HealthController::new();
                 let session = SessionsController::new();
+                let associations = AssociationsController::new(association_store.clone());
+                let targets = TargetsController::new(health_checks.clone());
                 info!("Configuring http router");
-                router.add(health)
-                    .add(session)
-            })
-            .configure_listener(|list_config| {
-                list_config.set_uri(&format!("http://0.0.0.0:{}", HTTP_SERVER_PORT))
+                router.add(health).add(session).add(associations).add(targets)
             })
+            .configure_listener(|list_config| list_config.set_uri(&format!("http://0.0.0.0:{}", port)))
             .build();
 
         HttpServer {
@@ -50,4 +51,4 @@ impl HttpServer {
             handle.terminate();
         }
     }
-}
\ No newline at end of file
+}
@@ -1,2 +1,2 @@
-pub mod http_server;
 pub mod controllers;
+pub mod http_server;
@@ -0,0 +1,62 @@
+use crate::interceptor::PacketInterceptor;
+use log::info;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// Counts the packets and bytes sent by each peer of a session and logs them when the session ends. The packets the
+/// interceptor missed are counted too.
+pub struct MetricsInterceptor {
+    session_id: Uuid,
+    server_addr: SocketAddr,
+    server_packets: u64,
+    server_bytes: u64,
+    client_packets: u64,
+    client_bytes: u64,
+}
+
+impl MetricsInterceptor {
+    pub fn new(session_id: Uuid, server_addr: SocketAddr) -> Self {
+        MetricsInterceptor {
+            session_id,
+            server_addr,
+            server_packets: 0,
+            server_bytes: 0,
+            client_packets: 0,
+            client_bytes: 0,
+        }
+    }
+}
+
+impl PacketInterceptor for MetricsInterceptor {
+    fn on_new_packet(&mut self, source_addr: Option<SocketAddr>, data: &[u8]) {
+        if source_addr == Some(self.server_addr) {
+            self.server_packets += 1;
+            self.server_bytes += data.len() as u64;
+        } else {
+            self.client_packets += 1;
+            self.client_bytes += data.len() as u64;
+        }
+    }
+
+    fn on_packets_lost(&mut self, source_addr: Option<SocketAddr>, packets: u64, bytes: u64) {
+        if source_addr == Some(self.server_addr) {
+            self.server_packets += packets;
+            self.server_bytes += bytes;
+        } else {
+            self.client_packets += packets;
+            self.client_bytes += bytes;
+        }
+    }
+
+    fn on_session_end(&mut self, aborted: bool) {
+        info!(
+            "Session {} {}: {} packets ({} bytes) sent by the server and {} packets ({} bytes) sent by the client",
+            self.session_id,
+            if aborted { "aborted" } else { "ended" },
+            self.server_packets,
+            self.server_bytes,
+            self.client_packets,
+            self.client_bytes
+        );
+    }
+}
@@ -1,11 +1,20 @@
 use byteorder::{LittleEndian, ReadBytesExt};
 use std::net::SocketAddr;
 
+pub mod metrics;
 pub mod pcap;
+pub mod pcapng;
+pub mod queue;
 pub mod rdp;
+pub mod rotation;
 
 pub trait PacketInterceptor: Send + Sync {
     fn on_new_packet(&mut self, source_addr: Option<SocketAddr>, data: &[u8]);
+    /// Called before the next event when packets sent by `source_addr` were not delivered to the interceptor because it
+    /// didn't keep up with the session
+    fn on_packets_lost(&mut self, _source_addr: Option<SocketAddr>, _packets: u64, _bytes: u64) {}
+    /// Called once the session ends, `aborted` when it ends with an error
+    fn on_session_end(&mut self, aborted: bool);
 }
 
 type MessageReader = dyn Fn(&mut Vec<u8>) -> Vec<Vec<u8>> + Send + Sync;
@@ -1,38 +1,389 @@
+use crate::interceptor::pcapng::{Direction, PcapNgWriter};
+use crate::interceptor::rotation::{CountingFile, PcapRotation, RotatedFiles};
 use crate::interceptor::MessageReader;
 use crate::interceptor::UnknownMessageReader;
 use crate::interceptor::{PacketInterceptor, PeerInfo};
-use log::{debug, error};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
 use packet::builder::Builder;
 use packet::ether::Builder as BuildEthernet;
 use packet::ether::Protocol;
 use packet::ip::v6::Builder as BuildV6;
 use packet::tcp::flag::Flags;
 use pcap_file::PcapWriter;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::mem;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 const TCP_IP_PACKET_MAX_SIZE: usize = 16384;
 
+const TCP_FIN: u16 = 0x01;
+const TCP_SYN: u16 = 0x02;
+const TCP_RST: u16 = 0x04;
+const TCP_PSH: u16 = 0x08;
+const TCP_ACK: u16 = 0x10;
+
+pub type JetPcapWriter = Arc<Mutex<CaptureWriter>>;
+
+/// Format of the capture files
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PcapFormat {
+    Pcap,
+    /// Adds an interface per relay leg, the direction of every packet and the session metadata as comments
+    PcapNg,
+}
+
+enum CaptureFile {
+    Pcap(PcapWriter<CountingFile>),
+    PcapNg(PcapNgWriter<CountingFile>),
+}
+
+/// Writer of a pcap or pcapng file, rotated according to its rotation settings. In pcapng, the interfaces are declared
+/// in each file before their first packet.
+pub struct CaptureWriter {
+    path: PathBuf,
+    format: PcapFormat,
+    comments: Vec<String>,
+    file: CaptureFile,
+    written: Arc<AtomicU64>,
+    opened: Instant,
+    rotation: PcapRotation,
+    rotated_files: RotatedFiles,
+    /// Name and description of the interfaces of the sessions in progress, by interface id
+    interfaces: HashMap<u32, (String, String)>,
+    next_interface_id: u32,
+    /// Ids in the current file of the interfaces declared in it
+    declared_interfaces: HashMap<u32, u32>,
+}
+
+impl CaptureWriter {
+    /// Creates the file. The comments describe the whole capture, they are dropped by the pcap format.
+    fn create(filename: &str, format: PcapFormat, comments: &[String], rotation: &PcapRotation) -> io::Result<Self> {
+        let path = PathBuf::from(filename);
+        let (file, written) = open_capture_file(&path, format, comments)?;
+        let rotated_files = RotatedFiles::new(&path, rotation.clone());
+        Ok(CaptureWriter {
+            path,
+            format,
+            comments: comments.to_vec(),
+            file,
+            written,
+            opened: Instant::now(),
+            rotation: rotation.clone(),
+            rotated_files,
+            interfaces: HashMap::new(),
+            next_interface_id: 0,
+            declared_interfaces: HashMap::new(),
+        })
+    }
+
+    /// Describes an interface and returns its id. Every packet of a pcap file is on the same interface.
+    fn add_interface(&mut self, name: &str, description: &str) -> u32 {
+        let interface_id = self.next_interface_id;
+        self.next_interface_id += 1;
+        self.interfaces
+            .insert(interface_id, (name.to_string(), description.to_string()));
+        interface_id
+    }
+
+    /// Forgets an interface once its session ended. No packet can be written on it afterwards.
+    fn remove_interface(&mut self, interface_id: u32) {
+        self.interfaces.remove(&interface_id);
+        self.declared_interfaces.remove(&interface_id);
+    }
+
+    /// Starts a new file if the current one is due for rotation. Called between the messages of the sessions, so a
+    /// message is never split between two files.
+    fn rotate_if_due(&mut self) {
+        if !self.rotation.is_due(self.written.load(Ordering::Relaxed), self.opened) {
+            return;
+        }
+
+        if let Err(e) = self.rotate() {
+            error!("Failed to rotate the pcap file {}: {}", self.path.display(), e);
+            // Not retried before the next period
+            self.opened = Instant::now();
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let size = self.written.load(Ordering::Relaxed);
+        self.rotated_files.rotate(&self.path, size)?;
+        let (file, written) = open_capture_file(&self.path, self.format, &self.comments)?;
+        self.file = file;
+        self.written = written;
+        self.opened = Instant::now();
+        self.declared_interfaces.clear();
+        Ok(())
+    }
+
+    fn write_packet(
+        &mut self,
+        interface_id: u32,
+        timestamp: Duration,
+        data: &[u8],
+        direction: Direction,
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        match &mut self.file {
+            CaptureFile::Pcap(writer) => writer
+                .write(timestamp.as_secs() as u32, timestamp.subsec_micros(), data)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+            CaptureFile::PcapNg(writer) => {
+                let file_interface_id = match self.declared_interfaces.get(&interface_id) {
+                    Some(file_interface_id) => *file_interface_id,
+                    None => {
+                        let (name, description) = self.interfaces.get(&interface_id).ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("Interface {} is not in the capture", interface_id),
+                            )
+                        })?;
+                        let file_interface_id = writer.add_interface(name, description)?;
+                        self.declared_interfaces.insert(interface_id, file_interface_id);
+                        file_interface_id
+                    }
+                };
+                writer.write_packet(file_interface_id, timestamp, data, direction, comment)
+            }
+        }
+    }
+}
+
+fn open_capture_file(
+    path: &Path,
+    format: PcapFormat,
+    comments: &[String],
+) -> io::Result<(CaptureFile, Arc<AtomicU64>)> {
+    let file = CountingFile::create(path).and_then(|file| {
+        let written = file.written();
+        let file = match format {
+            PcapFormat::Pcap => PcapWriter::new(file)
+                .map(CaptureFile::Pcap)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            PcapFormat::PcapNg => PcapNgWriter::new(file, comments).map(CaptureFile::PcapNg)?,
+        };
+        Ok((file, written))
+    });
+    file.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to create the pcap file {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Description of a captured session, written as comments of the pcapng captures
+#[derive(Clone, Debug)]
+pub struct SessionMetadata {
+    pub session_id: Uuid,
+    pub listener: String,
+    /// Destination of the session as chosen by the relay (routing url, TLS route, proxy destination...)
+    pub route: Option<String>,
+    /// Username of the authenticated RDP identity, or subject of the client certificate
+    pub identity: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn comment(&self) -> String {
+        let mut comment = format!("session_id={} listener={}", self.session_id, self.listener);
+        if let Some(route) = &self.route {
+            comment.push_str(&format!(" route={}", route));
+        }
+        if let Some(identity) = &self.identity {
+            comment.push_str(&format!(" identity={}", identity));
+        }
+        comment
+    }
+}
+
+/// Capture of a session: the writer with the interfaces of both relay legs
+pub struct PcapSession {
+    pcap_writer: JetPcapWriter,
+    /// Client address of the TCP connection in the capture
+    client_addr: SocketAddr,
+    client_interface: u32,
+    server_interface: u32,
+    comment: String,
+    /// File shared with other sessions, to release the addresses of the session in it once it ends
+    shared_file: Option<Arc<SharedPcapFile>>,
+}
+
+/// Where the sessions are captured
+#[derive(Clone)]
+pub enum PcapOutput {
+    /// A file per session, named from a template
+    PerSession(String, PcapFormat, PcapRotation),
+    /// Every session appended to a single file
+    Shared(Arc<SharedPcapFile>),
+}
+
+/// Capture shared by every session. Each session is saved as its own TCP connection.
+pub struct SharedPcapFile {
+    pcap_writer: JetPcapWriter,
+    /// (server, client) addresses of the connections of the sessions in progress
+    connections: Mutex<HashSet<(SocketAddr, SocketAddr)>>,
+}
+
+impl SharedPcapFile {
+    /// Lets another session use the addresses of a connection that was closed
+    fn release(&self, server_addr: SocketAddr, client_addr: SocketAddr) {
+        self.connections.lock().unwrap().remove(&(server_addr, client_addr));
+    }
+}
+
+impl PcapOutput {
+    /// The shared file is created immediately, replacing an existing one. Its section comment names the listener.
+    pub fn new(
+        filename: &str,
+        shared: bool,
+        format: PcapFormat,
+        rotation: PcapRotation,
+        listener: &str,
+    ) -> io::Result<Self> {
+        if shared {
+            let comments = [format!("listener={}", listener)];
+            Ok(PcapOutput::Shared(Arc::new(SharedPcapFile {
+                pcap_writer: Arc::new(Mutex::new(CaptureWriter::create(
+                    filename, format, &comments, &rotation,
+                )?)),
+                connections: Mutex::new(HashSet::new()),
+            })))
+        } else {
+            Ok(PcapOutput::PerSession(filename.to_string(), format, rotation))
+        }
+    }
+
+    /// Whether every session replaces the capture of the previous one
+    pub fn overwrites_sessions(&self) -> bool {
+        match self {
+            PcapOutput::PerSession(template, _, _) => !template.contains('{'),
+            PcapOutput::Shared(_) => false,
+        }
+    }
+
+    /// Capture of a session. In a shared capture, the client port is changed when the addresses of the session are
+    /// used by another session in progress.
+    pub fn open(
+        &self,
+        metadata: &SessionMetadata,
+        server_addr: SocketAddr,
+        client_addr: SocketAddr,
+    ) -> io::Result<PcapSession> {
+        let session_id = metadata.session_id;
+        let comment = metadata.comment();
+        let (pcap_writer, capture_addr, shared_file) = match self {
+            PcapOutput::PerSession(template, format, rotation) => {
+                let filename = expand_filename(template, session_id, Utc::now(), server_addr, client_addr);
+                if let Some(directory) = Path::new(&filename).parent() {
+                    fs::create_dir_all(directory)?;
+                }
+                info!("Session {} captured in {}", session_id, filename);
+                let writer = CaptureWriter::create(&filename, *format, &[comment.clone()], rotation)?;
+                (Arc::new(Mutex::new(writer)), client_addr, None)
+            }
+            PcapOutput::Shared(shared) => {
+                let mut connections = shared.connections.lock().unwrap();
+                let mut capture_addr = client_addr;
+                while !connections.insert((server_addr, capture_addr)) {
+                    let port = capture_addr.port().checked_add(1).unwrap_or(1024);
+                    capture_addr.set_port(port);
+                }
+                if capture_addr != client_addr {
+                    info!(
+                        "Client {} of session {} captured as {}",
+                        client_addr, session_id, capture_addr
+                    );
+                }
+                (shared.pcap_writer.clone(), capture_addr, Some(shared.clone()))
+            }
+        };
+
+        let (client_interface, server_interface) = {
+            let mut writer = pcap_writer.lock().unwrap();
+            (
+                writer.add_interface(
+                    "client",
+                    &format!("Client leg of session {}: {}", session_id, client_addr),
+                ),
+                writer.add_interface(
+                    "server",
+                    &format!("Server leg of session {}: {}", session_id, server_addr),
+                ),
+            )
+        };
+
+        Ok(PcapSession {
+            pcap_writer,
+            client_addr: capture_addr,
+            client_interface,
+            server_interface,
+            comment,
+            shared_file,
+        })
+    }
+}
+
+/// Replaces the placeholders of a filename template: {date}, {time}, {session_id}, {client_ip}, {client_port},
+/// {server_ip} and {server_port}. The colons of IPv6 addresses are replaced by dashes.
+fn expand_filename(
+    template: &str,
+    session_id: Uuid,
+    now: DateTime<Utc>,
+    server_addr: SocketAddr,
+    client_addr: SocketAddr,
+) -> String {
+    let ip = |addr: SocketAddr| addr.ip().to_string().replace(':', "-");
+    template
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{time}", &now.format("%H%M%S").to_string())
+        .replace("{session_id}", &session_id.to_string())
+        .replace("{client_ip}", &ip(client_addr))
+        .replace("{client_port}", &client_addr.port().to_string())
+        .replace("{server_ip}", &ip(server_addr))
+        .replace("{server_port}", &server_addr.port().to_string())
+}
+
+/// Saves the data relayed between a client and a server as a single TCP connection, opened by a three-way handshake
+/// when the session starts and closed by FIN segments, or a RST if the session is aborted. In pcapng, the segments of
+/// the client are on the client leg interface with the inbound direction and the segments of the server on the server
+/// leg interface with the outbound direction. The first packet carries the session metadata as comment. The data the
+/// interceptor missed is skipped in the sequence numbers, so the gap shows in the TCP stream.
 #[derive(Clone)]
 pub struct PcapInterceptor {
-    pcap_writer: Arc<Mutex<PcapWriter<File>>>,
+    pcap_writer: JetPcapWriter,
     server_info: Arc<Mutex<PeerInfo>>,
     client_info: Arc<Mutex<PeerInfo>>,
+    client_interface: u32,
+    server_interface: u32,
+    pending_comment: Arc<Mutex<Option<String>>>,
+    closed: Arc<AtomicBool>,
     message_reader: Arc<Box<MessageReader>>,
+    shared_file: Option<Arc<SharedPcapFile>>,
 }
 
 impl PcapInterceptor {
-    pub fn new(server_addr: SocketAddr, client_addr: SocketAddr, pcap_filename: &str) -> Self {
-        let file = File::create(pcap_filename).expect("Error creating file");
-        let pcap_writer: PcapWriter<File> = PcapWriter::new(file).expect("Error creating pcap writer");
-
-        PcapInterceptor {
+    pub fn new(server_addr: SocketAddr, session: PcapSession) -> Self {
+        let interceptor = PcapInterceptor {
             server_info: Arc::new(Mutex::new(PeerInfo::new(server_addr))),
-            client_info: Arc::new(Mutex::new(PeerInfo::new(client_addr))),
-            pcap_writer: Arc::new(Mutex::new(pcap_writer)),
+            client_info: Arc::new(Mutex::new(PeerInfo::new(session.client_addr))),
+            pcap_writer: session.pcap_writer,
+            client_interface: session.client_interface,
+            server_interface: session.server_interface,
+            pending_comment: Arc::new(Mutex::new(Some(session.comment))),
+            closed: Arc::new(AtomicBool::new(false)),
             message_reader: Arc::new(Box::new(UnknownMessageReader::get_messages)),
-        }
+            shared_file: session.shared_file,
+        };
+        interceptor.write_handshake();
+        interceptor
     }
 
     pub fn set_message_reader<F: 'static + Fn(&mut Vec<u8>) -> Vec<Vec<u8>> + Send + Sync>(
@@ -41,6 +392,78 @@ impl PcapInterceptor {
     ) {
         self.message_reader = Arc::new(Box::new(message_reader));
     }
+
+    /// SYN, SYN/ACK and ACK. The initial sequence numbers are 0, so the relative and absolute sequence numbers are the
+    /// same.
+    fn write_handshake(&self) {
+        let mut server_info = self.server_info.lock().unwrap();
+        let mut client_info = self.client_info.lock().unwrap();
+        let mut pcap_writer = self.pcap_writer.lock().unwrap();
+
+        self.write_segment(&mut pcap_writer, false, &mut client_info, &server_info, TCP_SYN, &[]);
+        self.write_segment(
+            &mut pcap_writer,
+            true,
+            &mut server_info,
+            &client_info,
+            TCP_SYN | TCP_ACK,
+            &[],
+        );
+        self.write_segment(&mut pcap_writer, false, &mut client_info, &server_info, TCP_ACK, &[]);
+    }
+
+    /// Writes a segment sent by `sender`, acknowledging everything sent by `receiver`. The sequence number of the sender
+    /// is advanced by the payload and the SYN and FIN flags.
+    fn write_segment(
+        &self,
+        pcap_writer: &mut CaptureWriter,
+        from_server: bool,
+        sender: &mut PeerInfo,
+        receiver: &PeerInfo,
+        flags: u16,
+        payload: &[u8],
+    ) {
+        let acknowledgment = if flags & TCP_ACK != 0 {
+            receiver.sequence_number
+        } else {
+            0
+        };
+        let tcpip_packet = build_segment(
+            sender.addr,
+            receiver.addr,
+            sender.sequence_number,
+            acknowledgment,
+            flags,
+            payload,
+        );
+        let (interface_id, direction) = if from_server {
+            (self.server_interface, Direction::Outbound)
+        } else {
+            (self.client_interface, Direction::Inbound)
+        };
+
+        // Write packet in pcap file
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("Time went backwards");
+        let comment = self.pending_comment.lock().unwrap().take();
+        if let Err(e) = pcap_writer.write_packet(
+            interface_id,
+            since_epoch,
+            tcpip_packet.as_ref(),
+            direction,
+            comment.as_deref(),
+        ) {
+            error!("Error writting pcap file: {}", e);
+        }
+
+        // Update the seq_number
+        let mut len = payload.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            len += 1;
+        }
+        sender.sequence_number = sender.sequence_number.wrapping_add(len);
+    }
 }
 
 impl PacketInterceptor for PcapInterceptor {
@@ -51,87 +474,345 @@ impl PacketInterceptor for PcapInterceptor {
         let mut client_info = self.client_info.lock().unwrap();
         let is_from_server = source_addr.unwrap() == server_info.addr;
 
-        let (messages, source_addr, dest_addr, seq_number, ack_number) = if is_from_server {
-            server_info.data.append(&mut data.to_vec());
-            (
-                (self.message_reader)(&mut server_info.data),
-                server_info.addr,
-                client_info.addr,
-                &mut client_info.sequence_number,
-                server_info.sequence_number,
-            )
+        let (sender, receiver) = if is_from_server {
+            (&mut *server_info, &*client_info)
         } else {
-            client_info.data.append(&mut data.to_vec());
-            (
-                (self.message_reader)(&mut client_info.data),
-                client_info.addr,
-                server_info.addr,
-                &mut server_info.sequence_number,
-                client_info.sequence_number,
-            )
+            (&mut *client_info, &*server_info)
         };
+        sender.data.extend_from_slice(data);
+        let messages = (self.message_reader)(&mut sender.data);
 
         for data in messages {
+            let mut pcap_writer = self.pcap_writer.lock().unwrap();
+            pcap_writer.rotate_if_due();
             for data_chunk in data.chunks(TCP_IP_PACKET_MAX_SIZE) {
-                // Build tcpip packet
-                let tcpip_packet = match (source_addr, dest_addr) {
-                    (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
-                        BuildEthernet::default()
-                            .destination([0x00, 0x15, 0x5D, 0x01, 0x64, 0x04].into())
-                            .unwrap() // 00:15:5D:01:64:04
-                            .source([0x00, 0x15, 0x5D, 0x01, 0x64, 0x01].into())
-                            .unwrap() // 00:15:5D:01:64:01
-                            .protocol(Protocol::Ipv4)
-                            .unwrap()
-                            .ip()
-                            .unwrap()
-                            .v4()
-                            .unwrap()
-                            .source(*source.ip())
-                            .unwrap()
-                            .destination(*dest.ip())
-                            .unwrap()
-                            .ttl(128)
-                            .unwrap()
-                            .tcp()
-                            .unwrap()
-                            .window(0x7fff)
-                            .unwrap()
-                            .source(source_addr.port())
-                            .unwrap()
-                            .destination(dest_addr.port())
-                            .unwrap()
-                            .acknowledgment(ack_number)
-                            .unwrap()
-                            .sequence(*seq_number)
-                            .unwrap()
-                            .flags(Flags::from_bits_truncate(0x0018))
-                            .unwrap()
-                            .payload(data_chunk)
-                            .unwrap()
-                            .build()
-                            .unwrap()
-                    }
-                    (SocketAddr::V6(_source), SocketAddr::V6(_dest)) => BuildV6::default().build().unwrap(),
-                    (_, _) => unreachable!(),
-                };
+                self.write_segment(
+                    &mut pcap_writer,
+                    is_from_server,
+                    sender,
+                    receiver,
+                    TCP_PSH | TCP_ACK,
+                    data_chunk,
+                );
+            }
+        }
+    }
 
-                // Write packet in pcap file
-                let since_epoch = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .expect("Time went backwards");
-                let mut pcap_writer = self.pcap_writer.lock().unwrap();
-                if let Err(e) = pcap_writer.write(
-                    since_epoch.as_secs() as u32,
-                    since_epoch.subsec_micros(),
-                    tcpip_packet.as_ref(),
-                ) {
-                    error!("Error writting pcap file: {}", e);
-                }
+    fn on_packets_lost(&mut self, source_addr: Option<SocketAddr>, packets: u64, bytes: u64) {
+        let mut server_info = self.server_info.lock().unwrap();
+        let mut client_info = self.client_info.lock().unwrap();
+        let is_from_server = source_addr == Some(server_info.addr);
+        let (sender, receiver, side) = if is_from_server {
+            (&mut *server_info, &*client_info, "server")
+        } else {
+            (&mut *client_info, &*server_info, "client")
+        };
 
-                // Update the seq_number
-                *seq_number += data_chunk.len() as u32;
+        // The message started before the gap can't be completed, it is written as is
+        if !sender.data.is_empty() {
+            let data = mem::take(&mut sender.data);
+            let mut pcap_writer = self.pcap_writer.lock().unwrap();
+            for data_chunk in data.chunks(TCP_IP_PACKET_MAX_SIZE) {
+                self.write_segment(
+                    &mut pcap_writer,
+                    is_from_server,
+                    sender,
+                    receiver,
+                    TCP_PSH | TCP_ACK,
+                    data_chunk,
+                );
             }
         }
+        sender.sequence_number = sender.sequence_number.wrapping_add(bytes as u32);
+
+        let loss = format!(
+            "{} packets ({} bytes) sent by the {} were not captured: the pcap interceptor was too slow",
+            packets, bytes, side
+        );
+        let mut pending_comment = self.pending_comment.lock().unwrap();
+        *pending_comment = Some(match pending_comment.take() {
+            Some(comment) => format!("{}\n{}", comment, loss),
+            None => loss,
+        });
+    }
+
+    fn on_session_end(&mut self, aborted: bool) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let mut server_info = self.server_info.lock().unwrap();
+        let mut client_info = self.client_info.lock().unwrap();
+        let mut pcap_writer = self.pcap_writer.lock().unwrap();
+        if aborted {
+            self.write_segment(
+                &mut pcap_writer,
+                false,
+                &mut client_info,
+                &server_info,
+                TCP_RST | TCP_ACK,
+                &[],
+            );
+        } else {
+            self.write_segment(
+                &mut pcap_writer,
+                false,
+                &mut client_info,
+                &server_info,
+                TCP_FIN | TCP_ACK,
+                &[],
+            );
+            self.write_segment(
+                &mut pcap_writer,
+                true,
+                &mut server_info,
+                &client_info,
+                TCP_FIN | TCP_ACK,
+                &[],
+            );
+            self.write_segment(&mut pcap_writer, false, &mut client_info, &server_info, TCP_ACK, &[]);
+        }
+
+        // The writer and the addresses are kept for the sessions in progress only
+        pcap_writer.remove_interface(self.client_interface);
+        pcap_writer.remove_interface(self.server_interface);
+        drop(pcap_writer);
+        if let Some(shared_file) = &self.shared_file {
+            shared_file.release(server_info.addr, client_info.addr);
+        }
+    }
+}
+
+/// Ethernet frame of a TCP segment
+fn build_segment(
+    source_addr: SocketAddr,
+    dest_addr: SocketAddr,
+    sequence_number: u32,
+    acknowledgment: u32,
+    flags: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    match (source_addr, dest_addr) {
+        (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
+            BuildEthernet::default()
+                .destination([0x00, 0x15, 0x5D, 0x01, 0x64, 0x04].into())
+                .unwrap() // 00:15:5D:01:64:04
+                .source([0x00, 0x15, 0x5D, 0x01, 0x64, 0x01].into())
+                .unwrap() // 00:15:5D:01:64:01
+                .protocol(Protocol::Ipv4)
+                .unwrap()
+                .ip()
+                .unwrap()
+                .v4()
+                .unwrap()
+                .source(*source.ip())
+                .unwrap()
+                .destination(*dest.ip())
+                .unwrap()
+                .ttl(128)
+                .unwrap()
+                .tcp()
+                .unwrap()
+                .window(0x7fff)
+                .unwrap()
+                .source(source_addr.port())
+                .unwrap()
+                .destination(dest_addr.port())
+                .unwrap()
+                .acknowledgment(acknowledgment)
+                .unwrap()
+                .sequence(sequence_number)
+                .unwrap()
+                .flags(Flags::from_bits_truncate(flags))
+                .unwrap()
+                .payload(payload)
+                .unwrap()
+                .build()
+                .unwrap()
+        }
+        (SocketAddr::V6(_source), SocketAddr::V6(_dest)) => BuildV6::default().build().unwrap(),
+        (_, _) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn expands_filename_templates() {
+        let session_id = Uuid::parse_str("2b2a3e9f-8e53-4fa3-a0b3-0d1b8c2e3d4f").unwrap();
+        let now = Utc.ymd(2019, 5, 17).and_hms(13, 4, 5);
+        let server_addr = "10.0.0.5:3389".parse().unwrap();
+        let client_addr = "[fd00::7]:50123".parse().unwrap();
+
+        assert_eq!(
+            expand_filename(
+                "captures/{date}/{session_id}_{client_ip}.pcap",
+                session_id,
+                now,
+                server_addr,
+                client_addr
+            ),
+            "captures/2019-05-17/2b2a3e9f-8e53-4fa3-a0b3-0d1b8c2e3d4f_fd00--7.pcap"
+        );
+        assert_eq!(
+            expand_filename(
+                "{time}_{client_port}_{server_ip}_{server_port}.pcap",
+                session_id,
+                now,
+                server_addr,
+                client_addr
+            ),
+            "130405_50123_10.0.0.5_3389.pcap"
+        );
+    }
+
+    fn metadata() -> SessionMetadata {
+        SessionMetadata {
+            session_id: Uuid::new_v4(),
+            listener: "tcp://0.0.0.0:8080".to_string(),
+            route: Some("tcp://10.0.0.5:3389".to_string()),
+            identity: None,
+        }
+    }
+
+    #[test]
+    fn describes_sessions() {
+        let mut metadata = metadata();
+        metadata.session_id = Uuid::parse_str("2b2a3e9f-8e53-4fa3-a0b3-0d1b8c2e3d4f").unwrap();
+        metadata.identity = Some("contractor".to_string());
+        assert_eq!(
+            metadata.comment(),
+            "session_id=2b2a3e9f-8e53-4fa3-a0b3-0d1b8c2e3d4f listener=tcp://0.0.0.0:8080 route=tcp://10.0.0.5:3389 \
+             identity=contractor"
+        );
+    }
+
+    #[test]
+    fn shared_captures_keep_sessions_apart() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let output = PcapOutput::new(
+            filename,
+            true,
+            PcapFormat::PcapNg,
+            PcapRotation::default(),
+            "tcp://0.0.0.0:8080",
+        )
+        .unwrap();
+        let server_addr = "10.0.0.5:3389".parse().unwrap();
+        let client_addr = "192.168.1.7:50123".parse().unwrap();
+
+        let first = output.open(&metadata(), server_addr, client_addr).unwrap();
+        let second = output.open(&metadata(), server_addr, client_addr).unwrap();
+        assert_eq!(first.client_addr, client_addr);
+        assert_eq!(second.client_addr, "192.168.1.7:50124".parse().unwrap());
+        // Every session has its own pair of interfaces in the file
+        assert_eq!((first.client_interface, first.server_interface), (0, 1));
+        assert_eq!((second.client_interface, second.server_interface), (2, 3));
+        assert!(!output.overwrites_sessions());
+    }
+
+    #[test]
+    fn rotated_files_declare_the_interfaces_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcapng");
+        let rotation = PcapRotation {
+            max_file_size: Some(1),
+            ..PcapRotation::default()
+        };
+        let mut writer = CaptureWriter::create(path.to_str().unwrap(), PcapFormat::PcapNg, &[], &rotation).unwrap();
+        let interface = writer.add_interface("client", "Client leg");
+
+        writer
+            .write_packet(interface, Duration::from_secs(0), b"data", Direction::Inbound, None)
+            .unwrap();
+        writer.rotate_if_due();
+        writer
+            .write_packet(interface, Duration::from_secs(1), b"data", Direction::Inbound, None)
+            .unwrap();
+
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 2);
+        let capture = fs::read(&path).unwrap();
+        assert!(capture
+            .windows(b"Client leg".len())
+            .any(|window| window == b"Client leg"));
+    }
+
+    #[test]
+    fn sessions_are_opened_and_closed() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let output = PcapOutput::new(
+            filename,
+            true,
+            PcapFormat::PcapNg,
+            PcapRotation::default(),
+            "tcp://0.0.0.0:8080",
+        )
+        .unwrap();
+        let server_addr = "10.0.0.5:3389".parse().unwrap();
+        let client_addr = "192.168.1.7:50123".parse().unwrap();
+        let session = output.open(&metadata(), server_addr, client_addr).unwrap();
+
+        let mut interceptor = PcapInterceptor::new(server_addr, session);
+        let sequence_numbers = |interceptor: &PcapInterceptor| {
+            (
+                interceptor.client_info.lock().unwrap().sequence_number,
+                interceptor.server_info.lock().unwrap().sequence_number,
+            )
+        };
+        // The SYN of each side counts for one byte
+        assert_eq!(sequence_numbers(&interceptor), (1, 1));
+
+        interceptor.on_new_packet(Some(client_addr), b"hello");
+        interceptor.on_new_packet(Some(server_addr), b"hi");
+        assert_eq!(sequence_numbers(&interceptor), (6, 3));
+
+        // The data the interceptor missed is skipped
+        interceptor.on_packets_lost(Some(server_addr), 2, 100);
+        assert_eq!(sequence_numbers(&interceptor), (6, 103));
+        assert!(interceptor.pending_comment.lock().unwrap().is_some());
+        interceptor.on_new_packet(Some(server_addr), b"hi");
+        assert_eq!(sequence_numbers(&interceptor), (6, 105));
+
+        // So does the FIN, written once for the session
+        interceptor.on_session_end(false);
+        interceptor.clone().on_session_end(true);
+        assert_eq!(sequence_numbers(&interceptor), (7, 106));
+    }
+
+    #[test]
+    fn ended_sessions_are_forgotten_by_shared_captures() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let filename = file.path().to_str().unwrap();
+        let output = PcapOutput::new(
+            filename,
+            true,
+            PcapFormat::PcapNg,
+            PcapRotation::default(),
+            "tcp://0.0.0.0:8080",
+        )
+        .unwrap();
+        let shared_file = match &output {
+            PcapOutput::Shared(shared_file) => shared_file.clone(),
+            PcapOutput::PerSession(..) => unreachable!(),
+        };
+        let server_addr = "10.0.0.5:3389".parse().unwrap();
+        let client_addr = "192.168.1.7:50123".parse().unwrap();
+
+        for _ in 0..2 {
+            let session = output.open(&metadata(), server_addr, client_addr).unwrap();
+            // The addresses of an ended session are used again
+            assert_eq!(session.client_addr, client_addr);
+            PcapInterceptor::new(server_addr, session).on_session_end(false);
+        }
+
+        assert!(shared_file.connections.lock().unwrap().is_empty());
+        let writer = shared_file.pcap_writer.lock().unwrap();
+        assert!(writer.interfaces.is_empty());
+        assert!(writer.declared_interfaces.is_empty());
     }
 }
@@ -0,0 +1,221 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{self, Write};
+use std::time::Duration;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const EPB_FLAGS: u16 = 2;
+
+/// Direction of a packet, written in the flags of its block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    fn flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// Writer of a pcapng file with a single section. Interfaces can be added at any time, packets refer to them by the
+/// id returned by `add_interface`.
+pub struct PcapNgWriter<W: Write> {
+    writer: W,
+    interface_count: u32,
+}
+
+impl<W: Write> PcapNgWriter<W> {
+    /// Writes the section header, with the comments describing the whole capture
+    pub fn new(mut writer: W, comments: &[String]) -> io::Result<Self> {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(BYTE_ORDER_MAGIC)?;
+        body.write_u16::<LittleEndian>(1)?;
+        body.write_u16::<LittleEndian>(0)?;
+        // Section length not specified
+        body.write_i64::<LittleEndian>(-1)?;
+
+        let mut options = Options::new();
+        for comment in comments {
+            options.push(OPT_COMMENT, comment.as_bytes());
+        }
+        options.push(
+            SHB_USERAPPL,
+            format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        body.extend_from_slice(&options.finish());
+
+        write_block(&mut writer, SECTION_HEADER_BLOCK, &body)?;
+        writer.flush()?;
+        Ok(PcapNgWriter {
+            writer,
+            interface_count: 0,
+        })
+    }
+
+    /// Describes a new ethernet interface and returns its id
+    pub fn add_interface(&mut self, name: &str, description: &str) -> io::Result<u32> {
+        let mut body = Vec::new();
+        body.write_u16::<LittleEndian>(LINKTYPE_ETHERNET)?;
+        body.write_u16::<LittleEndian>(0)?;
+        // No snapshot length limit
+        body.write_u32::<LittleEndian>(0)?;
+
+        let mut options = Options::new();
+        options.push(IF_NAME, name.as_bytes());
+        options.push(IF_DESCRIPTION, description.as_bytes());
+        body.extend_from_slice(&options.finish());
+
+        write_block(&mut self.writer, INTERFACE_DESCRIPTION_BLOCK, &body)?;
+        let interface_id = self.interface_count;
+        self.interface_count += 1;
+        Ok(interface_id)
+    }
+
+    /// Writes a packet captured on `interface_id`. The timestamp is the time since the Unix epoch, in microseconds in
+    /// the file.
+    pub fn write_packet(
+        &mut self,
+        interface_id: u32,
+        timestamp: Duration,
+        data: &[u8],
+        direction: Direction,
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let timestamp = timestamp.as_secs() * 1_000_000 + u64::from(timestamp.subsec_micros());
+        let mut body = Vec::with_capacity(data.len() + 64);
+        body.write_u32::<LittleEndian>(interface_id)?;
+        body.write_u32::<LittleEndian>((timestamp >> 32) as u32)?;
+        body.write_u32::<LittleEndian>(timestamp as u32)?;
+        body.write_u32::<LittleEndian>(data.len() as u32)?;
+        body.write_u32::<LittleEndian>(data.len() as u32)?;
+        body.extend_from_slice(data);
+        body.resize(padded_len(body.len()), 0);
+
+        let mut options = Options::new();
+        if let Some(comment) = comment {
+            options.push(OPT_COMMENT, comment.as_bytes());
+        }
+        let mut flags = Vec::new();
+        flags.write_u32::<LittleEndian>(direction.flags())?;
+        options.push(EPB_FLAGS, &flags);
+        body.extend_from_slice(&options.finish());
+
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK, &body)
+    }
+}
+
+/// Options of a block. Values longer than an option can hold are truncated.
+struct Options {
+    buffer: Vec<u8>,
+}
+
+impl Options {
+    fn new() -> Self {
+        Options { buffer: Vec::new() }
+    }
+
+    fn push(&mut self, code: u16, value: &[u8]) {
+        let value = &value[..value.len().min(usize::from(!0u16))];
+        self.buffer.extend_from_slice(&code.to_le_bytes());
+        self.buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        self.buffer.extend_from_slice(value);
+        self.buffer.resize(padded_len(self.buffer.len()), 0);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buffer.extend_from_slice(&OPT_ENDOFOPT.to_le_bytes());
+        self.buffer.extend_from_slice(&0u16.to_le_bytes());
+        self.buffer
+    }
+}
+
+fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total_len as usize);
+    block.write_u32::<LittleEndian>(block_type)?;
+    block.write_u32::<LittleEndian>(total_len)?;
+    block.extend_from_slice(body);
+    block.write_u32::<LittleEndian>(total_len)?;
+    writer.write_all(&block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::io::Cursor;
+
+    /// Type and body of every block of a pcapng file
+    fn blocks(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut cursor = Cursor::new(data);
+        let mut blocks = Vec::new();
+        while (cursor.position() as usize) < data.len() {
+            let block_type = cursor.read_u32::<LittleEndian>().unwrap();
+            let total_len = cursor.read_u32::<LittleEndian>().unwrap() as usize;
+            assert_eq!(total_len % 4, 0);
+            let start = cursor.position() as usize;
+            let body = data[start..start + total_len - 12].to_vec();
+            cursor.set_position((start + total_len - 12) as u64);
+            assert_eq!(cursor.read_u32::<LittleEndian>().unwrap() as usize, total_len);
+            blocks.push((block_type, body));
+        }
+        blocks
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn writes_sections_interfaces_and_packets() {
+        let mut writer = PcapNgWriter::new(Vec::new(), &["session_id=42".to_string()]).unwrap();
+        assert_eq!(writer.add_interface("client", "client leg").unwrap(), 0);
+        assert_eq!(writer.add_interface("server", "server leg").unwrap(), 1);
+        writer
+            .write_packet(1, Duration::new(5, 7_000), b"abcde", Direction::Outbound, Some("first"))
+            .unwrap();
+
+        let blocks = blocks(&writer.writer);
+        assert_eq!(blocks.len(), 4);
+
+        let (block_type, section) = &blocks[0];
+        assert_eq!(*block_type, SECTION_HEADER_BLOCK);
+        assert_eq!(section[..4], BYTE_ORDER_MAGIC.to_le_bytes());
+        assert!(contains(section, b"session_id=42"));
+
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert!(contains(&blocks[1].1, b"client leg"));
+
+        let (block_type, packet) = &blocks[3];
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        let mut cursor = Cursor::new(packet);
+        assert_eq!(cursor.read_u32::<LittleEndian>().unwrap(), 1);
+        let high = u64::from(cursor.read_u32::<LittleEndian>().unwrap());
+        let low = u64::from(cursor.read_u32::<LittleEndian>().unwrap());
+        assert_eq!((high << 32) | low, 5_000_007);
+        assert_eq!(cursor.read_u32::<LittleEndian>().unwrap(), 5);
+        assert_eq!(cursor.read_u32::<LittleEndian>().unwrap(), 5);
+        assert_eq!(packet[20..25], *b"abcde");
+        assert!(contains(packet, b"first"));
+        // epb_flags with the outbound direction, then the end of options
+        assert!(packet.ends_with(&[2, 0, 4, 0, 2, 0, 0, 0, 0, 0, 0, 0]));
+    }
+}
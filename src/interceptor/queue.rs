@@ -0,0 +1,398 @@
+use crate::interceptor::PacketInterceptor;
+use log::{error, warn};
+use std::mem;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of packets waiting for an interceptor before its new packets are dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// Kind of packet interceptor applied to the relayed sessions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterceptorKind {
+    /// Saves the sessions in the pcap files
+    Pcap,
+    /// Logs the packets and bytes intercepted in every session when it ends
+    Metrics,
+}
+
+impl InterceptorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            InterceptorKind::Pcap => "pcap",
+            InterceptorKind::Metrics => "metrics",
+        }
+    }
+}
+
+type InterceptorFactory = Box<dyn FnOnce() -> Option<Box<dyn PacketInterceptor>> + Send>;
+
+enum Event {
+    /// Creates the interceptor of the session
+    SessionStart(InterceptorFactory),
+    Packet(Option<SocketAddr>, Vec<u8>),
+    SessionEnd(bool),
+}
+
+/// Packets of a peer dropped because the queue was full
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct LostPackets {
+    source_addr: Option<SocketAddr>,
+    packets: u64,
+    bytes: u64,
+}
+
+/// Interceptor of a session, created by the first event of the session and disabled once it panics
+struct SessionInterceptor {
+    interceptor: Option<Box<dyn PacketInterceptor>>,
+    failed: bool,
+}
+
+/// Event of a session, with the packets of the session dropped since its previous event
+type Job = (Arc<Mutex<SessionInterceptor>>, Vec<LostPackets>, Event);
+
+/// Thread running the interceptors of one kind for every session. The relay only queues the events, so a slow or
+/// failing interceptor neither stalls the forwarding nor the other interceptors: when `QUEUE_SIZE` packets are
+/// waiting, the new packets are dropped for this interceptor only and reported to it with the next event of their
+/// session. The start and the end of the sessions are always queued.
+pub struct InterceptorWorker {
+    kind: InterceptorKind,
+    sender: Mutex<Sender<Job>>,
+    queued_packets: Arc<AtomicUsize>,
+    dropped_events: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl InterceptorWorker {
+    pub fn spawn(kind: InterceptorKind) -> Arc<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let queued_packets = Arc::new(AtomicUsize::new(0));
+        let worker_queued_packets = queued_packets.clone();
+        thread::Builder::new()
+            .name(format!("{} interceptor", kind.name()))
+            .spawn(move || InterceptorWorker::run(kind, receiver, worker_queued_packets))
+            .expect("interceptor thread can't be started.");
+
+        Arc::new(InterceptorWorker {
+            kind,
+            sender: Mutex::new(sender),
+            queued_packets,
+            dropped_events: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    pub fn kind(&self) -> InterceptorKind {
+        self.kind
+    }
+
+    fn run(kind: InterceptorKind, receiver: Receiver<Job>, queued_packets: Arc<AtomicUsize>) {
+        for (interceptor, lost_packets, event) in receiver {
+            if let Event::Packet(..) = event {
+                queued_packets.fetch_sub(1, Ordering::SeqCst);
+            }
+
+            let mut interceptor = interceptor.lock().unwrap_or_else(|e| e.into_inner());
+            if interceptor.failed {
+                continue;
+            }
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let create = match event {
+                    Event::SessionStart(create) => create,
+                    Event::Packet(source_addr, data) => {
+                        if let Some(interceptor) = interceptor.interceptor.as_mut() {
+                            report_lost_packets(interceptor.as_mut(), &lost_packets);
+                            interceptor.on_new_packet(source_addr, &data);
+                        }
+                        return;
+                    }
+                    Event::SessionEnd(aborted) => {
+                        if let Some(interceptor) = interceptor.interceptor.as_mut() {
+                            report_lost_packets(interceptor.as_mut(), &lost_packets);
+                            interceptor.on_session_end(aborted);
+                        }
+                        return;
+                    }
+                };
+                interceptor.interceptor = create();
+            }));
+            if result.is_err() {
+                error!("The {} interceptor failed, it is disabled for the session", kind.name());
+                interceptor.failed = true;
+            }
+        }
+    }
+
+    /// Takes a place in the queue for a packet. Returns false, and counts the packet as dropped, when the queue is full.
+    fn reserve_packet(&self) -> bool {
+        if self.queued_packets.fetch_add(1, Ordering::SeqCst) < QUEUE_SIZE {
+            return true;
+        }
+        self.queued_packets.fetch_sub(1, Ordering::SeqCst);
+
+        let dropped_events = self.dropped_events.fetch_add(1, Ordering::Relaxed) + 1;
+        // Logged for the first dropped packet, then less and less often
+        if dropped_events.is_power_of_two() {
+            warn!(
+                "The {} interceptor is too slow, {} packets dropped so far",
+                self.kind.name(),
+                dropped_events
+            );
+        }
+        false
+    }
+
+    fn submit(&self, job: Job) {
+        if self.sender.lock().unwrap().send(job).is_err() && !self.stopped.swap(true, Ordering::Relaxed) {
+            error!(
+                "The {} interceptor thread stopped, its events are dropped",
+                self.kind.name()
+            );
+        }
+    }
+}
+
+fn report_lost_packets(interceptor: &mut dyn PacketInterceptor, lost_packets: &[LostPackets]) {
+    for lost in lost_packets {
+        interceptor.on_packets_lost(lost.source_addr, lost.packets, lost.bytes);
+    }
+}
+
+/// Interceptor of a session whose events are handled by the thread of its worker
+#[derive(Clone)]
+pub struct QueuedInterceptor {
+    worker: Arc<InterceptorWorker>,
+    interceptor: Arc<Mutex<SessionInterceptor>>,
+    /// Packets dropped since the last event queued for the session
+    lost_packets: Arc<Mutex<Vec<LostPackets>>>,
+}
+
+impl QueuedInterceptor {
+    /// Runs the interceptor returned by `create` on the thread of `worker` for the events of a session. It is created
+    /// by that thread too, before the first event of the session. The events are ignored if `create` returns `None`.
+    pub fn new<F>(worker: Arc<InterceptorWorker>, create: F) -> Self
+    where
+        F: FnOnce() -> Option<Box<dyn PacketInterceptor>> + Send + 'static,
+    {
+        let interceptor = Arc::new(Mutex::new(SessionInterceptor {
+            interceptor: None,
+            failed: false,
+        }));
+        worker.submit((interceptor.clone(), Vec::new(), Event::SessionStart(Box::new(create))));
+        QueuedInterceptor {
+            worker,
+            interceptor,
+            lost_packets: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn submit(&self, event: Event) {
+        let lost_packets = mem::take(&mut *self.lost_packets.lock().unwrap());
+        self.worker.submit((self.interceptor.clone(), lost_packets, event));
+    }
+}
+
+impl PacketInterceptor for QueuedInterceptor {
+    fn on_new_packet(&mut self, source_addr: Option<SocketAddr>, data: &[u8]) {
+        if self.worker.reserve_packet() {
+            self.submit(Event::Packet(source_addr, data.to_vec()));
+            return;
+        }
+
+        let mut lost_packets = self.lost_packets.lock().unwrap();
+        match lost_packets.iter_mut().find(|lost| lost.source_addr == source_addr) {
+            Some(lost) => {
+                lost.packets += 1;
+                lost.bytes += data.len() as u64;
+            }
+            None => lost_packets.push(LostPackets {
+                source_addr,
+                packets: 1,
+                bytes: data.len() as u64,
+            }),
+        }
+    }
+
+    fn on_session_end(&mut self, aborted: bool) {
+        self.submit(Event::SessionEnd(aborted));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+    use std::time::Duration;
+
+    /// Interceptor blocked until the test releases it
+    struct BlockedInterceptor {
+        release: Arc<Mutex<()>>,
+    }
+
+    impl PacketInterceptor for BlockedInterceptor {
+        fn on_new_packet(&mut self, _source_addr: Option<SocketAddr>, _data: &[u8]) {
+            drop(self.release.lock());
+        }
+
+        fn on_session_end(&mut self, _aborted: bool) {}
+    }
+
+    /// Interceptor sending its events to the test, blocked after every packet until the test releases it
+    struct ForwardingInterceptor {
+        events: Mutex<Sender<String>>,
+        release: Arc<Mutex<()>>,
+    }
+
+    impl PacketInterceptor for ForwardingInterceptor {
+        fn on_new_packet(&mut self, _source_addr: Option<SocketAddr>, data: &[u8]) {
+            if data == b"panic" {
+                panic!("interceptor failure");
+            }
+            let _ = self
+                .events
+                .lock()
+                .unwrap()
+                .send(String::from_utf8_lossy(data).into_owned());
+            drop(self.release.lock());
+        }
+
+        fn on_packets_lost(&mut self, _source_addr: Option<SocketAddr>, packets: u64, bytes: u64) {
+            let _ = self
+                .events
+                .lock()
+                .unwrap()
+                .send(format!("lost packets={} bytes={}", packets, bytes));
+        }
+
+        fn on_session_end(&mut self, aborted: bool) {
+            let _ = self.events.lock().unwrap().send(format!("end aborted={}", aborted));
+        }
+    }
+
+    fn forwarding(events: Sender<String>) -> Option<Box<dyn PacketInterceptor>> {
+        Some(Box::new(ForwardingInterceptor {
+            events: Mutex::new(events),
+            release: Arc::new(Mutex::new(())),
+        }))
+    }
+
+    #[test]
+    fn slow_interceptors_do_not_block_the_others() {
+        let release = Arc::new(Mutex::new(()));
+        let blocked = release.lock().unwrap();
+        let (sender, events) = mpsc::channel();
+
+        let slow_worker = InterceptorWorker::spawn(InterceptorKind::Pcap);
+        let worker = InterceptorWorker::spawn(InterceptorKind::Metrics);
+        let slow_release = release.clone();
+        let mut slow = QueuedInterceptor::new(slow_worker.clone(), move || {
+            Some(Box::new(BlockedInterceptor { release: slow_release }) as Box<dyn PacketInterceptor>)
+        });
+        let mut other = QueuedInterceptor::new(worker.clone(), move || forwarding(sender));
+
+        // The queue of the slow interceptor overflows without blocking the caller
+        for _ in 0..QUEUE_SIZE + 10 {
+            slow.on_new_packet(None, b"data");
+        }
+        other.on_new_packet(None, b"data");
+        other.on_session_end(false);
+
+        assert!(slow_worker.dropped_events.load(Ordering::Relaxed) > 0);
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), "data");
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "end aborted=false"
+        );
+        assert_eq!(worker.dropped_events.load(Ordering::Relaxed), 0);
+        drop(blocked);
+    }
+
+    #[test]
+    fn failing_interceptors_are_disabled_for_the_session() {
+        let (sender, events) = mpsc::channel();
+        let worker = InterceptorWorker::spawn(InterceptorKind::Metrics);
+        let failing_sender = sender.clone();
+        let mut failing = QueuedInterceptor::new(worker.clone(), move || forwarding(failing_sender));
+        let mut other = QueuedInterceptor::new(worker.clone(), move || forwarding(sender));
+
+        failing.on_new_packet(None, b"panic");
+        failing.on_new_packet(None, b"ignored");
+        other.on_new_packet(None, b"data");
+        other.on_session_end(false);
+
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), "data");
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "end aborted=false"
+        );
+    }
+
+    #[test]
+    fn interceptors_are_created_by_their_thread() {
+        let (sender, events) = mpsc::channel();
+        let worker = InterceptorWorker::spawn(InterceptorKind::Metrics);
+        let mut uncreated = QueuedInterceptor::new(worker.clone(), || None);
+        let mut created = QueuedInterceptor::new(worker.clone(), move || {
+            let thread_name = thread::current().name().map(String::from).unwrap_or_default();
+            sender.send(thread_name).unwrap();
+            forwarding(sender)
+        });
+
+        // The events of a session without interceptor are ignored
+        uncreated.on_new_packet(None, b"ignored");
+        uncreated.on_session_end(true);
+        created.on_session_end(false);
+
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "metrics interceptor"
+        );
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "end aborted=false"
+        );
+    }
+
+    #[test]
+    fn dropped_packets_are_reported_with_the_next_event() {
+        let release = Arc::new(Mutex::new(()));
+        let blocked = release.lock().unwrap();
+        let (sender, events) = mpsc::channel();
+        let worker = InterceptorWorker::spawn(InterceptorKind::Pcap);
+        let interceptor_release = release.clone();
+        let mut interceptor = QueuedInterceptor::new(worker.clone(), move || {
+            Some(Box::new(ForwardingInterceptor {
+                events: Mutex::new(sender),
+                release: interceptor_release,
+            }) as Box<dyn PacketInterceptor>)
+        });
+
+        // The thread is blocked by the first packet, then the queue is filled and the next packets are dropped
+        interceptor.on_new_packet(None, b"data");
+        assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), "data");
+        for _ in 0..QUEUE_SIZE + 10 {
+            interceptor.on_new_packet(None, b"data");
+        }
+        // The end of the session is never dropped
+        interceptor.on_session_end(false);
+        drop(blocked);
+
+        for _ in 0..QUEUE_SIZE {
+            assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), "data");
+        }
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "lost packets=10 bytes=40"
+        );
+        assert_eq!(
+            events.recv_timeout(Duration::from_secs(5)).unwrap(),
+            "end aborted=false"
+        );
+        assert_eq!(worker.dropped_events.load(Ordering::Relaxed), 10);
+    }
+}
@@ -0,0 +1,498 @@
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// When the pcap files are rotated and how many rotated files are kept
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PcapRotation {
+    /// Size in bytes after which the file is rotated
+    pub max_file_size: Option<u64>,
+    /// Time after which the file is rotated
+    pub interval: Option<Duration>,
+    /// Number of rotated files kept
+    pub max_files: Option<usize>,
+    /// Total size in bytes of the rotated files kept
+    pub max_total_size: Option<u64>,
+    /// Whether the rotated files are compressed with gzip
+    pub compress: bool,
+}
+
+impl PcapRotation {
+    /// Whether a file of `size` bytes opened at `opened` has to be rotated
+    pub fn is_due(&self, size: u64, opened: Instant) -> bool {
+        self.max_file_size.map(|max| size >= max).unwrap_or(false)
+            || self
+                .interval
+                .map(|interval| opened.elapsed() >= interval)
+                .unwrap_or(false)
+    }
+}
+
+/// File counting the bytes written in it
+pub struct CountingFile {
+    file: File,
+    written: Arc<AtomicU64>,
+}
+
+impl CountingFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(CountingFile {
+            file: File::create(path)?,
+            written: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Counter that keeps counting once the file is owned by a writer
+    pub fn written(&self) -> Arc<AtomicU64> {
+        self.written.clone()
+    }
+}
+
+impl Write for CountingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.written.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Files rotated by a writer, oldest first, including the files rotated by a previous run
+pub struct RotatedFiles {
+    rotation: PcapRotation,
+    files: VecDeque<(PathBuf, u64)>,
+    /// Thread compressing the rotated files, which tracks them itself when they are compressed
+    compression: Option<Sender<(PathBuf, u64)>>,
+}
+
+impl RotatedFiles {
+    /// Tracks the rotated files of `path` found in its directory, so they count for the retention limits
+    pub fn new(path: &Path, rotation: PcapRotation) -> Self {
+        let files = match previously_rotated(path) {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Failed to find the rotated files of {}: {}", path.display(), e);
+                VecDeque::new()
+            }
+        };
+        RotatedFiles {
+            rotation,
+            files,
+            compression: None,
+        }
+    }
+
+    /// Renames the file at `path`, which holds `size` bytes, to its rotated name and deletes the oldest files beyond
+    /// the retention limits. When the files are compressed, they are compressed and deleted by a background thread.
+    pub fn rotate(&mut self, path: &Path, size: u64) -> io::Result<PathBuf> {
+        let compression = if self.rotation.compress {
+            Some(self.compression()?)
+        } else {
+            None
+        };
+
+        let rotated = rotated_path(path, Utc::now());
+        fs::rename(path, &rotated)?;
+        info!("pcap file {} rotated to {}", path.display(), rotated.display());
+
+        match compression {
+            Some(compression) => {
+                if compression.send((rotated.clone(), size)).is_err() {
+                    error!(
+                        "pcap file {} not compressed: the compression thread stopped",
+                        rotated.display()
+                    );
+                    self.compression = None;
+                }
+            }
+            None => {
+                self.files.push_back((rotated.clone(), size));
+                apply_retention(&self.rotation, &mut self.files);
+            }
+        }
+        Ok(rotated)
+    }
+
+    /// The compression thread, started with the first rotation
+    fn compression(&mut self) -> io::Result<Sender<(PathBuf, u64)>> {
+        if let Some(compression) = &self.compression {
+            return Ok(compression.clone());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let rotation = self.rotation.clone();
+        let files = mem::take(&mut self.files);
+        thread::Builder::new()
+            .name("pcap compression".to_string())
+            .spawn(move || compress_files(rotation, files, receiver))?;
+        self.compression = Some(sender.clone());
+        Ok(sender)
+    }
+}
+
+/// Compresses the rotated files one after the other, starting with the files a previous run didn't compress. A file
+/// counts for the retention limits once it is compressed, so it is never deleted while it is read.
+fn compress_files(
+    rotation: PcapRotation,
+    previous_files: VecDeque<(PathBuf, u64)>,
+    receiver: Receiver<(PathBuf, u64)>,
+) {
+    let (mut files, uncompressed): (VecDeque<_>, VecDeque<_>) = previous_files
+        .into_iter()
+        .partition(|(path, _)| path.extension() == Some(OsStr::new("gz")));
+    for (rotated, size) in uncompressed.into_iter().chain(receiver) {
+        let path = match compress(&rotated) {
+            Ok(compressed) => compressed,
+            Err(e) => {
+                error!("Failed to compress the pcap file {}: {}", rotated.display(), e);
+                rotated
+            }
+        };
+        files.push_back((path, size));
+        apply_retention(&rotation, &mut files);
+    }
+}
+
+/// Deletes the oldest files until the retention limits are kept. Sizes are counted before compression.
+fn apply_retention(rotation: &PcapRotation, files: &mut VecDeque<(PathBuf, u64)>) {
+    loop {
+        let total_size: u64 = files.iter().map(|(_, size)| size).sum();
+        let too_many = rotation.max_files.map(|max| files.len() > max).unwrap_or(false);
+        let too_large = rotation.max_total_size.map(|max| total_size > max).unwrap_or(false);
+        if !too_many && !too_large {
+            break;
+        }
+
+        let (oldest, _) = match files.pop_front() {
+            Some(oldest) => oldest,
+            None => break,
+        };
+        match fs::remove_file(&oldest) {
+            Ok(()) => info!("pcap file {} deleted by the retention limits", oldest.display()),
+            Err(e) => error!("Failed to delete the pcap file {}: {}", oldest.display(), e),
+        }
+    }
+}
+
+/// `<name>.<UTC date and time>.<extension>`, with a counter added if that file already exists
+fn rotated_path(path: &Path, now: DateTime<Utc>) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_os_string()).unwrap_or_default();
+    let timestamp = now.format("%Y%m%d-%H%M%S").to_string();
+    let file_name = |counter: u32| {
+        let mut file_name = stem.clone();
+        file_name.push(".");
+        file_name.push(&timestamp);
+        if counter > 0 {
+            file_name.push(format!("-{}", counter));
+        }
+        if let Some(extension) = path.extension() {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+        path.with_file_name(file_name)
+    };
+
+    let mut counter = 0;
+    while file_name(counter).exists() || gzip_path(&file_name(counter)).exists() {
+        counter += 1;
+    }
+    file_name(counter)
+}
+
+/// Files of a previous run named by `rotated_path`, compressed or not, oldest first
+fn previously_rotated(path: &Path) -> io::Result<VecDeque<(PathBuf, u64)>> {
+    let directory = match path.parent() {
+        Some(directory) if directory != Path::new("") => directory,
+        _ => Path::new("."),
+    };
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let extension = path.extension().and_then(OsStr::to_str);
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let rotation_time = match file_name.to_str() {
+            Some(file_name) => parse_rotated_name(file_name, stem, extension),
+            None => None,
+        };
+        if let Some(rotation_time) = rotation_time {
+            files.push((rotation_time, entry.path(), entry.metadata()?.len()));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path, size)| (path, size)).collect())
+}
+
+/// UTC date and time and counter of a rotated file name, which sort the files from the oldest
+fn parse_rotated_name(file_name: &str, stem: &str, extension: Option<&str>) -> Option<(String, u32)> {
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let rest = file_name.strip_prefix(stem)?.strip_prefix('.')?;
+    let rest = match extension {
+        Some(extension) => rest.strip_suffix(extension)?.strip_suffix('.')?,
+        None => rest,
+    };
+
+    let (timestamp, counter) = match rest.get(15..) {
+        Some("") => (rest, 0),
+        Some(counter) => (&rest[..15], counter.strip_prefix('-')?.parse().ok()?),
+        None => return None,
+    };
+    Utc.datetime_from_str(timestamp, "%Y%m%d-%H%M%S").ok()?;
+    Some((timestamp.to_string(), counter))
+}
+
+fn gzip_path(path: &Path) -> PathBuf {
+    let mut gzip_path = OsString::from(path.as_os_str());
+    gzip_path.push(".gz");
+    PathBuf::from(gzip_path)
+}
+
+/// Replaces the file by its gzip compressed version and returns its path. The file is kept as is if it can't be
+/// compressed.
+fn compress(path: &Path) -> io::Result<PathBuf> {
+    let compressed = gzip_path(path);
+    let result = File::open(path).and_then(|mut source| {
+        let mut encoder = GzEncoder::new(File::create(&compressed)?, Compression::default());
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()?.sync_all()
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&compressed);
+        return Err(e);
+    }
+
+    fs::remove_file(path)?;
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn rotates_by_size_and_time() {
+        let rotation = PcapRotation {
+            max_file_size: Some(1000),
+            ..PcapRotation::default()
+        };
+        assert!(!rotation.is_due(999, Instant::now()));
+        assert!(rotation.is_due(1000, Instant::now()));
+
+        let rotation = PcapRotation {
+            interval: Some(Duration::from_secs(0)),
+            ..PcapRotation::default()
+        };
+        assert!(rotation.is_due(0, Instant::now()));
+        assert!(!PcapRotation::default().is_due(u64::max_value(), Instant::now()));
+    }
+
+    #[test]
+    fn parses_rotated_names() {
+        assert_eq!(
+            parse_rotated_name("capture.20190517-130405.pcap", "capture", Some("pcap")),
+            Some(("20190517-130405".to_string(), 0))
+        );
+        assert_eq!(
+            parse_rotated_name("capture.20190517-130405-2.pcap.gz", "capture", Some("pcap")),
+            Some(("20190517-130405".to_string(), 2))
+        );
+        assert_eq!(
+            parse_rotated_name("capture.20190517-130405", "capture", None),
+            Some(("20190517-130405".to_string(), 0))
+        );
+        assert_eq!(
+            parse_rotated_name("capture.20190517-130405.pcapng", "capture", Some("pcap")),
+            None
+        );
+        assert_eq!(
+            parse_rotated_name("capture.2019-05-17.pcap", "capture", Some("pcap")),
+            None
+        );
+    }
+
+    #[test]
+    fn names_rotated_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcapng");
+        let now = Utc.ymd(2019, 5, 17).and_hms(13, 4, 5);
+
+        let rotated = rotated_path(&path, now);
+        assert_eq!(rotated, directory.path().join("capture.20190517-130405.pcapng"));
+        fs::write(&rotated, b"").unwrap();
+        assert_eq!(
+            rotated_path(&path, now),
+            directory.path().join("capture.20190517-130405-1.pcapng")
+        );
+    }
+
+    #[test]
+    fn deletes_the_oldest_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcap");
+        let mut rotated_files = RotatedFiles::new(
+            &path,
+            PcapRotation {
+                max_files: Some(2),
+                max_total_size: Some(25),
+                ..PcapRotation::default()
+            },
+        );
+
+        let mut rotated = Vec::new();
+        for size in &[10, 10, 10, 20] {
+            fs::write(&path, b"").unwrap();
+            rotated.push(rotated_files.rotate(&path, *size).unwrap());
+        }
+
+        // Two files at most, then 25 bytes at most
+        let remaining = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, [rotated[3].clone()]);
+    }
+
+    #[test]
+    fn deletes_the_oldest_files_once_compressed() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcap");
+        let mut rotated_files = RotatedFiles::new(
+            &path,
+            PcapRotation {
+                max_files: Some(2),
+                compress: true,
+                ..PcapRotation::default()
+            },
+        );
+
+        let mut rotated = Vec::new();
+        for _ in 0..4 {
+            fs::write(&path, b"captured data").unwrap();
+            rotated.push(gzip_path(&rotated_files.rotate(&path, 13).unwrap()));
+        }
+
+        // The files are compressed and deleted by the compression thread
+        let mut expected = vec![rotated[2].clone(), rotated[3].clone()];
+        expected.sort();
+        for _ in 0..500 {
+            let mut remaining = fs::read_dir(directory.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>();
+            remaining.sort();
+            if remaining == expected {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The rotated files were not compressed and deleted");
+    }
+
+    #[test]
+    fn deletes_the_files_rotated_by_a_previous_run() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcap");
+        let previous = [
+            "capture.20190517-130405.pcap.gz",
+            "capture.20190517-130405-1.pcap.gz",
+            "capture.20190518-090000.pcap",
+        ];
+        for file_name in &previous {
+            fs::write(directory.path().join(file_name), b"captured data").unwrap();
+        }
+        // Not rotated from capture.pcap
+        let others = ["capture.pcapng", "capture.old.pcap", "other.20190517-130405.pcap"];
+        for file_name in &others {
+            fs::write(directory.path().join(file_name), b"").unwrap();
+        }
+
+        let mut rotated_files = RotatedFiles::new(
+            &path,
+            PcapRotation {
+                max_files: Some(2),
+                ..PcapRotation::default()
+            },
+        );
+        fs::write(&path, b"").unwrap();
+        let rotated = rotated_files.rotate(&path, 13).unwrap();
+
+        let mut remaining = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        let mut expected = others
+            .iter()
+            .chain(&previous[2..])
+            .map(|file_name| directory.path().join(file_name))
+            .chain(Some(rotated))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(remaining, expected);
+    }
+
+    #[test]
+    fn compresses_the_files_left_by_a_previous_run() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcap");
+        let previous = directory.path().join("capture.20190517-130405.pcap");
+        fs::write(&previous, b"captured data").unwrap();
+
+        let mut rotated_files = RotatedFiles::new(
+            &path,
+            PcapRotation {
+                max_files: Some(1),
+                compress: true,
+                ..PcapRotation::default()
+            },
+        );
+        fs::write(&path, b"captured data").unwrap();
+        let rotated = gzip_path(&rotated_files.rotate(&path, 13).unwrap());
+
+        // The previous file is compressed first, then deleted when the new one is compressed
+        for _ in 0..500 {
+            let remaining = fs::read_dir(directory.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>();
+            if remaining == [rotated.clone()] {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("The previous file was not compressed and deleted");
+    }
+
+    #[test]
+    fn compresses_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("capture.pcap");
+        fs::write(&path, b"captured data").unwrap();
+
+        assert_eq!(compress(&path).unwrap(), gzip_path(&path));
+        assert!(!path.exists());
+        let mut data = String::new();
+        GzDecoder::new(File::open(gzip_path(&path)).unwrap())
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "captured data");
+    }
+}
@@ -1,62 +1,120 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+pub mod association;
+pub mod association_store;
+pub mod keepalive;
+pub mod multiplexed;
+pub mod resumable;
+
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use std::{env, io, str};
 
-use futures::future::{err, ok};
+use futures::future::{err, ok, Either};
 use futures::{try_ready, Async, Future};
 use tokio::runtime::TaskExecutor;
 use tokio::timer::Delay;
+use tokio_io::io::{shutdown, write_all};
 use tokio_io::{AsyncRead, AsyncWrite};
+use url::Url;
 
 use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use lazy_static::lazy_static;
 use uuid::Uuid;
 
-use jet_proto::{JetPacket, ResponseStatusCode};
+use jet_proto::{
+    JetMethod, JetPacket, ResponseStatusCode, JET_VERSION_CONNECTED_NOTIFICATION, JET_VERSION_NOTIFICATIONS,
+};
 use log::{debug, error, info};
 
 use crate::config::Config;
+use crate::transport::tcp::TcpTransport;
 use crate::transport::JetTransport;
 use crate::Proxy;
 
-pub type JetAssociationsMap = Arc<Mutex<HashMap<Uuid, JetTransport>>>;
+use self::association::{Association, AssociationInfo, ConnectingClient, ServerConnectionOptions};
+use self::association_store::JetAssociationStore;
+use self::keepalive::{build_pong, KeepaliveHandle, ParkedKeepalive, ReleaseRequests};
+use self::multiplexed::MultiplexedProxy;
+use self::resumable::{JetResumableSessions, ResumableProxy};
 
 lazy_static! {
     static ref JET_INSTANCE: Option<String> = { env::var("JET_INSTANCE").ok() };
 }
 
-const ACCEPT_REQUEST_TIMEOUT_SEC: u64 = 5 * 60;
-
 pub struct JetClient {
     config: Config,
-    jet_associations: JetAssociationsMap,
+    association_store: JetAssociationStore,
+    resumable_sessions: JetResumableSessions,
     _executor_handle: TaskExecutor,
 }
 
 impl JetClient {
-    pub fn new(config: Config, jet_associations: JetAssociationsMap, executor_handle: TaskExecutor) -> Self {
+    pub fn new(
+        config: Config,
+        association_store: JetAssociationStore,
+        resumable_sessions: JetResumableSessions,
+        executor_handle: TaskExecutor,
+    ) -> Self {
         JetClient {
             config,
-            jet_associations,
+            association_store,
+            resumable_sessions,
             _executor_handle: executor_handle,
         }
     }
 
     pub fn serve(self, transport: JetTransport) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
         let msg_reader = JetMsgReader::new(transport.clone());
-        let jet_associations = self.jet_associations.clone();
+        let association_store = self.association_store.clone();
+        let resumable_sessions = self.resumable_sessions.clone();
         let executor_handle = self._executor_handle.clone();
         let config = self.config.clone();
 
         Box::new(msg_reader.and_then(move |msg| {
             if msg.is_accept() {
-                let handle_msg = HandleAcceptJetMsg::new(transport.clone(), msg, jet_associations, executor_handle);
+                let handle_msg =
+                    HandleAcceptJetMsg::new(&config, transport.clone(), msg, association_store, executor_handle);
                 Box::new(handle_msg) as Box<dyn Future<Item = (), Error = io::Error> + Send>
             } else if msg.is_connect() {
-                let handle_msg = HandleConnectJetMsg::new(transport.clone(), msg, jet_associations);
-                Box::new(handle_msg.and_then(|(t1, t2)| Proxy::new(config).build(t1, t2)))
-                    as Box<dyn Future<Item = (), Error = io::Error> + Send>
+                let handle_msg = HandleConnectJetMsg::new(&config, transport.clone(), msg.clone(), association_store);
+                Box::new(
+                    handle_msg.and_then(move |(destination, client_transport)| match destination {
+                        ConnectDestination::Server {
+                            transport: server_transport,
+                            options,
+                            client_resume_token,
+                        } => {
+                            let association = msg.association().unwrap();
+                            if options.multiplexed {
+                                Box::new(MultiplexedProxy::new(
+                                    &config,
+                                    association,
+                                    server_transport,
+                                    client_transport,
+                                ))
+                            } else if options.resume_token.is_some() || client_resume_token.is_some() {
+                                Box::new(ResumableProxy::new(
+                                    &config,
+                                    association,
+                                    server_transport,
+                                    options.resume_token,
+                                    client_transport,
+                                    client_resume_token,
+                                    resumable_sessions,
+                                    executor_handle,
+                                ))
+                            } else {
+                                Proxy::new(config)
+                                    .with_route(format!("JET association {}", association))
+                                    .build(server_transport, client_transport)
+                            }
+                        }
+                        ConnectDestination::Peer(instance, peer_url) => {
+                            forward_connect_to_peer(config, msg, instance, peer_url, client_transport)
+                        }
+                    }),
+                ) as Box<dyn Future<Item = (), Error = io::Error> + Send>
+            } else if msg.is_resume() {
+                resumable_sessions.resume(transport.clone(), msg)
             } else {
                 Box::new(err(error_other("Invalid method"))) as Box<dyn Future<Item = (), Error = io::Error> + Send>
             }
@@ -68,9 +126,68 @@ fn error_other(desc: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, desc)
 }
 
+fn is_local_instance(instance: &str) -> bool {
+    JET_INSTANCE.as_ref().map(|local| local == instance).unwrap_or(false)
+}
+
+/// Sends the connect request to the instance where the server is connected and splices the client with that instance.
+fn forward_connect_to_peer(
+    config: Config,
+    mut request_msg: JetPacket,
+    instance: String,
+    peer_url: Url,
+    client_transport: JetTransport,
+) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+    let association = request_msg.association().unwrap();
+    info!(
+        "Association {} is not local. Connect request forwarded to instance {} ({})",
+        association, instance, peer_url
+    );
+
+    // The peer must handle the request itself, it never forwards it again
+    request_msg.set_jet_instance(Some(instance.clone()));
+    request_msg.set_client_address(client_transport.peer_addr().map(|addr| addr.to_string()));
+    let mut request = Vec::new();
+    if let Err(e) = request_msg.write_to(&mut request) {
+        return Box::new(err(e));
+    }
+
+    Box::new(
+        TcpTransport::connect_through(&peer_url, config.upstream_tls_verification(), config.upstream_proxy())
+            .map(JetTransport::Tcp)
+            .and_then(move |peer_transport| write_all(peer_transport, request))
+            .and_then(|(peer_transport, _)| {
+                JetMsgReader::new(peer_transport.clone()).map(move |response| (peer_transport, response))
+            })
+            .and_then(move |(peer_transport, response)| {
+                let mut v = Vec::new();
+                response.write_to(&mut v)?;
+                Ok((peer_transport, response, v))
+            })
+            .and_then(move |(peer_transport, response, v)| {
+                write_all(client_transport, v).and_then(move |(client_transport, _)| {
+                    if response.response_status_code() == Some(&ResponseStatusCode::StatusCode200) {
+                        Either::A(
+                            Proxy::new(config)
+                                .with_route(format!("JET association {} on instance {}", association, instance))
+                                .build(peer_transport, client_transport),
+                        )
+                    } else {
+                        Either::B(err(error_other(&format!(
+                            "Instance {} refused the connect request for association {}",
+                            instance, association
+                        ))))
+                    }
+                })
+            }),
+    )
+}
+
+/// Reads a JetPacket. Ping messages received before it are answered with a Pong.
 struct JetMsgReader {
     transport: JetTransport,
     data_received: Vec<u8>,
+    pong: Vec<u8>,
 }
 
 impl JetMsgReader {
@@ -78,50 +195,77 @@ impl JetMsgReader {
         JetMsgReader {
             transport,
             data_received: Vec::new(),
+            pong: Vec::new(),
         }
     }
-}
 
-impl Future for JetMsgReader {
-    type Item = JetPacket;
-    type Error = io::Error;
+    fn poll_packet(&mut self) -> Result<Async<JetPacket>, io::Error> {
+        loop {
+            // Only read the bytes of the JetPacket. The data following it has to stay in the socket for the proxy.
+            let needed = if self.data_received.len() >= jet_proto::JET_MSG_HEADER_SIZE as usize {
+                let mut slice = self.data_received.as_slice();
+                let signature = slice.read_u32::<LittleEndian>()?; // signature
+                if signature != jet_proto::JET_MSG_SIGNATURE {
+                    return Err(error_other(&format!("Invalid JetPacket - Signature = {}.", signature)));
+                }
 
-    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
-        let mut buff = [0u8; 1024];
-        let len = try_ready!(self.transport.poll_read(&mut buff));
-        let mut buf = buff.to_vec();
-        buf.truncate(len);
-        self.data_received.append(&mut buf);
-
-        if self.data_received.len() >= jet_proto::JET_MSG_HEADER_SIZE as usize {
-            let mut slice = self.data_received.as_slice();
-            let signature = slice.read_u32::<LittleEndian>()?; // signature
-            if signature != jet_proto::JET_MSG_SIGNATURE {
-                return Err(error_other(&format!("Invalid JetPacket - Signature = {}.", signature)));
-            }
+                let msg_len = slice.read_u16::<BigEndian>()?;
 
-            let msg_len = slice.read_u16::<BigEndian>()?;
+                if self.data_received.len() >= msg_len as usize {
+                    let mut slice = self.data_received.as_slice();
+                    let jet_packet = jet_proto::JetPacket::read_from(&mut slice)?;
+                    debug!("jet_packet received: {:?}", jet_packet);
+                    self.data_received.clear();
+                    return Ok(Async::Ready(jet_packet));
+                }
 
-            if self.data_received.len() >= msg_len as usize {
-                let mut slice = self.data_received.as_slice();
-                let jet_packet = jet_proto::JetPacket::read_from(&mut slice)?;
-                debug!("jet_packet received: {:?}", jet_packet);
-                Ok(Async::Ready(jet_packet))
-            } else {
                 debug!(
                     "Waiting more data: received:{} - needed:{}",
                     self.data_received.len(),
                     msg_len
                 );
-                Ok(Async::NotReady)
+                msg_len as usize - self.data_received.len()
+            } else {
+                debug!(
+                    "Waiting more data: received:{} - needed: at least header length ({})",
+                    self.data_received.len(),
+                    jet_proto::JET_MSG_HEADER_SIZE
+                );
+                jet_proto::JET_MSG_HEADER_SIZE as usize - self.data_received.len()
+            };
+
+            let mut buff = [0u8; 1024];
+            let len = try_ready!(self.transport.poll_read(&mut buff[..needed.min(1024)]));
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before a complete JetPacket was received.",
+                ));
             }
-        } else {
-            debug!(
-                "Waiting more data: received:{} - needed: at least header length ({})",
-                self.data_received.len(),
-                jet_proto::JET_MSG_HEADER_SIZE
-            );
-            Ok(Async::NotReady)
+            self.data_received.extend_from_slice(&buff[..len]);
+        }
+    }
+}
+
+impl Future for JetMsgReader {
+    type Item = JetPacket;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
+        loop {
+            while !self.pong.is_empty() {
+                let len = try_ready!(self.transport.poll_write(&self.pong));
+                if len == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.pong.drain(..len);
+            }
+
+            let jet_packet = try_ready!(self.poll_packet());
+            if !jet_packet.is_ping() {
+                return Ok(Async::Ready(jet_packet));
+            }
+            build_pong(&jet_packet).write_to(&mut self.pong)?;
         }
     }
 }
@@ -130,23 +274,68 @@ struct HandleAcceptJetMsg {
     transport: JetTransport,
     request_msg: JetPacket,
     response_msg: Option<JetPacket>,
-    jet_associations: JetAssociationsMap,
+    accepted: bool,
+    timeout: u32,
+    connection_id: u64,
+    pool_token: Option<Uuid>,
+    options: ServerConnectionOptions,
+    keepalive: Option<KeepaliveHandle>,
+    release_requests: Option<ReleaseRequests>,
+    config: Config,
+    association_store: JetAssociationStore,
     executor_handle: TaskExecutor,
 }
 
 impl HandleAcceptJetMsg {
     fn new(
+        config: &Config,
         transport: JetTransport,
         msg: JetPacket,
-        jet_associations: JetAssociationsMap,
+        association_store: JetAssociationStore,
         executor_handle: TaskExecutor,
     ) -> Self {
         assert!(msg.is_accept());
+
+        // The server can request its own timeout, but it can't exceed the configured maximum
+        let timeout = msg
+            .timeout()
+            .map(|timeout| timeout.min(config.association_max_timeout()))
+            .unwrap_or_else(|| config.association_timeout());
+
+        // The session will be resumable on the server side if it is asked and enabled. Multiplexed sessions can't be
+        // resumed.
+        let resume_token = if msg.resumable() && !msg.multiplexed() && config.resume_grace_period() > 0 {
+            Some(Uuid::new_v4())
+        } else {
+            None
+        };
+        let options = ServerConnectionOptions {
+            version: msg.version().unwrap_or(0),
+            resume_token,
+            multiplexed: msg.multiplexed(),
+        };
+
+        // Servers asking for a keepalive are pinged while their connection is parked
+        let (keepalive, release_requests) = if msg.keepalive() && config.keepalive_interval() > 0 {
+            let (keepalive, release_requests) = KeepaliveHandle::channel();
+            (Some(keepalive), Some(release_requests))
+        } else {
+            (None, None)
+        };
+
         HandleAcceptJetMsg {
             transport,
             request_msg: msg,
             response_msg: None,
-            jet_associations,
+            accepted: false,
+            timeout,
+            connection_id: 0,
+            pool_token: None,
+            options,
+            keepalive,
+            release_requests,
+            config: config.clone(),
+            association_store,
             executor_handle,
         }
     }
@@ -158,22 +347,75 @@ impl Future for HandleAcceptJetMsg {
 
     fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
         if self.response_msg.is_none() {
-            if let Ok(mut jet_associations) = self.jet_associations.try_lock() {
-                let uuid = Uuid::new_v4();
+            let uuid = match self.request_msg.association() {
+                // A zero timeout would expire the association as soon as it is accepted
+                _ if self.timeout == 0 => Uuid::nil(),
+                // The association has been created in advance through the management API or the server is adding a
+                // connection to the pool of an existing association with the token it was given
+                Some(uuid) => {
+                    match self.association_store.add_server_connection(
+                        uuid,
+                        self.request_msg.pool_token(),
+                        self.transport.clone(),
+                        self.options,
+                        self.keepalive.take(),
+                    ) {
+                        Ok(parked_connection) => {
+                            self.connection_id = parked_connection.connection_id;
+                            self.pool_token = Some(parked_connection.pool_token);
+                            if let Some(remaining_time) = parked_connection.remaining_time {
+                                self.timeout = self.timeout.min(remaining_time);
+                            }
+                            self.accepted = true;
+                        }
+                        Err(e) => error!("Invalid association ID received in accept request: {}", e),
+                    }
+                    uuid
+                }
+                None => {
+                    let uuid = Uuid::new_v4();
+                    let mut association = Association::new(uuid);
+                    association.set_instance(JET_INSTANCE.clone());
+                    self.pool_token = association.authorize_server(None);
+                    self.connection_id =
+                        association.add_server_transport(self.transport.clone(), self.options, self.keepalive.take());
+                    match self.association_store.create(association) {
+                        Ok(_) => self.accepted = true,
+                        Err(e) => error!("Failed to create association {}: {}", uuid, e),
+                    }
+                    uuid
+                }
+            };
+
+            let response_msg = if self.accepted {
                 let mut response_msg = JetPacket::new_response(
                     self.request_msg.flags(),
                     self.request_msg.mask(),
                     ResponseStatusCode::StatusCode200,
                 );
-                response_msg.set_timeout(Some(ACCEPT_REQUEST_TIMEOUT_SEC as u32));
+                response_msg.set_timeout(Some(self.timeout));
                 response_msg.set_association(Some(uuid));
+                response_msg.set_pool_token(self.pool_token);
                 response_msg.set_jet_instance(JET_INSTANCE.clone());
-                self.response_msg = Some(response_msg);
-
-                jet_associations.insert(uuid, self.transport.clone());
+                if self.options.resume_token.is_some() {
+                    response_msg.set_resumable(Some(true));
+                    response_msg.set_resume_token(self.options.resume_token);
+                }
+                if self.options.multiplexed {
+                    response_msg.set_multiplexed(Some(true));
+                }
+                if self.release_requests.is_some() {
+                    response_msg.set_keepalive_interval(Some(self.config.keepalive_interval()));
+                }
+                response_msg
             } else {
-                return Ok(Async::NotReady);
-            }
+                JetPacket::new_response(
+                    self.request_msg.flags(),
+                    self.request_msg.mask(),
+                    ResponseStatusCode::StatusCode400,
+                )
+            };
+            self.response_msg = Some(response_msg);
         }
 
         // We have a response ==> Send response + timeout to remove the server if not used
@@ -182,44 +424,169 @@ impl Future for HandleAcceptJetMsg {
         response_msg.write_to(&mut v)?;
         try_ready!(self.transport.poll_write(&v));
 
+        if !self.accepted {
+            let reason = match self.request_msg.association() {
+                _ if self.timeout == 0 => "the timeout can't be 0".to_string(),
+                Some(association) => format!("association {} can't be used", association),
+                None => "the association can't be created".to_string(),
+            };
+            return Err(error_other(&format!("Invalid accept request: {}.", reason)));
+        }
+
         // Start timeout to remove the server if no connect request is received with that UUID
         let association = response_msg.association().unwrap();
-        let jet_associations = self.jet_associations.clone();
-        let timeout = Delay::new(Instant::now() + Duration::from_secs(ACCEPT_REQUEST_TIMEOUT_SEC));
+        let connection_id = self.connection_id;
+        if let Some(release_requests) = self.release_requests.take() {
+            let keepalive = ParkedKeepalive::new(
+                &self.config,
+                self.transport.clone(),
+                association,
+                connection_id,
+                self.association_store.clone(),
+                release_requests,
+            );
+            self.executor_handle.spawn(keepalive.map_err(move |e| {
+                error!("Keepalive of association {} failed: {}", association, e);
+            }));
+        }
+        let association_store = self.association_store.clone();
+        let mut timeout_msg = JetPacket::new_response(
+            self.request_msg.flags(),
+            self.request_msg.mask(),
+            ResponseStatusCode::StatusCode408,
+        );
+        timeout_msg.set_association(Some(association));
+        let timeout = Delay::new(Instant::now() + Duration::from_secs(u64::from(self.timeout)));
         self.executor_handle.spawn(timeout.then(move |_| {
-            RemoveAssociation::new(jet_associations, association).then(move |res| {
-                if let Ok(true) = res {
-                    info!(
-                        "No connect request received with association {}. Association removed!",
-                        association
-                    );
-                }
-                ok(())
-            })
+            let server_transport = match association_store.expire_server_connection(association, connection_id) {
+                Some(server_transport) => server_transport,
+                None => return Either::A(ok(())),
+            };
+
+            info!(
+                "No connect request received with association {}. Server connection removed!",
+                association
+            );
+
+            // Let the server know why the connection is closed
+            let mut v = Vec::new();
+            if let Err(e) = timeout_msg.write_to(&mut v) {
+                error!("Failed to build the timeout response: {}", e);
+                return Either::A(ok(()));
+            }
+            Either::B(
+                write_all(server_transport, v)
+                    .and_then(|(server_transport, _)| shutdown(server_transport))
+                    .then(move |res| {
+                        if let Err(e) = res {
+                            error!(
+                                "Failed to send the timeout response for association {}: {}",
+                                association, e
+                            );
+                        }
+                        ok(())
+                    }),
+            )
         }));
 
         Ok(Async::Ready(()))
     }
 }
 
+enum ConnectDestination {
+    Server {
+        transport: JetTransport,
+        options: ServerConnectionOptions,
+        client_resume_token: Option<Uuid>,
+    },
+    Peer(String, Url),
+}
+
 struct HandleConnectJetMsg {
+    config: Config,
     transport: JetTransport,
     server_transport: Option<JetTransport>,
+    server_options: ServerConnectionOptions,
+    server_release: Option<Box<dyn Future<Item = (), Error = io::Error> + Send>>,
+    client_resume_token: Option<Uuid>,
     request_msg: JetPacket,
     response_msg: Option<JetPacket>,
-    jet_associations: JetAssociationsMap,
+    response_sent: bool,
+    /// Notifications to send to the server before the client data
+    notifications: Vec<u8>,
+    association_store: JetAssociationStore,
 }
 
 impl HandleConnectJetMsg {
-    fn new(transport: JetTransport, msg: JetPacket, jet_associations: JetAssociationsMap) -> Self {
+    fn new(config: &Config, transport: JetTransport, msg: JetPacket, association_store: JetAssociationStore) -> Self {
         assert!(msg.is_connect());
 
         HandleConnectJetMsg {
+            config: config.clone(),
             transport,
             server_transport: None,
+            server_options: ServerConnectionOptions::default(),
+            server_release: None,
+            client_resume_token: None,
             request_msg: msg,
             response_msg: None,
-            jet_associations,
+            response_sent: false,
+            notifications: Vec::new(),
+            association_store,
+        }
+    }
+
+    /// A request naming this instance has been forwarded by a peer and must be handled here. Only the connections
+    /// coming from a peer are trusted, other clients can't claim to be forwarded to choose their client address.
+    fn is_forwarded(&self) -> bool {
+        self.request_msg.jet_instance().map(is_local_instance).unwrap_or(false)
+            && self
+                .transport
+                .peer_addr()
+                .map(|addr| self.config.is_peer_address(addr.ip()))
+                .unwrap_or(false)
+    }
+
+    /// Address of the client. A request forwarded by a peer carries the address of the client connected to that peer.
+    fn client_addr(&self) -> Option<SocketAddr> {
+        if self.is_forwarded() {
+            self.request_msg.client_address().and_then(|addr| addr.parse().ok())
+        } else {
+            self.transport.peer_addr()
+        }
+    }
+
+    /// Subject of the client certificate. It is unknown for a request forwarded by a peer.
+    fn client_certificate_subject(&self) -> Option<String> {
+        if self.is_forwarded() {
+            None
+        } else {
+            self.transport.peer_certificate_subject()
+        }
+    }
+
+    /// Instance to which the request has to be forwarded, if the server of the association is not connected here.
+    fn remote_instance(&self, association: Option<&AssociationInfo>) -> Option<String> {
+        if self.is_forwarded() {
+            return None;
+        }
+
+        let instance = match association {
+            Some(association) => association.instance.as_deref(),
+            None => self.request_msg.jet_instance(),
+        }?;
+
+        if is_local_instance(instance) {
+            None
+        } else if self.config.peer_url(instance).is_none() {
+            error!(
+                "Association {} belongs to instance {} but that peer is unknown",
+                self.request_msg.association().unwrap(),
+                instance
+            );
+            None
+        } else {
+            Some(instance.to_string())
         }
     }
 
@@ -229,10 +596,34 @@ impl HandleConnectJetMsg {
         let len = try_ready!(self.transport.poll_write(&v));
         Ok(Async::Ready(len))
     }
+
+    fn build_notification(&self, method: JetMethod) -> JetPacket {
+        let mut notification = JetPacket::new_response(
+            self.request_msg.flags(),
+            self.request_msg.mask(),
+            ResponseStatusCode::StatusCode200,
+        );
+        notification.set_method(Some(method));
+        notification.set_association(self.request_msg.association());
+        notification
+    }
+
+    fn send_notifications(&mut self) -> Result<Async<()>, io::Error> {
+        if let Some(server_transport) = self.server_transport.as_mut() {
+            while !self.notifications.is_empty() {
+                let len = try_ready!(server_transport.poll_write(&self.notifications));
+                if len == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.notifications.drain(..len);
+            }
+        }
+        Ok(Async::Ready(()))
+    }
 }
 
 impl Future for HandleConnectJetMsg {
-    type Item = (JetTransport, JetTransport);
+    type Item = (ConnectDestination, JetTransport);
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
@@ -248,41 +639,122 @@ impl Future for HandleConnectJetMsg {
         }
 
         // Find the server transport
-        if self.server_transport.is_none() {
-            if let Ok(mut jet_associations) = self.jet_associations.try_lock() {
-                let server_stream_opt = jet_associations.remove(&self.request_msg.association().unwrap());
+        if self.response_msg.is_none() {
+            let uuid = self.request_msg.association().unwrap();
+
+            if let Some(instance) = self.remote_instance(self.association_store.get(uuid).as_ref()) {
+                let peer_url = self.config.peer_url(&instance).unwrap().clone();
+                return Ok(Async::Ready((
+                    ConnectDestination::Peer(instance, peer_url),
+                    self.transport.clone(),
+                )));
+            }
 
-                if let Some(server_transport) = server_stream_opt {
-                    self.server_transport = Some(server_transport);
-                    self.response_msg = Some(JetPacket::new_response(
-                        self.request_msg.flags(),
-                        self.request_msg.mask(),
-                        ResponseStatusCode::StatusCode200,
-                    ));
+            let multiplexed = self.request_msg.multiplexed();
+            let client_addr = self.client_addr();
+            let client = ConnectingClient {
+                addr: client_addr,
+                certificate_subject: self.client_certificate_subject(),
+            };
+            let server_connection_opt = self.association_store.take_server_connection(uuid, client, multiplexed);
+
+            if let Some((server_connection, pool_remaining)) = server_connection_opt {
+                let server_version = server_connection.options.version;
+
+                // Servers supporting it are told who took their connection before receiving the client data
+                if server_version >= JET_VERSION_CONNECTED_NOTIFICATION {
+                    let mut notification = self.build_notification(JetMethod::CONNECTED);
+                    notification.set_client_address(client_addr.map(|addr| addr.to_string()));
+                    notification.set_multiplexed(if multiplexed { Some(true) } else { None });
+                    notification.write_to(&mut self.notifications)?;
+                }
+
+                // Servers keeping a pool of connections are told when it runs low, if they support notifications
+                let pool_remaining = pool_remaining.map(|pool_remaining| pool_remaining as u32);
+                if let Some(pool_remaining) = pool_remaining {
+                    if server_version >= JET_VERSION_NOTIFICATIONS
+                        && pool_remaining <= self.config.association_pool_low_watermark()
+                    {
+                        let mut notification = self.build_notification(JetMethod::POOL_LOW);
+                        notification.set_pool_remaining(Some(pool_remaining));
+                        notification.write_to(&mut self.notifications)?;
+                    }
+                }
+
+                self.server_transport = Some(server_connection.transport);
+                self.server_options = server_connection.options;
+                self.server_release = server_connection.keepalive.map(|keepalive| {
+                    Box::new(keepalive.release()) as Box<dyn Future<Item = (), Error = io::Error> + Send>
+                });
+
+                let mut response_msg = JetPacket::new_response(
+                    self.request_msg.flags(),
+                    self.request_msg.mask(),
+                    ResponseStatusCode::StatusCode200,
+                );
+                if multiplexed {
+                    response_msg.set_multiplexed(Some(true));
+                } else if self.request_msg.resumable() && self.config.resume_grace_period() > 0 {
+                    self.client_resume_token = Some(Uuid::new_v4());
+                    response_msg.set_association(Some(uuid));
+                    response_msg.set_resumable(Some(true));
+                    response_msg.set_resume_token(self.client_resume_token);
+                }
+                self.response_msg = Some(response_msg);
+            } else {
+                self.response_msg = Some(JetPacket::new_response(
+                    self.request_msg.flags(),
+                    self.request_msg.mask(),
+                    ResponseStatusCode::StatusCode400,
+                ));
+                if multiplexed {
+                    error!("No multiplexed server connection available for association {}", uuid);
                 } else {
+                    error!("Invalid association ID received: {}", uuid);
+                }
+            }
+        }
+
+        // Wait for the keepalive of the parked connection to stop
+        if let Some(mut server_release) = self.server_release.take() {
+            match server_release.poll() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => {
+                    self.server_release = Some(server_release);
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    error!(
+                        "Server connection of association {} can't be used: {}",
+                        self.request_msg.association().unwrap(),
+                        e
+                    );
+                    self.server_transport = None;
                     self.response_msg = Some(JetPacket::new_response(
                         self.request_msg.flags(),
                         self.request_msg.mask(),
                         ResponseStatusCode::StatusCode400,
                     ));
-                    error!(
-                        "Invalid association ID received: {}",
-                        self.request_msg.association().unwrap()
-                    );
                 }
-            } else {
-                return Ok(Async::NotReady);
             }
         }
 
         // Send response
-        let msg = self.response_msg.clone().unwrap();
-        try_ready!(self.send_response(&msg));
+        if !self.response_sent {
+            let msg = self.response_msg.clone().unwrap();
+            try_ready!(self.send_response(&msg));
+            self.response_sent = true;
+        }
 
         // If server stream found, start the proxy
         if self.server_transport.is_some() {
+            try_ready!(self.send_notifications());
             Ok(Async::Ready((
-                self.server_transport.take().unwrap().clone(),
+                ConnectDestination::Server {
+                    transport: self.server_transport.take().unwrap(),
+                    options: self.server_options,
+                    client_resume_token: self.client_resume_token,
+                },
                 self.transport.clone(),
             )))
         } else {
@@ -293,31 +765,3 @@ impl Future for HandleConnectJetMsg {
         }
     }
 }
-
-struct RemoveAssociation {
-    jet_associations: JetAssociationsMap,
-    association: Uuid,
-}
-
-impl RemoveAssociation {
-    fn new(jet_associations: JetAssociationsMap, association: Uuid) -> Self {
-        RemoveAssociation {
-            jet_associations,
-            association,
-        }
-    }
-}
-
-impl Future for RemoveAssociation {
-    type Item = bool;
-    type Error = ();
-
-    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
-        if let Ok(mut jet_associations) = self.jet_associations.try_lock() {
-            let removed = jet_associations.remove(&self.association).is_some();
-            Ok(Async::Ready(removed))
-        } else {
-            Ok(Async::NotReady)
-        }
-    }
-}
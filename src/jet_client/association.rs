@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use super::keepalive::KeepaliveHandle;
+use crate::transport::JetTransport;
+
+const ASSOCIATION_HISTORY_RETENTION_SEC: i64 = 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AssociationState {
+    Waiting,
+    Connected,
+    Expired,
+}
+
+/// Options negotiated by the accept request of a server connection.
+#[derive(Clone, Copy, Default)]
+pub struct ServerConnectionOptions {
+    pub version: u8,
+    /// Token to resume the session if the server asked for a resumable session.
+    pub resume_token: Option<Uuid>,
+    /// The connection carries multiplexed channels and can only be used by a multiplexed client.
+    pub multiplexed: bool,
+}
+
+/// A server connection parked by an accept request, waiting for a client.
+pub struct ServerConnection {
+    pub id: u64,
+    pub transport: JetTransport,
+    pub options: ServerConnectionOptions,
+    /// Pings the connection while it is parked
+    pub keepalive: Option<KeepaliveHandle>,
+}
+
+/// Client of a connect request taking a server connection.
+#[derive(Default)]
+pub struct ConnectingClient {
+    pub addr: Option<SocketAddr>,
+    /// Subject of the client certificate, on listeners requiring one
+    pub certificate_subject: Option<String>,
+}
+
+pub struct Association {
+    id: Uuid,
+    state: AssociationState,
+    creation_timestamp: DateTime<Utc>,
+    expiration_timestamp: Option<DateTime<Utc>>,
+    metadata: Option<Value>,
+    instance: Option<String>,
+    server_addresses: Vec<String>,
+    client_addresses: Vec<String>,
+    server_certificate_subjects: Vec<String>,
+    client_certificate_subjects: Vec<String>,
+    server_connections: VecDeque<ServerConnection>,
+    next_connection_id: u64,
+    connection_count: u64,
+    /// Token given to the first server parking a connection, required to add more connections to the pool
+    pool_token: Option<Uuid>,
+    /// Whether the server added connections to the pool with its token
+    pooled: bool,
+}
+
+/// Body of a `POST /associations` request. Every field is optional.
+#[derive(Default, Deserialize)]
+pub struct AssociationRequest {
+    pub id: Option<Uuid>,
+    pub timeout: Option<u32>,
+    pub metadata: Option<Value>,
+    pub instance: Option<String>,
+}
+
+/// Representation of an association exposed by the management API and saved by the association stores.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AssociationInfo {
+    pub id: Uuid,
+    pub state: AssociationState,
+    pub pending_server_connections: usize,
+    pub connection_count: u64,
+    pub creation_timestamp: DateTime<Utc>,
+    pub expiration_timestamp: Option<DateTime<Utc>>,
+    pub metadata: Option<Value>,
+    pub instance: Option<String>,
+    #[serde(default)]
+    pub server_addresses: Vec<String>,
+    #[serde(default)]
+    pub client_addresses: Vec<String>,
+    /// Subjects of the client certificates of the server connections, on listeners requiring one
+    #[serde(default)]
+    pub server_certificate_subjects: Vec<String>,
+    #[serde(default)]
+    pub client_certificate_subjects: Vec<String>,
+}
+
+impl Association {
+    pub fn new(id: Uuid) -> Self {
+        Association {
+            id,
+            state: AssociationState::Waiting,
+            creation_timestamp: Utc::now(),
+            expiration_timestamp: None,
+            metadata: None,
+            instance: None,
+            server_addresses: Vec::new(),
+            client_addresses: Vec::new(),
+            server_certificate_subjects: Vec::new(),
+            client_certificate_subjects: Vec::new(),
+            server_connections: VecDeque::new(),
+            next_connection_id: 0,
+            connection_count: 0,
+            pool_token: None,
+            pooled: false,
+        }
+    }
+
+    pub fn from_request(request: AssociationRequest) -> Self {
+        let mut association = Association::new(request.id.unwrap_or_else(Uuid::new_v4));
+        association.expiration_timestamp = request
+            .timeout
+            .map(|timeout| association.creation_timestamp + Duration::seconds(i64::from(timeout)));
+        association.metadata = request.metadata;
+        association.instance = request.instance;
+        association
+    }
+
+    /// Restores an association saved by a store. Server connections don't survive a restart, so an association that
+    /// had some is considered expired. Pool tokens are not saved, so the pool of a restored association can't be
+    /// refilled.
+    pub fn from_info(info: AssociationInfo) -> Self {
+        let state = if info.state == AssociationState::Waiting && info.pending_server_connections > 0 {
+            AssociationState::Expired
+        } else {
+            info.state
+        };
+
+        Association {
+            id: info.id,
+            state,
+            creation_timestamp: info.creation_timestamp,
+            expiration_timestamp: info.expiration_timestamp,
+            metadata: info.metadata,
+            instance: info.instance,
+            server_addresses: info.server_addresses,
+            client_addresses: info.client_addresses,
+            server_certificate_subjects: info.server_certificate_subjects,
+            client_certificate_subjects: info.client_certificate_subjects,
+            server_connections: VecDeque::new(),
+            next_connection_id: 0,
+            connection_count: info.connection_count,
+            pool_token: None,
+            pooled: false,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Instance of devolutions-jet where the server of this association connects, if it is not this one.
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    pub fn set_instance(&mut self, instance: Option<String>) {
+        self.instance = instance;
+    }
+
+    pub fn state(&self) -> AssociationState {
+        match (self.state, self.expiration_timestamp) {
+            (AssociationState::Waiting, Some(expiration)) if expiration <= Utc::now() => AssociationState::Expired,
+            (state, _) => state,
+        }
+    }
+
+    /// Number of seconds left before the association expires, if an expiration was requested.
+    pub fn remaining_time(&self) -> Option<u32> {
+        self.expiration_timestamp
+            .map(|expiration| (expiration - Utc::now()).num_seconds().max(0) as u32)
+    }
+
+    /// Number of server connections parked in the pool of the association.
+    pub fn pool_size(&self) -> usize {
+        self.server_connections.len()
+    }
+
+    /// Checks the pool token presented by a server parking a connection and returns the token to send back. The first
+    /// server parking a connection gets a new token, the next ones must present it: knowing the association ID is not
+    /// enough to add a connection to the pool of a server.
+    pub fn authorize_server(&mut self, pool_token: Option<Uuid>) -> Option<Uuid> {
+        match self.pool_token {
+            None if self.state == AssociationState::Waiting && self.next_connection_id == 0 => {
+                self.pool_token = Some(Uuid::new_v4());
+                self.pool_token
+            }
+            Some(token) if pool_token == Some(token) => {
+                self.pooled = true;
+                Some(token)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the server keeps a pool of connections in the association, so it wants to know when the pool runs low
+    pub fn is_pooled(&self) -> bool {
+        self.pooled
+    }
+
+    /// Parks a server connection in the pool of the association and returns its ID within the association.
+    pub fn add_server_transport(
+        &mut self,
+        transport: JetTransport,
+        options: ServerConnectionOptions,
+        keepalive: Option<KeepaliveHandle>,
+    ) -> u64 {
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        if let Some(addr) = transport.peer_addr() {
+            self.server_addresses.push(addr.to_string());
+        }
+        if let Some(subject) = transport.peer_certificate_subject() {
+            self.server_certificate_subjects.push(subject);
+        }
+        self.server_connections.push_back(ServerConnection {
+            id,
+            transport,
+            options,
+            keepalive,
+        });
+        id
+    }
+
+    /// Hands the oldest parked server connection using the same mode (multiplexed or not) over to a client.
+    pub fn take_server_connection(&mut self, client: ConnectingClient, multiplexed: bool) -> Option<ServerConnection> {
+        if self.state() == AssociationState::Expired {
+            return None;
+        }
+
+        let connection = self
+            .server_connections
+            .iter()
+            .position(|connection| connection.options.multiplexed == multiplexed)
+            .and_then(|position| self.server_connections.remove(position));
+        if connection.is_some() {
+            self.state = AssociationState::Connected;
+            self.connection_count += 1;
+            if let Some(addr) = client.addr {
+                self.client_addresses.push(addr.to_string());
+            }
+            if let Some(subject) = client.certificate_subject {
+                self.client_certificate_subjects.push(subject);
+            }
+        }
+        connection
+    }
+
+    /// Removes a parked server connection that has not been used in time and returns its transport.
+    /// An association that has never been used expires when its last server connection is removed, a used one can
+    /// still get new connections in its pool.
+    pub fn expire_server_connection(&mut self, connection_id: u64) -> Option<JetTransport> {
+        let position = self
+            .server_connections
+            .iter()
+            .position(|connection| connection.id == connection_id)?;
+        let connection = self.server_connections.remove(position)?;
+
+        if self.server_connections.is_empty() && self.state == AssociationState::Waiting {
+            self.state = AssociationState::Expired;
+        }
+        Some(connection.transport)
+    }
+
+    /// Associations that are not waiting anymore are kept for a while so their state can be queried.
+    pub fn is_outdated(&self) -> bool {
+        self.state() != AssociationState::Waiting
+            && self.server_connections.is_empty()
+            && Utc::now() - self.creation_timestamp > Duration::seconds(ASSOCIATION_HISTORY_RETENTION_SEC)
+    }
+
+    pub fn info(&self) -> AssociationInfo {
+        AssociationInfo {
+            id: self.id,
+            state: self.state(),
+            pending_server_connections: self.pool_size(),
+            connection_count: self.connection_count,
+            creation_timestamp: self.creation_timestamp,
+            expiration_timestamp: self.expiration_timestamp,
+            metadata: self.metadata.clone(),
+            instance: self.instance.clone(),
+            server_addresses: self.server_addresses.clone(),
+            client_addresses: self.client_addresses.clone(),
+            server_certificate_subjects: self.server_certificate_subjects.clone(),
+            client_certificate_subjects: self.client_certificate_subjects.clone(),
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use log::{error, info};
+use uuid::Uuid;
+
+use super::association::{
+    Association, AssociationInfo, AssociationState, ConnectingClient, ServerConnection, ServerConnectionOptions,
+};
+use super::keepalive::KeepaliveHandle;
+use crate::transport::JetTransport;
+
+pub type JetAssociationStore = Arc<dyn AssociationStore>;
+
+/// Server connection parked in an association by an accept request.
+pub struct ParkedConnection {
+    pub association: Uuid,
+    pub connection_id: u64,
+    pub remaining_time: Option<u32>,
+    /// Token to present to add more connections to the pool of the association
+    pub pool_token: Uuid,
+}
+
+/// Storage of the JET associations. Every operation is short and done under a lock held only for its duration, so it
+/// can be called directly from a future without waiting for a wakeup.
+pub trait AssociationStore: Send + Sync {
+    /// Adds a new association. Fails with `AlreadyExists` if an association with the same ID is still in use.
+    fn create(&self, association: Association) -> io::Result<AssociationInfo>;
+
+    /// Parks a server connection in an existing association. Fails with `NotFound` if the association doesn't exist or
+    /// is expired, and with `PermissionDenied` if a server already parked connections in it and `pool_token` is not
+    /// the token it was given.
+    fn add_server_connection(
+        &self,
+        id: Uuid,
+        pool_token: Option<Uuid>,
+        transport: JetTransport,
+        options: ServerConnectionOptions,
+        keepalive: Option<KeepaliveHandle>,
+    ) -> io::Result<ParkedConnection>;
+
+    /// Hands a parked server connection using the requested mode over to a client. Returns the connection and, if the
+    /// server keeps a pool of connections in the association, the number of connections left.
+    fn take_server_connection(
+        &self,
+        id: Uuid,
+        client: ConnectingClient,
+        multiplexed: bool,
+    ) -> Option<(ServerConnection, Option<usize>)>;
+
+    /// Removes a parked server connection that has not been used in time and returns its transport.
+    fn expire_server_connection(&self, id: Uuid, connection_id: u64) -> Option<JetTransport>;
+
+    fn get(&self, id: Uuid) -> Option<AssociationInfo>;
+
+    fn list(&self) -> Vec<AssociationInfo>;
+}
+
+/// Associations kept in memory. The map is behind a std mutex rather than a futures-aware lock: it is only held for a
+/// lookup or an update of the map, never while waiting for I/O, so locking it from a future doesn't block the reactor
+/// longer than the operation itself and no wakeup is needed. Associations outdated for the history are removed when a
+/// new one is created.
+#[derive(Default)]
+pub struct MemoryAssociationStore {
+    associations: Mutex<HashMap<Uuid, Association>>,
+}
+
+impl MemoryAssociationStore {
+    pub fn new() -> Self {
+        MemoryAssociationStore::default()
+    }
+
+    fn with_associations(associations: HashMap<Uuid, Association>) -> Self {
+        MemoryAssociationStore {
+            associations: Mutex::new(associations),
+        }
+    }
+}
+
+impl AssociationStore for MemoryAssociationStore {
+    fn create(&self, association: Association) -> io::Result<AssociationInfo> {
+        let mut associations = self.associations.lock().unwrap();
+        if let Some(existing) = associations.get(&association.id()) {
+            if existing.state() != AssociationState::Expired {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Association {} already exists", association.id()),
+                ));
+            }
+        }
+
+        associations.retain(|_, association| !association.is_outdated());
+        let info = association.info();
+        associations.insert(association.id(), association);
+        Ok(info)
+    }
+
+    fn add_server_connection(
+        &self,
+        id: Uuid,
+        pool_token: Option<Uuid>,
+        transport: JetTransport,
+        options: ServerConnectionOptions,
+        keepalive: Option<KeepaliveHandle>,
+    ) -> io::Result<ParkedConnection> {
+        let mut associations = self.associations.lock().unwrap();
+        let association = match associations.get_mut(&id) {
+            Some(association) if association.state() != AssociationState::Expired => association,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Association {} doesn't exist or is expired", id),
+                ))
+            }
+        };
+        let pool_token = association.authorize_server(pool_token).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Association {} is used by another server", id),
+            )
+        })?;
+
+        Ok(ParkedConnection {
+            association: id,
+            connection_id: association.add_server_transport(transport, options, keepalive),
+            remaining_time: association.remaining_time(),
+            pool_token,
+        })
+    }
+
+    fn take_server_connection(
+        &self,
+        id: Uuid,
+        client: ConnectingClient,
+        multiplexed: bool,
+    ) -> Option<(ServerConnection, Option<usize>)> {
+        let mut associations = self.associations.lock().unwrap();
+        let association = associations.get_mut(&id)?;
+        let connection = association.take_server_connection(client, multiplexed)?;
+        let pool_remaining = if association.is_pooled() {
+            Some(association.pool_size())
+        } else {
+            None
+        };
+        Some((connection, pool_remaining))
+    }
+
+    fn expire_server_connection(&self, id: Uuid, connection_id: u64) -> Option<JetTransport> {
+        let mut associations = self.associations.lock().unwrap();
+        associations
+            .get_mut(&id)
+            .and_then(|association| association.expire_server_connection(connection_id))
+    }
+
+    fn get(&self, id: Uuid) -> Option<AssociationInfo> {
+        let associations = self.associations.lock().unwrap();
+        associations.get(&id).map(Association::info)
+    }
+
+    fn list(&self) -> Vec<AssociationInfo> {
+        let associations = self.associations.lock().unwrap();
+        associations.values().map(Association::info).collect()
+    }
+}
+
+/// Association store keeping an history of the associations in a file. Every change is appended to the file as a JSON
+/// line by a writer thread, so the callers never wait for the disk. The file is never rewritten: it keeps every state
+/// of every association for auditing. When the store is opened, the latest state of each association found in the file
+/// is loaded, except for the associations too old to be kept in memory.
+pub struct FileAssociationStore {
+    memory: MemoryAssociationStore,
+    records: Mutex<Option<Sender<AssociationInfo>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl FileAssociationStore {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut associations = HashMap::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                match serde_json::from_str::<AssociationInfo>(&line) {
+                    // The last line of an association is its latest state
+                    Ok(info) => {
+                        associations.insert(info.id, Association::from_info(info));
+                    }
+                    Err(e) => error!("Invalid association record in {}: {}", path, e),
+                }
+            }
+        }
+        associations.retain(|_, association| !association.is_outdated());
+        info!("{} associations loaded from {}", associations.len(), path);
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("associations file".to_string())
+            .spawn(move || write_records(file, receiver))?;
+
+        Ok(FileAssociationStore {
+            memory: MemoryAssociationStore::with_associations(associations),
+            records: Mutex::new(Some(sender)),
+            writer: Some(writer),
+        })
+    }
+
+    fn record(&self, id: Uuid) {
+        if let Some(info) = self.memory.get(id) {
+            if let Some(records) = self.records.lock().unwrap().as_ref() {
+                if records.send(info).is_err() {
+                    error!("Failed to save association {}: the writer thread stopped", id);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileAssociationStore {
+    /// Waits for the pending records to be written
+    fn drop(&mut self) {
+        self.records.lock().unwrap().take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_records(mut file: File, records: Receiver<AssociationInfo>) {
+    for info in records {
+        let result = serde_json::to_string(&info)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .and_then(|line| writeln!(file, "{}", line));
+        if let Err(e) = result {
+            error!("Failed to save association {}: {}", info.id, e);
+        }
+    }
+}
+
+impl AssociationStore for FileAssociationStore {
+    fn create(&self, association: Association) -> io::Result<AssociationInfo> {
+        let id = association.id();
+        let info = self.memory.create(association)?;
+        self.record(id);
+        Ok(info)
+    }
+
+    fn add_server_connection(
+        &self,
+        id: Uuid,
+        pool_token: Option<Uuid>,
+        transport: JetTransport,
+        options: ServerConnectionOptions,
+        keepalive: Option<KeepaliveHandle>,
+    ) -> io::Result<ParkedConnection> {
+        let parked_connection = self
+            .memory
+            .add_server_connection(id, pool_token, transport, options, keepalive)?;
+        self.record(id);
+        Ok(parked_connection)
+    }
+
+    fn take_server_connection(
+        &self,
+        id: Uuid,
+        client: ConnectingClient,
+        multiplexed: bool,
+    ) -> Option<(ServerConnection, Option<usize>)> {
+        let result = self.memory.take_server_connection(id, client, multiplexed)?;
+        self.record(id);
+        Some(result)
+    }
+
+    fn expire_server_connection(&self, id: Uuid, connection_id: u64) -> Option<JetTransport> {
+        let transport = self.memory.expire_server_connection(id, connection_id)?;
+        self.record(id);
+        Some(transport)
+    }
+
+    fn get(&self, id: Uuid) -> Option<AssociationInfo> {
+        self.memory.get(id)
+    }
+
+    fn list(&self) -> Vec<AssociationInfo> {
+        self.memory.list()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jet_client::association::AssociationRequest;
+    use chrono::{Duration, Utc};
+    use std::fs;
+
+    fn expired_association(id: Uuid) -> Association {
+        Association::from_request(AssociationRequest {
+            id: Some(id),
+            timeout: Some(0),
+            ..AssociationRequest::default()
+        })
+    }
+
+    fn line(info: &AssociationInfo) -> String {
+        serde_json::to_string(info).unwrap()
+    }
+
+    #[test]
+    fn associations_are_reloaded_from_the_history() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("associations.json");
+        let path = path.to_str().unwrap();
+        let (expired, waiting) = (Uuid::new_v4(), Uuid::new_v4());
+
+        {
+            let store = FileAssociationStore::open(path).unwrap();
+            store.create(expired_association(expired)).unwrap();
+            // An expired association can be created again, its history gets a second line
+            store.create(expired_association(expired)).unwrap();
+            store.create(Association::new(waiting)).unwrap();
+        }
+        let history = fs::read_to_string(path).unwrap();
+        assert_eq!(history.lines().count(), 3);
+
+        let store = FileAssociationStore::open(path).unwrap();
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(store.get(expired).unwrap().state, AssociationState::Expired);
+        assert_eq!(store.get(waiting).unwrap().state, AssociationState::Waiting);
+        assert_eq!(fs::read_to_string(path).unwrap(), history);
+    }
+
+    #[test]
+    fn outdated_associations_are_kept_in_the_history() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("associations.json");
+        let path = path.to_str().unwrap();
+        let outdated = Uuid::new_v4();
+
+        let mut info = expired_association(outdated).info();
+        info.creation_timestamp = Utc::now() - Duration::days(1);
+        fs::write(path, format!("{}\n", line(&info))).unwrap();
+
+        let store = FileAssociationStore::open(path).unwrap();
+        assert!(store.get(outdated).is_none());
+        drop(store);
+
+        let history = fs::read_to_string(path).unwrap();
+        assert_eq!(history.lines().collect::<Vec<_>>(), [line(&info)]);
+    }
+}
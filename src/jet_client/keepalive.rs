@@ -0,0 +1,213 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Stream};
+use log::{error, info, warn};
+use tokio::timer::Interval;
+use tokio_io::AsyncWrite;
+use uuid::Uuid;
+
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+
+use super::association_store::JetAssociationStore;
+use super::JetMsgReader;
+use crate::config::Config;
+use crate::transport::JetTransport;
+
+/// Requests to stop the keepalive of a parked connection. The keepalive answers on the given channel once the
+/// connection can be used by a client.
+pub type ReleaseRequests = oneshot::Receiver<oneshot::Sender<()>>;
+
+/// Kept with a parked server connection to take it back from its keepalive.
+pub struct KeepaliveHandle {
+    release: oneshot::Sender<oneshot::Sender<()>>,
+}
+
+impl KeepaliveHandle {
+    pub fn channel() -> (Self, ReleaseRequests) {
+        let (release, requests) = oneshot::channel();
+        (KeepaliveHandle { release }, requests)
+    }
+
+    /// Stops the pings. Resolves when no Pong is expected anymore from the server.
+    pub fn release(self) -> impl Future<Item = (), Error = io::Error> + Send {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.release.send(sender);
+        receiver.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "The server connection was lost while it was parked",
+            )
+        })
+    }
+}
+
+pub fn build_ping() -> JetPacket {
+    let mut ping = JetPacket::new_response(0, 0, ResponseStatusCode::StatusCode200);
+    ping.set_method(Some(JetMethod::PING));
+    ping
+}
+
+pub fn build_pong(ping: &JetPacket) -> JetPacket {
+    let mut pong = JetPacket::new_response(ping.flags(), ping.mask(), ResponseStatusCode::StatusCode200);
+    pong.set_method(Some(JetMethod::PONG));
+    pong
+}
+
+/// Pings a parked server connection until a client takes it. The connection is removed from its association when the
+/// server misses too many pongs or closes it. Pings sent by the server are answered too.
+pub struct ParkedKeepalive {
+    transport: JetTransport,
+    reader: JetMsgReader,
+    association: Uuid,
+    connection_id: u64,
+    association_store: JetAssociationStore,
+    interval: Interval,
+    max_missed_pongs: u32,
+    missed_pongs: u32,
+    pong_expected: bool,
+    outgoing: Vec<u8>,
+    release_requests: Option<ReleaseRequests>,
+    released: Option<oneshot::Sender<()>>,
+}
+
+impl ParkedKeepalive {
+    pub fn new(
+        config: &Config,
+        transport: JetTransport,
+        association: Uuid,
+        connection_id: u64,
+        association_store: JetAssociationStore,
+        release_requests: ReleaseRequests,
+    ) -> Self {
+        let period = Duration::from_secs(u64::from(config.keepalive_interval()));
+        ParkedKeepalive {
+            reader: JetMsgReader::new(transport.clone()),
+            transport,
+            association,
+            connection_id,
+            association_store,
+            interval: Interval::new(Instant::now() + period, period),
+            max_missed_pongs: config.keepalive_max_missed_pongs().max(1),
+            missed_pongs: 0,
+            pong_expected: false,
+            outgoing: Vec::new(),
+            release_requests: Some(release_requests),
+            released: None,
+        }
+    }
+
+    fn queue(&mut self, packet: &JetPacket) -> io::Result<()> {
+        packet.write_to(&mut self.outgoing)
+    }
+
+    /// Removes the connection from its association, unless a client already took it.
+    fn remove(&mut self, reason: &str) {
+        if self.released.is_some() {
+            error!(
+                "Server connection of association {} can't be handed to the client: {}",
+                self.association, reason
+            );
+            return;
+        }
+
+        if let Some(mut transport) = self
+            .association_store
+            .expire_server_connection(self.association, self.connection_id)
+        {
+            info!(
+                "Parked server connection of association {} removed: {}",
+                self.association, reason
+            );
+            let _ = transport.shutdown();
+        }
+    }
+
+    fn poll_write(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.transport.poll_write(&self.outgoing)? {
+                Async::Ready(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Async::Ready(len) => {
+                    self.outgoing.drain(..len);
+                }
+                Async::NotReady => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Future for ParkedKeepalive {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
+        loop {
+            match self.release_requests.as_mut().map(Future::poll) {
+                Some(Ok(Async::Ready(released))) => {
+                    self.released = Some(released);
+                    self.release_requests = None;
+                }
+                // The connection has been removed from its association
+                Some(Err(_)) => return Ok(Async::Ready(())),
+                _ => {}
+            }
+
+            if let Err(e) = self.poll_write() {
+                self.remove(&format!("Failed to write: {}", e));
+                return Ok(Async::Ready(()));
+            }
+
+            // The connection is handed over between two messages
+            if self.released.is_some()
+                && !self.pong_expected
+                && self.outgoing.is_empty()
+                && self.reader.data_received.is_empty()
+            {
+                let _ = self.released.take().unwrap().send(());
+                return Ok(Async::Ready(()));
+            }
+
+            match self.reader.poll_packet() {
+                Ok(Async::Ready(packet)) => {
+                    if packet.is_ping() {
+                        self.queue(&build_pong(&packet))?;
+                    } else if packet.is_pong() {
+                        self.pong_expected = false;
+                        self.missed_pongs = 0;
+                    } else {
+                        warn!(
+                            "Unexpected message received on a parked server connection of association {}: {:?}",
+                            self.association, packet
+                        );
+                    }
+                    continue;
+                }
+                Ok(Async::NotReady) => {}
+                Err(e) => {
+                    self.remove(&format!("Connection closed by the server: {}", e));
+                    return Ok(Async::Ready(()));
+                }
+            }
+
+            match self.interval.poll() {
+                Ok(Async::Ready(Some(_))) => {
+                    if self.pong_expected {
+                        self.missed_pongs += 1;
+                        if self.released.is_some() || self.missed_pongs >= self.max_missed_pongs {
+                            let reason = format!("{} Pong messages missed", self.missed_pongs);
+                            self.remove(&reason);
+                            return Ok(Async::Ready(()));
+                        }
+                    } else if self.released.is_none() {
+                        self.queue(&build_ping())?;
+                        self.pong_expected = true;
+                    }
+                }
+                Ok(_) => return Ok(Async::NotReady),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            }
+        }
+    }
+}
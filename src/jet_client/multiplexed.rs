@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+use futures::{Async, Future};
+use log::{error, info, warn};
+use tokio_io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use jet_proto::mux::{is_server_channel, MuxFrame, MuxFrameType, MUX_INITIAL_WINDOW_SIZE};
+
+use crate::config::Config;
+use crate::interceptor::queue::QueuedInterceptor;
+use crate::interceptor::PacketInterceptor;
+use crate::transport::JetTransport;
+use crate::{Proxy, SESSION_IN_PROGRESS_COUNT};
+
+const READ_BUFFER_SIZE: usize = 16384;
+/// Reading from a peer stops when this many bytes are waiting to be written to the other peer.
+const MAX_PENDING_SIZE: usize = 4 * 65536;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Server = 0,
+    Client = 1,
+}
+
+impl Side {
+    fn name(self) -> &'static str {
+        match self {
+            Side::Server => "server",
+            Side::Client => "client",
+        }
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Server => Side::Client,
+            Side::Client => Side::Server,
+        }
+    }
+}
+
+/// Logical channel of a multiplexed session. Indexes of the arrays are the side sending the data.
+struct Channel {
+    name: String,
+    /// Number of bytes each side can still send before receiving a window update
+    windows: [u32; 2],
+    /// Number of bytes sent by each side
+    bytes: [u64; 2],
+    /// Sides that sent a Close frame. A side that closed the channel doesn't send data anymore but can still receive
+    /// the data of the other side until it closes too.
+    closed: [bool; 2],
+}
+
+impl Channel {
+    fn log_close(&self, id: u32, association: Uuid) {
+        info!(
+            "Channel {} ({}) of association {} closed: {} bytes sent by the server and {} bytes sent by the client",
+            id,
+            self.name,
+            association,
+            self.bytes[Side::Server as usize],
+            self.bytes[Side::Client as usize]
+        );
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct MuxPeer {
+    side: Side,
+    transport: JetTransport,
+    addr: Option<SocketAddr>,
+    closed: bool,
+    /// Bytes received from the peer that don't form a complete frame yet
+    incoming: Vec<u8>,
+    /// Frames waiting to be written to the peer
+    outgoing: Vec<u8>,
+}
+
+impl MuxPeer {
+    fn new(side: Side, transport: JetTransport) -> Self {
+        MuxPeer {
+            side,
+            addr: transport.peer_addr(),
+            transport,
+            closed: false,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    fn poll_write(&mut self) -> io::Result<bool> {
+        let mut progress = false;
+        while !self.outgoing.is_empty() {
+            match self.transport.poll_write(&self.outgoing)? {
+                Async::Ready(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Async::Ready(len) => {
+                    self.outgoing.drain(..len);
+                    progress = true;
+                }
+                Async::NotReady => break,
+            }
+        }
+        Ok(progress)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.transport.shutdown();
+    }
+}
+
+/// Relay between a server and a client sharing one connection between several logical channels. Frames are forwarded
+/// unchanged, but the proxy keeps track of the channels to enforce their flow control and to account their traffic.
+pub struct MultiplexedProxy {
+    association: Uuid,
+    server: MuxPeer,
+    client: MuxPeer,
+    channels: HashMap<u32, Channel>,
+    interceptors: Vec<QueuedInterceptor>,
+}
+
+impl MultiplexedProxy {
+    pub fn new(
+        config: &Config,
+        association: Uuid,
+        server_transport: JetTransport,
+        client_transport: JetTransport,
+    ) -> Self {
+        let server = MuxPeer::new(Side::Server, server_transport);
+        let client = MuxPeer::new(Side::Client, client_transport);
+
+        let interceptors = match (server.addr, client.addr) {
+            (Some(server_addr), Some(client_addr)) => Proxy::new(config.clone())
+                .with_route(format!("JET association {}", association))
+                .packet_interceptors(association, server_addr, client_addr),
+            _ => Vec::new(),
+        };
+
+        info!("Multiplexed session started for association {}", association);
+        SESSION_IN_PROGRESS_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        MultiplexedProxy {
+            association,
+            server,
+            client,
+            channels: HashMap::new(),
+            interceptors,
+        }
+    }
+
+    /// Reads the frames sent by `source` and queues them for `destination`.
+    fn relay(
+        source: &mut MuxPeer,
+        destination: &mut MuxPeer,
+        channels: &mut HashMap<u32, Channel>,
+        association: Uuid,
+        interceptors: &mut [QueuedInterceptor],
+    ) -> io::Result<bool> {
+        let mut progress = false;
+        if source.closed {
+            return Ok(progress);
+        }
+
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        // Stop reading when the destination doesn't take the data, the peer is slowed down by TCP
+        while destination.outgoing.len() < MAX_PENDING_SIZE {
+            match source.transport.poll_read(&mut buffer)? {
+                Async::Ready(0) => {
+                    if !source.incoming.is_empty() {
+                        error!(
+                            "The {} of association {} closed the connection in the middle of a frame",
+                            source.side.name(),
+                            association
+                        );
+                    }
+                    source.closed = true;
+                    return Ok(true);
+                }
+                Async::Ready(len) => {
+                    for interceptor in interceptors.iter_mut() {
+                        interceptor.on_new_packet(source.addr, &buffer[..len]);
+                    }
+                    source.incoming.extend_from_slice(&buffer[..len]);
+                    progress = true;
+                }
+                Async::NotReady => break,
+            }
+
+            while let Some(frame_len) = MuxFrame::frame_len(&source.incoming) {
+                if source.incoming.len() < frame_len {
+                    break;
+                }
+
+                let frame = MuxFrame::read_from(&mut &source.incoming[..frame_len])?;
+                let frame_data = source.incoming.drain(..frame_len);
+                match check_frame(&frame, source.side, channels, association)? {
+                    FrameAction::Forward => destination.outgoing.extend(frame_data),
+                    FrameAction::Reject => MuxFrame::new(frame.channel_id(), MuxFrameType::Close, Vec::new())
+                        .write_to(&mut source.outgoing)?,
+                    FrameAction::Drop => {}
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    fn poll_peers(&mut self) -> io::Result<bool> {
+        let mut progress = false;
+        progress |= self.server.poll_write()?;
+        progress |= self.client.poll_write()?;
+        progress |= MultiplexedProxy::relay(
+            &mut self.server,
+            &mut self.client,
+            &mut self.channels,
+            self.association,
+            &mut self.interceptors,
+        )?;
+        progress |= MultiplexedProxy::relay(
+            &mut self.client,
+            &mut self.server,
+            &mut self.channels,
+            self.association,
+            &mut self.interceptors,
+        )?;
+        Ok(progress)
+    }
+}
+
+/// What the relay does with a frame
+enum FrameAction {
+    Forward,
+    /// The channel is closed for the sender only: a Close frame is sent back and the other side never hears about it
+    Reject,
+    /// The frame is about a channel the other side doesn't know
+    Drop,
+}
+
+/// Updates the state of the channels with a frame sent by `side`. An Open frame using an ID of the other side or of a
+/// channel already open is rejected, the other frames breaking the protocol end the session.
+fn check_frame(
+    frame: &MuxFrame,
+    side: Side,
+    channels: &mut HashMap<u32, Channel>,
+    association: Uuid,
+) -> io::Result<FrameAction> {
+    let id = frame.channel_id();
+    match frame.frame_type() {
+        MuxFrameType::Open => {
+            if is_server_channel(id) != (side == Side::Server) || channels.contains_key(&id) {
+                warn!(
+                    "The {} of association {} opened channel {} which is already open or is an ID of the {}",
+                    side.name(),
+                    association,
+                    id,
+                    side.other().name()
+                );
+                return Ok(FrameAction::Reject);
+            }
+
+            let name = String::from_utf8_lossy(frame.payload()).into_owned();
+            info!(
+                "Channel {} ({}) of association {} opened by the {}",
+                id,
+                name,
+                association,
+                side.name()
+            );
+            channels.insert(
+                id,
+                Channel {
+                    name,
+                    windows: [MUX_INITIAL_WINDOW_SIZE; 2],
+                    bytes: [0; 2],
+                    closed: [false; 2],
+                },
+            );
+        }
+        MuxFrameType::Data => {
+            let channel = channels
+                .get_mut(&id)
+                .ok_or_else(|| protocol_error(format!("The {} sent data on unknown channel {}", side.name(), id)))?;
+            if channel.closed[side as usize] {
+                return Err(protocol_error(format!(
+                    "The {} sent data on channel {} after closing it",
+                    side.name(),
+                    id
+                )));
+            }
+
+            let len = frame.payload().len() as u32;
+            let window = &mut channel.windows[side as usize];
+            if len > *window {
+                return Err(protocol_error(format!(
+                    "The {} sent {} bytes on channel {} but only {} bytes were allowed",
+                    side.name(),
+                    len,
+                    id,
+                    window
+                )));
+            }
+            *window -= len;
+            channel.bytes[side as usize] += u64::from(len);
+        }
+        MuxFrameType::WindowUpdate => {
+            let channel = channels.get_mut(&id).ok_or_else(|| {
+                protocol_error(format!(
+                    "The {} sent a window update on unknown channel {}",
+                    side.name(),
+                    id
+                ))
+            })?;
+
+            // The side receiving the data grants more bytes to the other side
+            let increment = frame
+                .window_increment()
+                .ok_or_else(|| protocol_error(format!("The {} sent an invalid window update", side.name())))?;
+            let window = &mut channel.windows[side.other() as usize];
+            *window = window
+                .checked_add(increment)
+                .ok_or_else(|| protocol_error(format!("The window of channel {} overflowed", id)))?;
+        }
+        MuxFrameType::Close => {
+            // The channel is closed once both sides closed it, until then the other side can still send data. The
+            // answer to the Close frame of a rejected channel is not forwarded.
+            let closed = match channels.get_mut(&id) {
+                Some(channel) => {
+                    channel.closed[side as usize] = true;
+                    channel.closed == [true; 2]
+                }
+                None => return Ok(FrameAction::Drop),
+            };
+            if closed {
+                if let Some(channel) = channels.remove(&id) {
+                    channel.log_close(id, association);
+                }
+            }
+        }
+    }
+    Ok(FrameAction::Forward)
+}
+
+impl Future for MultiplexedProxy {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
+        loop {
+            match self.poll_peers() {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.server.shutdown();
+                    self.client.shutdown();
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("Multiplexed session of association {} failed: {}", self.association, e),
+                    ));
+                }
+            }
+        }
+
+        // A peer closing its connection ends the session once the frames it sent have been delivered
+        if (self.server.closed && self.client.outgoing.is_empty())
+            || (self.client.closed && self.server.outgoing.is_empty())
+        {
+            self.server.shutdown();
+            self.client.shutdown();
+            for mut interceptor in self.interceptors.drain(..) {
+                interceptor.on_session_end(false);
+            }
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for MultiplexedProxy {
+    fn drop(&mut self) {
+        // The session didn't end normally if the interceptors are still there
+        for mut interceptor in self.interceptors.drain(..) {
+            interceptor.on_session_end(true);
+        }
+        SESSION_IN_PROGRESS_COUNT.fetch_sub(1, Ordering::Relaxed);
+
+        for (id, channel) in &self.channels {
+            channel.log_close(*id, self.association);
+        }
+        info!("Multiplexed session of association {} finished", self.association);
+    }
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{err, ok};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Async, Future, Stream};
+use log::{error, info, warn};
+use tokio::runtime::TaskExecutor;
+use tokio::timer::Delay;
+use tokio_io::io::write_all;
+use tokio_io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
+
+use jet_proto::{JetPacket, ResponseStatusCode};
+
+use crate::config::Config;
+use crate::interceptor::queue::QueuedInterceptor;
+use crate::interceptor::PacketInterceptor;
+use crate::transport::JetTransport;
+use crate::{Proxy, SESSION_IN_PROGRESS_COUNT};
+
+const READ_BUFFER_SIZE: usize = 16384;
+
+pub type JetResumableSessions = Arc<ResumableSessions>;
+
+/// Peer reconnecting to a resumable session.
+struct ResumeRequest {
+    transport: JetTransport,
+    request_msg: JetPacket,
+}
+
+struct RegisteredPeer {
+    association: Uuid,
+    resume_requests: UnboundedSender<ResumeRequest>,
+}
+
+/// Resume tokens of the resumable sessions in progress.
+#[derive(Default)]
+pub struct ResumableSessions {
+    peers: Mutex<HashMap<Uuid, RegisteredPeer>>,
+}
+
+impl ResumableSessions {
+    pub fn new() -> Self {
+        ResumableSessions::default()
+    }
+
+    /// Hands a reconnecting peer over to its session, which answers the resume request. The request is refused if the
+    /// resume token is unknown or doesn't belong to the association.
+    pub fn resume(
+        &self,
+        transport: JetTransport,
+        request_msg: JetPacket,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let peers = self.peers.lock().unwrap();
+        let peer = request_msg
+            .resume_token()
+            .and_then(|token| peers.get(&token))
+            .filter(|peer| Some(peer.association) == request_msg.association());
+
+        let result = match peer {
+            Some(peer) => peer
+                .resume_requests
+                .unbounded_send(ResumeRequest { transport, request_msg })
+                .map_err(|e| e.into_inner()),
+            None => Err(ResumeRequest { transport, request_msg }),
+        };
+
+        match result {
+            Ok(()) => Box::new(ok(())),
+            Err(request) => {
+                let association = request.request_msg.association();
+                Box::new(refuse(request).and_then(move |_| {
+                    err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "Invalid resume request: unknown resume token for association {:?}",
+                            association
+                        ),
+                    ))
+                }))
+            }
+        }
+    }
+
+    fn register(&self, token: Uuid, association: Uuid) -> UnboundedReceiver<ResumeRequest> {
+        let (sender, receiver) = unbounded();
+        self.peers.lock().unwrap().insert(
+            token,
+            RegisteredPeer {
+                association,
+                resume_requests: sender,
+            },
+        );
+        receiver
+    }
+
+    fn unregister(&self, token: Uuid) {
+        self.peers.lock().unwrap().remove(&token);
+    }
+}
+
+fn refuse(request: ResumeRequest) -> impl Future<Item = (), Error = io::Error> {
+    let response = JetPacket::new_response(
+        request.request_msg.flags(),
+        request.request_msg.mask(),
+        ResponseStatusCode::StatusCode400,
+    );
+    let mut v = Vec::new();
+    let result = response.write_to(&mut v);
+    futures::future::result(result).and_then(move |_| write_all(request.transport, v).map(|_| ()))
+}
+
+/// One side of a resumable session. The bytes sent to the peer are kept after being written, so they can be sent again
+/// if the peer didn't receive them before the connection was lost. Sequence numbers are byte offsets in each direction.
+struct Peer {
+    name: &'static str,
+    transport: Option<JetTransport>,
+    addr: Option<SocketAddr>,
+    resume_token: Option<Uuid>,
+    resume_requests: Option<UnboundedReceiver<ResumeRequest>>,
+    grace_timer: Option<Delay>,
+    closed: bool,
+    /// Number of bytes received from the peer
+    received: u64,
+    /// Resume response to send before the data
+    response: Vec<u8>,
+    outgoing: Vec<u8>,
+    /// Sequence number of the first byte of `outgoing`
+    outgoing_start: u64,
+    /// Sequence number of the next byte to write
+    written: u64,
+}
+
+impl Peer {
+    fn new(
+        name: &'static str,
+        transport: JetTransport,
+        resume_token: Option<Uuid>,
+        association: Uuid,
+        sessions: &ResumableSessions,
+    ) -> Self {
+        Peer {
+            name,
+            addr: transport.peer_addr(),
+            transport: Some(transport),
+            resume_token,
+            resume_requests: resume_token.map(|token| sessions.register(token, association)),
+            grace_timer: None,
+            closed: false,
+            received: 0,
+            response: Vec::new(),
+            outgoing: Vec::new(),
+            outgoing_start: 0,
+            written: 0,
+        }
+    }
+
+    fn pending(&self) -> usize {
+        (self.outgoing_start + self.outgoing.len() as u64 - self.written) as usize
+    }
+
+    fn is_flushed(&self) -> bool {
+        self.pending() == 0 && self.response.is_empty()
+    }
+
+    /// The connection with the peer is lost. The session waits for the peer during the grace period if it can resume.
+    fn lose(&mut self, error: io::Error, association: Uuid, grace_period: Duration) -> io::Result<()> {
+        self.transport = None;
+        if self.resume_token.is_none() {
+            return Err(error);
+        }
+
+        warn!(
+            "Connection with the {} of association {} lost ({}). Waiting {}s for the {} to resume",
+            self.name,
+            association,
+            error,
+            grace_period.as_secs(),
+            self.name
+        );
+        self.grace_timer = Some(Delay::new(Instant::now() + grace_period));
+        Ok(())
+    }
+
+    fn poll_resume_requests(&mut self, association: Uuid, executor_handle: &TaskExecutor) -> bool {
+        let mut progress = false;
+        loop {
+            let request = match self.resume_requests.as_mut().map(Stream::poll) {
+                Some(Ok(Async::Ready(Some(request)))) => request,
+                _ => return progress,
+            };
+            progress = true;
+
+            // The peer tells how many bytes it received, they must still be in the buffer to be sent again
+            let sequence = request.request_msg.sequence().unwrap_or(0);
+            if sequence < self.outgoing_start || sequence > self.written {
+                error!(
+                    "The {} of association {} can't resume at byte {}: bytes {} to {} are available",
+                    self.name, association, sequence, self.outgoing_start, self.written
+                );
+                executor_handle.spawn(refuse(request).map_err(|e| error!("Failed to refuse resume request: {}", e)));
+                continue;
+            }
+
+            let mut response = JetPacket::new_response(
+                request.request_msg.flags(),
+                request.request_msg.mask(),
+                ResponseStatusCode::StatusCode200,
+            );
+            response.set_association(Some(association));
+            response.set_resumable(Some(true));
+            response.set_resume_token(self.resume_token);
+            response.set_sequence(Some(self.received));
+            self.response.clear();
+            if let Err(e) = response.write_to(&mut self.response) {
+                error!("Failed to build the resume response: {}", e);
+                continue;
+            }
+
+            info!(
+                "The {} of association {} resumed the session at byte {}",
+                self.name, association, sequence
+            );
+            if let Some(mut transport) = self.transport.take() {
+                let _ = transport.shutdown();
+            }
+            self.transport = Some(request.transport);
+            self.grace_timer = None;
+            self.written = sequence;
+        }
+    }
+
+    fn poll_grace_timer(&mut self, association: Uuid) -> io::Result<()> {
+        let expired = match self.grace_timer.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+            _ => false,
+        };
+
+        if expired {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "The {} of association {} didn't resume the session in time",
+                    self.name, association
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn poll_write(&mut self, replay_buffer_size: u64) -> io::Result<bool> {
+        let mut progress = false;
+        if let Some(transport) = self.transport.as_mut() {
+            while !self.response.is_empty() {
+                match transport.poll_write(&self.response)? {
+                    Async::Ready(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Async::Ready(len) => {
+                        self.response.drain(..len);
+                        progress = true;
+                    }
+                    Async::NotReady => return Ok(progress),
+                }
+            }
+
+            while self.written < self.outgoing_start + self.outgoing.len() as u64 {
+                let offset = (self.written - self.outgoing_start) as usize;
+                match transport.poll_write(&self.outgoing[offset..])? {
+                    Async::Ready(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Async::Ready(len) => {
+                        self.written += len as u64;
+                        progress = true;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+        }
+
+        // Only keep the written bytes that could have to be sent again
+        let replay_buffer_size = if self.resume_token.is_some() {
+            replay_buffer_size
+        } else {
+            0
+        };
+        let written = self.written - self.outgoing_start;
+        if written > replay_buffer_size {
+            let len = (written - replay_buffer_size) as usize;
+            self.outgoing.drain(..len);
+            self.outgoing_start += len as u64;
+        }
+
+        Ok(progress)
+    }
+
+    fn poll_read(
+        &mut self,
+        destination: &mut Peer,
+        max_pending: usize,
+        interceptors: &mut [QueuedInterceptor],
+    ) -> io::Result<bool> {
+        let mut progress = false;
+        if self.closed {
+            return Ok(progress);
+        }
+
+        if let Some(transport) = self.transport.as_mut() {
+            let mut buffer = [0u8; READ_BUFFER_SIZE];
+            // Stop reading when the destination doesn't take the data, the peer is slowed down by TCP
+            while destination.pending() < max_pending {
+                match transport.poll_read(&mut buffer)? {
+                    Async::Ready(0) => {
+                        self.closed = true;
+                        return Ok(true);
+                    }
+                    Async::Ready(len) => {
+                        for interceptor in interceptors.iter_mut() {
+                            interceptor.on_new_packet(self.addr, &buffer[..len]);
+                        }
+                        destination.outgoing.extend_from_slice(&buffer[..len]);
+                        self.received += len as u64;
+                        progress = true;
+                    }
+                    Async::NotReady => break,
+                }
+            }
+        }
+
+        Ok(progress)
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(mut transport) = self.transport.take() {
+            let _ = transport.shutdown();
+        }
+    }
+}
+
+/// Relay between a server and a client where a peer losing its connection can reconnect and continue the session
+/// without losing data.
+pub struct ResumableProxy {
+    association: Uuid,
+    server: Peer,
+    client: Peer,
+    grace_period: Duration,
+    buffer_size: usize,
+    interceptors: Vec<QueuedInterceptor>,
+    sessions: JetResumableSessions,
+    executor_handle: TaskExecutor,
+}
+
+impl ResumableProxy {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: &Config,
+        association: Uuid,
+        server_transport: JetTransport,
+        server_resume_token: Option<Uuid>,
+        client_transport: JetTransport,
+        client_resume_token: Option<Uuid>,
+        sessions: JetResumableSessions,
+        executor_handle: TaskExecutor,
+    ) -> Self {
+        let server = Peer::new("server", server_transport, server_resume_token, association, &sessions);
+        let client = Peer::new("client", client_transport, client_resume_token, association, &sessions);
+
+        let interceptors = match (server.addr, client.addr) {
+            (Some(server_addr), Some(client_addr)) => Proxy::new(config.clone())
+                .with_route(format!("JET association {}", association))
+                .packet_interceptors(association, server_addr, client_addr),
+            _ => Vec::new(),
+        };
+
+        info!("Resumable session started for association {}", association);
+        SESSION_IN_PROGRESS_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        ResumableProxy {
+            association,
+            server,
+            client,
+            grace_period: Duration::from_secs(u64::from(config.resume_grace_period())),
+            buffer_size: config.resume_buffer_size() as usize,
+            interceptors,
+            sessions,
+            executor_handle,
+        }
+    }
+
+    fn poll_peers(&mut self) -> io::Result<bool> {
+        let association = self.association;
+        let grace_period = self.grace_period;
+        let mut progress = false;
+
+        progress |= self.server.poll_resume_requests(association, &self.executor_handle);
+        progress |= self.client.poll_resume_requests(association, &self.executor_handle);
+        self.server.poll_grace_timer(association)?;
+        self.client.poll_grace_timer(association)?;
+
+        for peer in [&mut self.server, &mut self.client].iter_mut() {
+            match peer.poll_write(self.buffer_size as u64) {
+                Ok(written) => progress |= written,
+                Err(e) => {
+                    peer.lose(e, association, grace_period)?;
+                    progress = true;
+                }
+            }
+        }
+
+        match self
+            .server
+            .poll_read(&mut self.client, self.buffer_size, &mut self.interceptors)
+        {
+            Ok(read) => progress |= read,
+            Err(e) => {
+                self.server.lose(e, association, grace_period)?;
+                progress = true;
+            }
+        }
+
+        match self
+            .client
+            .poll_read(&mut self.server, self.buffer_size, &mut self.interceptors)
+        {
+            Ok(read) => progress |= read,
+            Err(e) => {
+                self.client.lose(e, association, grace_period)?;
+                progress = true;
+            }
+        }
+
+        Ok(progress)
+    }
+}
+
+impl Future for ResumableProxy {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
+        while self.poll_peers()? {}
+
+        // A peer closing its connection ends the session once the data it sent has been delivered
+        if (self.server.closed && self.client.is_flushed()) || (self.client.closed && self.server.is_flushed()) {
+            self.server.shutdown();
+            self.client.shutdown();
+            for mut interceptor in self.interceptors.drain(..) {
+                interceptor.on_session_end(false);
+            }
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for ResumableProxy {
+    fn drop(&mut self) {
+        // The session didn't end normally if the interceptors are still there
+        for mut interceptor in self.interceptors.drain(..) {
+            interceptor.on_session_end(true);
+        }
+        for token in [self.server.resume_token, self.client.resume_token].iter().flatten() {
+            self.sessions.unregister(*token);
+        }
+        SESSION_IN_PROGRESS_COUNT.fetch_sub(1, Ordering::Relaxed);
+
+        info!(
+            "Resumable session of association {} finished: {} bytes received from the server and {} bytes received from the client",
+            self.association, self.server.received, self.client.received
+        );
+    }
+}
@@ -1,46 +1,66 @@
 #[macro_use]
 mod utils;
 mod config;
+mod forward_proxy;
+mod health_check;
 mod http;
 mod interceptor;
 mod jet_client;
+mod mutual_tls;
+mod outbound_proxy;
 mod rdp;
+mod relay_tls;
 mod routing_client;
+mod sni_routing;
+mod sniffer;
+mod source_filter;
+mod tls_verification;
 mod transport;
+mod upstream;
 
-use std::collections::HashMap;
 use std::io;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::{future, future::ok, Future, Stream};
-use native_tls::Identity;
+use futures::{future, future::err, future::ok, Future, Sink, Stream};
 use tokio::runtime::Runtime;
 use tokio_tcp::{TcpListener, TcpStream};
 
+use lazy_static::lazy_static;
 use log::{error, info, warn};
 use url::Url;
-use lazy_static::lazy_static;
+use uuid::Uuid;
 
 use crate::config::{Config, Protocol};
-use crate::interceptor::pcap::PcapInterceptor;
-use crate::interceptor::{rdp::RdpMessageReader, UnknownMessageReader, WaykMessageReader};
-use crate::jet_client::{JetAssociationsMap, JetClient};
+use crate::health_check::{HealthChecks, JetHealthChecks};
+use crate::http::http_server::HttpServer;
+use crate::interceptor::metrics::MetricsInterceptor;
+use crate::interceptor::pcap::{PcapInterceptor, PcapOutput, SessionMetadata};
+use crate::interceptor::queue::{InterceptorKind, QueuedInterceptor};
+use crate::interceptor::{rdp::RdpMessageReader, PacketInterceptor, UnknownMessageReader, WaykMessageReader};
+use crate::jet_client::association_store::{FileAssociationStore, JetAssociationStore, MemoryAssociationStore};
+use crate::jet_client::resumable::{JetResumableSessions, ResumableSessions};
+use crate::jet_client::JetClient;
+use crate::mutual_tls::JetTlsAcceptor;
 use crate::rdp::RdpClient;
+use crate::relay_tls::{KeyLog, RelayTlsAcceptor};
 use crate::routing_client::Client;
+use crate::sni_routing::{JetSniRoutingTable, SniRoutingTable, TlsRoute};
+use crate::sniffer::{SniffedProtocol, Sniffer};
 use crate::transport::tcp::TcpTransport;
 use crate::transport::{JetTransport, Transport};
+use crate::upstream::{JetUpstreamTargets, UpstreamTargets};
 use crate::utils::get_tls_pubkey;
-use crate::http::http_server::HttpServer;
 
 const SOCKET_SEND_BUFFER_SIZE: usize = 0x7FFFF;
 const SOCKET_RECV_BUFFER_SIZE: usize = 0x7FFFF;
 
 lazy_static! {
     pub static ref SESSION_IN_PROGRESS_COUNT: AtomicU64 = AtomicU64::new(0);
+    pub static ref REJECTED_CONNECTION_COUNT: AtomicU64 = AtomicU64::new(0);
 }
 
 fn main() {
@@ -60,56 +80,224 @@ fn main() {
 
     let socket_addr = listener_addr.parse::<SocketAddr>().unwrap();
 
-    let routing_url_opt = match config.routing_url() {
-        Some(url) => Some(Url::parse(&url).expect("routing_url is invalid.")),
-        None => None,
-    };
+    let routing_urls: Vec<Url> = config
+        .routing_urls()
+        .iter()
+        .map(|url| Url::parse(url).expect("routing_url is invalid."))
+        .collect();
+    let routing_url_opt = routing_urls.first().cloned();
+    if let Some(ref routing_url) = routing_url_opt {
+        if routing_urls.iter().any(|url| url.scheme() != routing_url.scheme()) {
+            panic!("All the routing urls must use the same scheme");
+        }
+    }
+    let routing_targets: JetUpstreamTargets = Arc::new(UpstreamTargets::from_config(routing_urls, &config));
+
+    // A sniff listener identifies the protocol of every connection instead of using the routing url
+    let sniff_protocol = url.scheme() == "sniff";
+    if sniff_protocol && routing_url_opt.is_some() {
+        warn!("routing_url is ignored by a sniff listener");
+    }
+    // socks5 and http-connect listeners relay every connection to the destination requested by its client
+    let socks5_listener = url.scheme() == "socks5";
+    let http_connect_listener = url.scheme() == "http-connect";
+    if (socks5_listener || http_connect_listener) && routing_url_opt.is_some() {
+        warn!("routing_url is ignored by a {} listener", url.scheme());
+    }
+    if (socks5_listener || http_connect_listener) && !config.forward_proxy_rules().has_allowed_destinations() {
+        warn!(
+            "Every destination is denied on the {} listener: proxy_listener_allow is not set",
+            url.scheme()
+        );
+    }
+    // A tls listener terminates TLS in front of the JET protocol
+    let tls_listener = url.scheme() == "tls";
+    if tls_listener && routing_url_opt.is_some() {
+        warn!("The tls scheme of the listener url is only used by JET listeners");
+    }
+    let tls_forward_route_opt = config.tls_forward_url().map(|url| {
+        let url = Url::parse(&url).expect("tls_forward_url is invalid.");
+        Arc::new(TlsRoute::passthrough(Arc::new(
+            UpstreamTargets::single(url, config.upstream_tls_verification().clone())
+                .with_proxy(config.upstream_proxy().cloned()),
+        )))
+    });
+
+    if config
+        .pcap_output()
+        .map(|output| output.overwrites_sessions())
+        .unwrap_or(false)
+    {
+        warn!("Every session overwrites the pcap file: pcap_file has no placeholder and pcap_shared is not set");
+    }
+    if config.pcap_output().is_some()
+        && !config
+            .packet_interceptors()
+            .iter()
+            .any(|worker| worker.kind() == InterceptorKind::Pcap)
+    {
+        warn!("Sessions are not captured: pcap_file is set but the pcap interceptor is not selected");
+    }
+
+    // The key log applies to the acceptors and connectors created afterwards
+    if let Some(filename) = config.tls_keylog_filename() {
+        KeyLog::install(&filename).expect("tls_keylog_file can't be opened.");
+        warn!(
+            "The secrets of the TLS sessions are written in {}: anyone reading it can decrypt them",
+            filename
+        );
+    }
 
     // Initialize the various data structures we're going to use in our server.
     let listener = TcpListener::bind(&socket_addr).unwrap();
-    let jet_associations: JetAssociationsMap = Arc::new(Mutex::new(HashMap::new()));
+    let association_store: JetAssociationStore = match config.associations_filename() {
+        Some(filename) => Arc::new(FileAssociationStore::open(&filename).expect("associations_file can't be opened.")),
+        None => Arc::new(MemoryAssociationStore::new()),
+    };
+    let resumable_sessions: JetResumableSessions = Arc::new(ResumableSessions::new());
 
     let mut runtime =
         Runtime::new().expect("This should never fails, a runtime is needed by the entire implementation");
     let executor_handle = runtime.executor();
 
     info!("Starting http server ...");
-    let http_server = HttpServer::new();
+    let health_checks: JetHealthChecks = Arc::new(HealthChecks::new());
+    let http_server = HttpServer::new(config.http_port(), association_store.clone(), health_checks.clone());
     if let Err(e) = http_server.start(executor_handle.clone()) {
         error!("http_server failed to start: {}", e);
         return;
     }
     info!("Http server succesfully started");
 
+    if config.health_check_interval() > 0 {
+        executor_handle.spawn(health_check::run(
+            config.clone(),
+            routing_targets.clone(),
+            health_checks,
+        ));
+    }
+
     // Create the TLS acceptor.
     let der = include_bytes!("cert/certificate.p12");
     let tls_public_key = get_tls_pubkey(der.as_ref(), "").unwrap();
-    let cert = Identity::from_pkcs12(der, "").unwrap();
-    let tls_acceptor = tokio_tls::TlsAcceptor::from(native_tls::TlsAcceptor::builder(cert).build().unwrap());
+    let tls_acceptor = RelayTlsAcceptor::from_pkcs12(der, "").unwrap();
+    // The JET and relay listeners require a client certificate when client authentication is configured
+    let jet_tls_acceptor = JetTlsAcceptor::from_pkcs12(der, "", config.tls_client_authentication())
+        .expect("tls_client_ca_file can't be loaded.");
+
+    let default_tls_route = Arc::new(TlsRoute::terminate(routing_targets.clone(), jet_tls_acceptor.clone()));
+    let tls_routes_opt: Option<JetSniRoutingTable> = config.tls_routes_filename().map(|filename| {
+        Arc::new(
+            SniRoutingTable::load(
+                &filename,
+                &jet_tls_acceptor,
+                config.upstream_tls_verification(),
+                config.upstream_proxy(),
+                config.tls_client_authentication(),
+            )
+            .expect("tls_routes_file can't be loaded."),
+        )
+    });
 
     info!("Listening for devolutions-jet proxy connections on {}", socket_addr);
     let server = listener.incoming().for_each(move |conn| {
+        // The source address rules of the listener apply before anything is read from the connection
+        if source_filter::check_client(conn.peer_addr().ok(), config.source_rules(), "listener").is_err() {
+            return ok(());
+        }
         set_socket_option(&conn);
 
         let config_clone = config.clone();
-        let client_fut = if let Some(ref routing_url) = routing_url_opt {
+        let client_fut = if sniff_protocol {
+            let listener_url = url.clone();
+            let tls_forward_route_opt = tls_forward_route_opt.clone();
+            let tls_routes_opt = tls_routes_opt.clone();
+            let association_store = association_store.clone();
+            let resumable_sessions = resumable_sessions.clone();
+            let executor_handle = executor_handle.clone();
+            let tls_public_key = tls_public_key.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            Box::new(Sniffer::new(conn).and_then(
+                move |(conn, protocol, prefix)| -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+                    let peer_addr = conn
+                        .peer_addr()
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_else(|_| "unknown".to_string());
+                    match protocol {
+                        SniffedProtocol::Jet => {
+                            JetClient::new(config_clone, association_store, resumable_sessions, executor_handle)
+                                .serve(JetTransport::new_tcp(conn))
+                        }
+                        // The destination of an RDP connection comes from the identities file
+                        SniffedProtocol::Rdp if config_clone.identities_filename().is_some() => {
+                            RdpClient::new(listener_url, config_clone, tls_public_key, tls_acceptor).serve(conn)
+                        }
+                        SniffedProtocol::Rdp => Box::new(err(io::Error::new(
+                            ErrorKind::Other,
+                            format!("RDP connection from {} rejected: no identities file", peer_addr),
+                        ))),
+                        SniffedProtocol::Tls { server_name } => {
+                            let server_name = server_name.as_deref();
+                            let route = tls_routes_opt
+                                .as_ref()
+                                .and_then(|tls_routes| tls_routes.route(server_name))
+                                .or(tls_forward_route_opt);
+                            match route {
+                                Some(route) => {
+                                    info!(
+                                        "TLS connection from {} (server name: {}) routed to {}",
+                                        peer_addr,
+                                        server_name.unwrap_or("none"),
+                                        route.destination()
+                                    );
+                                    route.serve(conn, config_clone, executor_handle)
+                                }
+                                None => Box::new(err(io::Error::new(
+                                    ErrorKind::Other,
+                                    format!("TLS connection from {} rejected: no TLS route", peer_addr),
+                                ))),
+                            }
+                        }
+                        SniffedProtocol::Unknown => Box::new(err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Unknown protocol received from {}: {:02X?}", peer_addr, prefix),
+                        ))),
+                    }
+                },
+            )) as Box<dyn Future<Item = (), Error = io::Error> + Send>
+        } else if socks5_listener {
+            forward_proxy::socks5::serve(conn, config_clone)
+        } else if http_connect_listener {
+            forward_proxy::http_connect::serve(conn, config_clone)
+        } else if let Some(ref routing_url) = routing_url_opt {
             match routing_url.scheme() {
                 "tcp" => {
                     let transport = TcpTransport::new(conn);
-                    Client::new(routing_url.clone(), config_clone, executor_handle.clone()).serve(transport)
+                    Client::new(routing_targets.clone(), config_clone, executor_handle.clone()).serve(transport)
                 }
                 "tls" => {
-                    let routing_url_clone = routing_url.clone();
-                    let executor_handle_clone = executor_handle.clone();
-                    Box::new(
-                        tls_acceptor
-                            .accept(conn)
-                            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
-                            .and_then(move |tls_stream| {
-                                let transport = TcpTransport::new_tls(tls_stream);
-                                Client::new(routing_url_clone, config_clone, executor_handle_clone).serve(transport)
-                            }),
-                    ) as Box<dyn Future<Item = (), Error = io::Error> + Send>
+                    let default_route = default_tls_route.clone();
+                    match tls_routes_opt.clone() {
+                        // The server name of the ClientHello is needed to choose the route
+                        Some(tls_routes) => {
+                            let executor_handle_clone = executor_handle.clone();
+                            Box::new(Sniffer::new(conn).and_then(move |(conn, protocol, _)| {
+                                let server_name = match protocol {
+                                    SniffedProtocol::Tls { server_name } => server_name,
+                                    _ => None,
+                                };
+                                let server_name = server_name.as_deref();
+                                let route = tls_routes.route(server_name).unwrap_or(default_route);
+                                info!(
+                                    "TLS connection (server name: {}) routed to {}",
+                                    server_name.unwrap_or("none"),
+                                    route.destination()
+                                );
+                                route.serve(conn, config_clone, executor_handle_clone)
+                            })) as Box<dyn Future<Item = (), Error = io::Error> + Send>
+                        }
+                        None => default_route.serve(conn, config_clone, executor_handle.clone()),
+                    }
                 }
                 "rdp" => RdpClient::new(
                     routing_url.clone(),
@@ -120,9 +308,22 @@ fn main() {
                 .serve(conn),
                 scheme => panic!("Unsupported routing url scheme {}", scheme),
             }
+        } else if tls_listener {
+            let association_store = association_store.clone();
+            let resumable_sessions = resumable_sessions.clone();
+            let executor_handle = executor_handle.clone();
+            Box::new(jet_tls_acceptor.accept(conn).and_then(move |transport| {
+                JetClient::new(config_clone, association_store, resumable_sessions, executor_handle)
+                    .serve(JetTransport::Tcp(transport))
+            })) as Box<dyn Future<Item = (), Error = io::Error> + Send>
         } else {
-            JetClient::new(config_clone, jet_associations.clone(), executor_handle.clone())
-                .serve(JetTransport::new_tcp(conn))
+            JetClient::new(
+                config_clone,
+                association_store.clone(),
+                resumable_sessions.clone(),
+                executor_handle.clone(),
+            )
+            .serve(JetTransport::new_tcp(conn))
         };
 
         executor_handle.spawn(client_fut.then(move |res| {
@@ -159,11 +360,73 @@ fn set_socket_option(stream: &TcpStream) {
 
 struct Proxy {
     config: Config,
+    route: Option<String>,
+    identity: Option<String>,
+    client_data: Vec<u8>,
 }
 
 impl Proxy {
     pub fn new(config: Config) -> Self {
-        Proxy { config }
+        Proxy {
+            config,
+            route: None,
+            identity: None,
+            client_data: Vec::new(),
+        }
+    }
+
+    /// Destination of the session, written in its capture
+    pub fn with_route(mut self, route: String) -> Self {
+        self.route = Some(route);
+        self
+    }
+
+    /// Authenticated identity of the client, written in the capture of the session
+    pub fn with_identity(mut self, identity: String) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Data received from the client before the relay started. It is sent to the server first, and seen by the
+    /// interceptors like the rest of the data of the client.
+    pub fn with_client_data(mut self, data: Vec<u8>) -> Self {
+        self.client_data = data;
+        self
+    }
+
+    /// Interceptors of the traffic between the server and the client, in the configured order. They are created by
+    /// the threads running them, so the pcap file of the session is never opened by the relay. The session is not
+    /// captured if its pcap file can't be created.
+    pub fn packet_interceptors(
+        &self,
+        session_id: Uuid,
+        server_addr: SocketAddr,
+        client_addr: SocketAddr,
+    ) -> Vec<QueuedInterceptor> {
+        self.config
+            .packet_interceptors()
+            .iter()
+            .filter_map(|worker| match worker.kind() {
+                InterceptorKind::Pcap => {
+                    let pcap_output = self.config.pcap_output()?.clone();
+                    let protocol = self.config.protocol().clone();
+                    let metadata = SessionMetadata {
+                        session_id,
+                        listener: self.config.listener_url(),
+                        route: self.route.clone(),
+                        identity: self.identity.clone(),
+                    };
+                    Some(QueuedInterceptor::new(worker.clone(), move || {
+                        let interceptor =
+                            pcap_interceptor(&pcap_output, &protocol, &metadata, server_addr, client_addr)?;
+                        Some(Box::new(interceptor) as Box<dyn PacketInterceptor>)
+                    }))
+                }
+                InterceptorKind::Metrics => Some(QueuedInterceptor::new(worker.clone(), move || {
+                    Some(Box::new(MetricsInterceptor::new(session_id, server_addr)) as Box<dyn PacketInterceptor>)
+                })),
+            })
+            .collect()
     }
 
     pub fn build<T: Transport, U: Transport>(
@@ -177,36 +440,29 @@ impl Proxy {
         let jet_sink_client = client_transport.message_sink();
         let mut jet_stream_client = client_transport.message_stream();
 
-        if let Some(pcap_filename) = self.config.pcap_filename() {
-            let mut interceptor = PcapInterceptor::new(
-                jet_stream_server.peer_addr().unwrap(),
-                jet_stream_client.peer_addr().unwrap(),
-                &pcap_filename,
-            );
-
-            match self.config.protocol() {
-                Protocol::WAYK => {
-                    info!("WaykMessageReader will be used to interpret application protocol.");
-                    interceptor.set_message_reader(WaykMessageReader::get_messages);
-                }
-                Protocol::RDP => {
-                    info!("RdpMessageReader will be used to interpret application protocol");
-                    interceptor.set_message_reader(RdpMessageReader::get_messages);
-                }
-                Protocol::UNKNOWN => {
-                    warn!("Protocol is unknown. Data received will not be split to get application message.");
-                    interceptor.set_message_reader(UnknownMessageReader::get_messages);
-                }
+        let session_id = Uuid::new_v4();
+        let client_addr = jet_stream_client.peer_addr().unwrap();
+        let mut interceptors =
+            self.packet_interceptors(session_id, jet_stream_server.peer_addr().unwrap(), client_addr);
+        for interceptor in &mut interceptors {
+            jet_stream_server.add_packet_interceptor(Box::new(interceptor.clone()));
+            jet_stream_client.add_packet_interceptor(Box::new(interceptor.clone()));
+            if !self.client_data.is_empty() {
+                interceptor.on_new_packet(Some(client_addr), &self.client_data);
             }
-
-            jet_stream_server.set_packet_interceptor(Box::new(interceptor.clone()));
-            jet_stream_client.set_packet_interceptor(Box::new(interceptor.clone()));
         }
 
-
         // Build future to forward all bytes
         let f1 = jet_stream_server.forward(jet_sink_client);
-        let f2 = jet_stream_client.forward(jet_sink_server);
+        let f2 = if self.client_data.is_empty() {
+            future::Either::A(jet_stream_client.forward(jet_sink_server))
+        } else {
+            future::Either::B(
+                jet_sink_server
+                    .send(self.client_data.clone())
+                    .and_then(move |jet_sink_server| jet_stream_client.forward(jet_sink_server)),
+            )
+        };
 
         SESSION_IN_PROGRESS_COUNT.fetch_add(1, Ordering::Relaxed);
 
@@ -222,7 +478,7 @@ impl Proxy {
             let _ = jet_sink.shutdown();
             ok((jet_stream, jet_sink))
         }))
-        .and_then(|((jet_stream_1, jet_sink_1), (jet_stream_2, jet_sink_2))| {
+        .and_then(move |((jet_stream_1, jet_sink_1), (jet_stream_2, jet_sink_2))| {
             let server_addr = jet_stream_1
                 .peer_addr()
                 .map(|addr| addr.to_string())
@@ -232,7 +488,8 @@ impl Proxy {
                 .map(|addr| addr.to_string())
                 .unwrap_or_else(|_| "unknown".to_string());
             info!(
-                "Proxy result : {} bytes read on {server} and {} bytes written on {client}. {} bytes read on {client} and {} bytes written on {server}",
+                "Proxy result of session {} : {} bytes read on {server} and {} bytes written on {client}. {} bytes read on {client} and {} bytes written on {server}",
+                session_id,
                 jet_stream_1.nb_bytes_read(),
                 jet_sink_1.nb_bytes_written(),
                 jet_stream_2.nb_bytes_read(),
@@ -241,9 +498,47 @@ impl Proxy {
                 client = client_addr
             );
             ok(())
-        }).then(|result|{
+        }).then(move |result|{
             SESSION_IN_PROGRESS_COUNT.fetch_sub(1, Ordering::Relaxed);
+            for interceptor in &mut interceptors {
+                interceptor.on_session_end(result.is_err());
+            }
             result
         }) )
     }
 }
+
+/// Capture of a session: creates the pcap file, or adds the session to the shared one, and writes the opening handshake
+fn pcap_interceptor(
+    pcap_output: &PcapOutput,
+    protocol: &Protocol,
+    metadata: &SessionMetadata,
+    server_addr: SocketAddr,
+    client_addr: SocketAddr,
+) -> Option<PcapInterceptor> {
+    let session = match pcap_output.open(metadata, server_addr, client_addr) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Session {} can't be captured: {}", metadata.session_id, e);
+            return None;
+        }
+    };
+    let mut interceptor = PcapInterceptor::new(server_addr, session);
+
+    match protocol {
+        Protocol::WAYK => {
+            info!("WaykMessageReader will be used to interpret application protocol.");
+            interceptor.set_message_reader(WaykMessageReader::get_messages);
+        }
+        Protocol::RDP => {
+            info!("RdpMessageReader will be used to interpret application protocol");
+            interceptor.set_message_reader(RdpMessageReader::get_messages);
+        }
+        Protocol::UNKNOWN => {
+            warn!("Protocol is unknown. Data received will not be split to get application message.");
+            interceptor.set_message_reader(UnknownMessageReader::get_messages);
+        }
+    }
+
+    Some(interceptor)
+}
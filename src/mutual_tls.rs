@@ -0,0 +1,208 @@
+use std::io;
+use std::sync::Arc;
+
+use futures::Future;
+use log::{error, info};
+use tokio_tcp::TcpStream;
+
+use crate::relay_tls::RelayTlsAcceptor;
+use crate::transport::tcp::TcpTransport;
+use crate::transport::JetFuture;
+
+/// Client certificates required by the TLS listeners.
+#[derive(Clone)]
+pub struct ClientAuthentication {
+    /// PEM file with the CA certificates issuing the client certificates
+    pub ca_file: String,
+    /// Subjects allowed to open a session, compared to the common name or to the whole subject. Any subject is
+    /// allowed when empty.
+    pub allowed_subjects: Vec<String>,
+}
+
+/// Acceptor of the TLS listeners, requiring a client certificate when client authentication is configured.
+#[derive(Clone)]
+pub enum JetTlsAcceptor {
+    Server(RelayTlsAcceptor),
+    Mutual(Arc<MutualTlsAcceptor>),
+}
+
+impl JetTlsAcceptor {
+    pub fn from_pkcs12(
+        der: &[u8],
+        password: &str,
+        client_authentication: Option<&ClientAuthentication>,
+    ) -> io::Result<Self> {
+        match client_authentication {
+            Some(client_authentication) => Ok(JetTlsAcceptor::Mutual(Arc::new(MutualTlsAcceptor::new(
+                der,
+                password,
+                client_authentication,
+            )?))),
+            None => Ok(JetTlsAcceptor::Server(RelayTlsAcceptor::from_pkcs12(der, password)?)),
+        }
+    }
+
+    pub fn accept(&self, conn: TcpStream) -> JetFuture<TcpTransport> {
+        match self {
+            JetTlsAcceptor::Server(acceptor) => Box::new(acceptor.accept(conn).map(TcpTransport::new_tls)),
+            JetTlsAcceptor::Mutual(acceptor) => acceptor.accept(conn),
+        }
+    }
+}
+
+/// Sessions resumed by the clients are only accepted by the listeners of devolutions-jet
+#[cfg(target_os = "linux")]
+const SESSION_ID_CONTEXT: &[u8] = b"devolutions-jet";
+
+#[cfg(target_os = "linux")]
+pub type MutualTlsStream = tokio_openssl::SslStream<TcpStream>;
+
+#[cfg(target_os = "linux")]
+pub struct MutualTlsAcceptor {
+    acceptor: openssl::ssl::SslAcceptor,
+    allowed_subjects: Vec<String>,
+}
+
+#[cfg(target_os = "linux")]
+impl MutualTlsAcceptor {
+    fn new(der: &[u8], password: &str, client_authentication: &ClientAuthentication) -> io::Result<Self> {
+        use openssl::ssl::SslVerifyMode;
+        use openssl::x509::X509Name;
+
+        let mut builder = crate::relay_tls::acceptor_builder(der, password)?;
+        builder.set_ca_file(&client_authentication.ca_file)?;
+        // The names of the CAs are sent to the clients to help them choose their certificate
+        builder.set_client_ca_list(X509Name::load_client_ca_file(&client_authentication.ca_file)?);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+
+        Ok(MutualTlsAcceptor {
+            acceptor: builder.build(),
+            allowed_subjects: client_authentication.allowed_subjects.clone(),
+        })
+    }
+
+    fn accept(&self, conn: TcpStream) -> JetFuture<TcpTransport> {
+        use tokio_openssl::SslAcceptorExt;
+
+        let peer_addr = conn
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let allowed_subjects = self.allowed_subjects.clone();
+        Box::new(
+            self.acceptor
+                .accept_async(conn)
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))
+                .and_then(move |stream| {
+                    let entries = stream
+                        .get_ref()
+                        .ssl()
+                        .peer_certificate()
+                        .map(|certificate| subject_entries(&certificate))
+                        .unwrap_or_default();
+                    let subject = format_subject(&entries);
+                    if is_authorized(&entries, &allowed_subjects) {
+                        info!("Client certificate of {} accepted: {}", peer_addr, subject);
+                        Ok(TcpTransport::new_mutual_tls(stream))
+                    } else {
+                        error!("Client certificate of {} not authorized: {}", peer_addr, subject);
+                        Err(io::Error::new(
+                            io::ErrorKind::PermissionDenied,
+                            format!("Client certificate not authorized: {}", subject),
+                        ))
+                    }
+                }),
+        )
+    }
+}
+
+/// Subject of the certificate presented by the peer of a mutual TLS stream
+#[cfg(target_os = "linux")]
+pub fn peer_certificate_subject(stream: &MutualTlsStream) -> Option<String> {
+    let certificate = stream.get_ref().ssl().peer_certificate()?;
+    Some(format_subject(&subject_entries(&certificate)))
+}
+
+#[cfg(target_os = "linux")]
+fn subject_entries(certificate: &openssl::x509::X509Ref) -> Vec<(String, String)> {
+    certificate
+        .subject_name()
+        .entries()
+        .filter_map(|entry| {
+            let name = entry.object().nid().short_name().ok()?;
+            let value = entry.data().as_utf8().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Client certificates rely on OpenSSL, the TLS library of native-tls on Linux.
+#[cfg(target_os = "windows")]
+pub enum MutualTlsAcceptor {}
+
+#[cfg(target_os = "windows")]
+impl MutualTlsAcceptor {
+    fn new(_der: &[u8], _password: &str, _client_authentication: &ClientAuthentication) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Client certificates are only supported on Linux",
+        ))
+    }
+
+    fn accept(&self, _conn: TcpStream) -> JetFuture<TcpTransport> {
+        match *self {}
+    }
+}
+
+/// `CN=alice, O=Example` from the entries of a subject
+fn format_subject(entries: &[(String, String)]) -> String {
+    entries
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Whether a subject is one of the allowed subjects, written as a common name or as a whole subject.
+fn is_authorized(entries: &[(String, String)], allowed_subjects: &[String]) -> bool {
+    if allowed_subjects.is_empty() {
+        return true;
+    }
+
+    let subject = format_subject(entries);
+    let common_names = entries
+        .iter()
+        .filter(|(name, _)| name == "CN")
+        .map(|(_, value)| value)
+        .collect::<Vec<_>>();
+    allowed_subjects.iter().any(|allowed| {
+        allowed.eq_ignore_ascii_case(&subject) || common_names.iter().any(|name| allowed.eq_ignore_ascii_case(name))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<(String, String)> {
+        vec![
+            ("O".to_string(), "Example".to_string()),
+            ("CN".to_string(), "alice".to_string()),
+        ]
+    }
+
+    #[test]
+    fn formats_subjects() {
+        assert_eq!(format_subject(&entries()), "O=Example, CN=alice");
+    }
+
+    #[test]
+    fn authorizes_common_names_and_subjects() {
+        assert!(is_authorized(&entries(), &[]));
+        assert!(is_authorized(&entries(), &["ALICE".to_string()]));
+        assert!(is_authorized(&entries(), &["O=Example, CN=alice".to_string()]));
+        assert!(!is_authorized(&entries(), &["bob".to_string()]));
+        assert!(!is_authorized(&entries(), &["Example".to_string()]));
+    }
+}
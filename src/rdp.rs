@@ -1,19 +1,16 @@
 mod credssp_future;
-mod identities_proxy;
+pub mod identities_proxy;
 
 use std::{io, net::SocketAddr};
 
 use bytes::BytesMut;
 use futures::{Future, Stream};
-use native_tls::TlsConnector;
 use slog::{error, info, Drain};
 use tokio::{
     codec::{Decoder, Framed},
-    net::tcp::ConnectFuture,
     prelude::*,
 };
 use tokio_tcp::TcpStream;
-use tokio_tls::{TlsAcceptor, TlsStream};
 use url::Url;
 
 use self::{
@@ -22,7 +19,12 @@ use self::{
 };
 use crate::{
     config::Config,
-    transport::{tcp::TcpTransport, tsrequest::TsRequestTransport, x224::X224Transport},
+    forward_proxy::Destination,
+    outbound_proxy,
+    relay_tls::{RelayTlsAcceptor, RelayTlsStream},
+    source_filter,
+    tls_verification::TlsVerification,
+    transport::{tcp::TcpTransport, tsrequest::TsRequestTransport, x224::X224Transport, JetFuture},
     utils::get_tls_peer_pubkey,
     Proxy,
 };
@@ -34,7 +36,7 @@ pub struct RdpClient {
     routing_url: Url,
     config: Config,
     tls_public_key: Vec<u8>,
-    tls_acceptor: TlsAcceptor,
+    tls_acceptor: RelayTlsAcceptor,
 }
 
 const LOGGER_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
@@ -53,7 +55,7 @@ fn create_client_logger(client_addr: String) -> slog::Logger {
 }
 
 impl RdpClient {
-    pub fn new(routing_url: Url, config: Config, tls_public_key: Vec<u8>, tls_acceptor: TlsAcceptor) -> Self {
+    pub fn new(routing_url: Url, config: Config, tls_public_key: Vec<u8>, tls_acceptor: RelayTlsAcceptor) -> Self {
         Self {
             routing_url,
             config,
@@ -63,6 +65,7 @@ impl RdpClient {
     }
 
     pub fn serve(self, client: TcpStream) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let client_peer_addr = client.peer_addr().ok();
         let client_addr = client
             .peer_addr()
             .map(|addr| addr.to_string())
@@ -77,6 +80,8 @@ impl RdpClient {
             .identities_filename()
             .expect("identities file is not present");
         let config_clone = self.config.clone();
+        let client_certificate = self.config.upstream_tls_verification().client_certificate().cloned();
+        let default_proxy = self.config.upstream_proxy().cloned();
 
         let client_future = negotiate_with_client(client, client_logger.clone())
             .map_err(move |e| {
@@ -125,18 +130,32 @@ impl RdpClient {
         let future = client_future
             .and_then(
                 move |(client_tls, rdp_identity, client_logger, request_protocol, request_flags)| {
+                    let scope = format!("identity {}", rdp_identity.proxy.username);
+                    source_filter::check_client(client_peer_addr, &rdp_identity.source_rules()?, &scope)?;
+                    let proxy = rdp_identity.outbound_proxy(default_proxy.as_ref())?;
+                    let session_proxy = Proxy::new(config_clone)
+                        .with_route(rdp_identity.destination.clone())
+                        .with_identity(rdp_identity.proxy.username.clone());
                     let target_identity = rdp_identity.target;
+                    let tls_server_name = rdp_identity.tls_server_name;
                     let destination = rdp_identity.destination;
+                    let tls_verification = match &rdp_identity.tls_verification {
+                        Some(tls_verification) => Some(TlsVerification::from_config(tls_verification)?),
+                        None => None,
+                    };
                     let client_logger_clone = client_logger.clone();
 
-                    let server_addr = destination.parse().map_err(move |e| {
+                    let server_addr: SocketAddr = destination.parse().map_err(move |e| {
                         error!(
                             client_logger_clone,
                             "invalid target destination ({}): {}", destination, e
                         );
                         io::Error::new(io::ErrorKind::Other, e)
                     })?;
-                    let server = TcpStream::connect(&server_addr);
+                    // The certificate of the server is verified against its IP address unless a name is given
+                    let server_name = tls_server_name.unwrap_or_else(|| server_addr.ip().to_string());
+                    // The X.224 negotiation and TLS happen inside the tunnel of the proxy
+                    let server = outbound_proxy::connect(Destination::from(server_addr), proxy.as_ref());
                     let client_logger_clone = client_logger.clone();
 
                     let negotiate_with_server_fut =
@@ -154,7 +173,9 @@ impl RdpClient {
                                                 protocol,
                                                 nego_flags,
                                                 target_identity,
-                                                server_addr,
+                                                server_name,
+                                                tls_verification,
+                                                session_proxy,
                                                 client_logger,
                                             ))
                                         } else {
@@ -175,10 +196,20 @@ impl RdpClient {
             )
             .and_then(|nego_fut| nego_fut)
             .and_then(
-                move |(server, client_tls, protocol, nego_flags, target_identity, server_addr, client_logger)| {
+                move |(
+                    server,
+                    client_tls,
+                    protocol,
+                    nego_flags,
+                    target_identity,
+                    server_name,
+                    tls_verification,
+                    session_proxy,
+                    client_logger,
+                )| {
                     let client_logger_clone = client_logger.clone();
                     let create_proxy = move |server_transport| {
-                        Proxy::new(config_clone)
+                        session_proxy
                             .build(server_transport, TcpTransport::new_tls(client_tls))
                             .map_err(move |e| {
                                 error!(client_logger_clone, "proxy error: {}", e);
@@ -190,48 +221,59 @@ impl RdpClient {
                         rdp_proto::SecurityProtocol::HYBRID
                         | rdp_proto::SecurityProtocol::HYBRID_EX
                         | rdp_proto::SecurityProtocol::SSL => {
-                            let accept_invalid_certs_and_hostnames = match protocol {
-                                rdp_proto::SecurityProtocol::HYBRID | rdp_proto::SecurityProtocol::HYBRID_EX => true,
-                                _ => false,
-                            };
+                            // Without settings in the identity, only the certificates of SSL servers are verified.
+                            // CredSSP authenticates the public key of HYBRID servers.
+                            let tls_verification = tls_verification.unwrap_or_else(|| {
+                                match protocol {
+                                    rdp_proto::SecurityProtocol::HYBRID | rdp_proto::SecurityProtocol::HYBRID_EX => {
+                                        TlsVerification::insecure()
+                                    }
+                                    _ => TlsVerification::default(),
+                                }
+                                .with_client_certificate(client_certificate)
+                            });
                             let client_logger_clone = client_logger.clone();
 
                             Ok(future::Either::A(
-                                establish_tls_connection_with_server(
-                                    server,
-                                    server_addr,
-                                    accept_invalid_certs_and_hostnames,
-                                )
-                                .map_err(move |e| {
-                                    error!(client_logger_clone, "failed to accept a tls connection: {}", e);
-                                    e
-                                })
-                                .and_then(move |server_tls| {
-                                    info!(client_logger, "TLS connection has been established with server");
-                                    let server_fut = match protocol {
-                                        rdp_proto::SecurityProtocol::HYBRID
-                                        | rdp_proto::SecurityProtocol::HYBRID_EX => {
-                                            let client_logger_clone = client_logger.clone();
-                                            future::Either::A(
-                                                process_cred_ssp_with_server(server_tls, target_identity, nego_flags)
-                                                    .map_err(move |e| {
-                                                        error!(client_logger_clone, "CredSSP failed: {}", e);
-                                                        e
-                                                    })
-                                                    .and_then(move |server_transport| {
-                                                        info!(client_logger, "CredSSP has been finished with server");
-                                                        let server_tls = server_transport.into_inner();
-
-                                                        Ok(server_tls)
-                                                    }),
-                                            )
-                                        }
-                                        _ => future::Either::B(future::ok(server_tls)),
-                                    };
-
-                                    server_fut
-                                        .and_then(move |server_tls| create_proxy(TcpTransport::new_tls(server_tls)))
-                                }),
+                                establish_tls_connection_with_server(server, server_name, tls_verification)
+                                    .map_err(move |e| {
+                                        error!(client_logger_clone, "failed to accept a tls connection: {}", e);
+                                        e
+                                    })
+                                    .and_then(move |server_tls| {
+                                        info!(client_logger, "TLS connection has been established with server");
+                                        let server_fut =
+                                            match protocol {
+                                                rdp_proto::SecurityProtocol::HYBRID
+                                                | rdp_proto::SecurityProtocol::HYBRID_EX => {
+                                                    let client_logger_clone = client_logger.clone();
+                                                    future::Either::A(
+                                                        process_cred_ssp_with_server(
+                                                            server_tls,
+                                                            target_identity,
+                                                            nego_flags,
+                                                        )
+                                                        .map_err(move |e| {
+                                                            error!(client_logger_clone, "CredSSP failed: {}", e);
+                                                            e
+                                                        })
+                                                        .and_then(move |server_transport| {
+                                                            info!(
+                                                                client_logger,
+                                                                "CredSSP has been finished with server"
+                                                            );
+                                                            let server_tls = server_transport.into_inner();
+
+                                                            Ok(server_tls)
+                                                        }),
+                                                    )
+                                                }
+                                                _ => future::Either::B(future::ok(server_tls)),
+                                            };
+
+                                        server_fut
+                                            .and_then(move |server_tls| create_proxy(TcpTransport::new_tls(server_tls)))
+                                    }),
                             ))
                         }
                         rdp_proto::SecurityProtocol::RDP => {
@@ -320,8 +362,8 @@ fn negotiate_with_client(
 
 fn establish_tls_connection_with_client(
     client: TcpStream,
-    tls_acceptor: TlsAcceptor,
-) -> impl Future<Item = TlsStream<TcpStream>, Error = io::Error> + Send {
+    tls_acceptor: RelayTlsAcceptor,
+) -> impl Future<Item = RelayTlsStream<TcpStream>, Error = io::Error> + Send {
     tls_acceptor.accept(client).map_err(move |e| {
         io::Error::new(
             io::ErrorKind::ConnectionRefused,
@@ -331,10 +373,11 @@ fn establish_tls_connection_with_client(
 }
 
 fn process_cred_ssp_with_client(
-    client: TlsStream<TcpStream>,
+    client: RelayTlsStream<TcpStream>,
     proxy_public_key: Vec<u8>,
     identities_proxy: IdentitiesProxy,
-) -> impl Future<Item = (Framed<TlsStream<TcpStream>, TsRequestTransport>, RdpIdentity), Error = io::Error> + Send {
+) -> impl Future<Item = (Framed<RelayTlsStream<TcpStream>, TsRequestTransport>, RdpIdentity), Error = io::Error> + Send
+{
     future::lazy(move || {
         let client_transport = TsRequestTransport::new().framed(client);
 
@@ -364,7 +407,7 @@ fn process_cred_ssp_with_client(
 }
 
 fn negotiate_with_server(
-    server: ConnectFuture,
+    server: JetFuture<TcpStream>,
     credentials: rdp_proto::Credentials,
     protocol: rdp_proto::SecurityProtocol,
     flags: rdp_proto::NegotiationRequestFlags,
@@ -443,30 +486,22 @@ fn process_negotiation_response_from_server(
 
 fn establish_tls_connection_with_server(
     server: TcpStream,
-    server_addr: SocketAddr,
-    accept_invalid_certs_and_hostnames: bool,
-) -> impl Future<Item = TlsStream<TcpStream>, Error = io::Error> + Send {
-    let tls_connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(accept_invalid_certs_and_hostnames)
-        .danger_accept_invalid_hostnames(accept_invalid_certs_and_hostnames)
-        .build()
-        .unwrap();
-    let tls_connector = tokio_tls::TlsConnector::from(tls_connector);
-    tls_connector
-        .connect(&server_addr.ip().to_string(), server)
-        .map_err(move |e| {
-            io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                format!("failed to handshake with a server: {}", e),
-            )
-        })
+    server_name: String,
+    tls_verification: TlsVerification,
+) -> impl Future<Item = RelayTlsStream<TcpStream>, Error = io::Error> + Send {
+    tls_verification.connect(&server_name, server).map_err(move |e| {
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("failed to handshake with a server: {}", e),
+        )
+    })
 }
 
 fn process_cred_ssp_with_server(
-    server_tls: TlsStream<TcpStream>,
+    server_tls: RelayTlsStream<TcpStream>,
     target_identity: rdp_proto::Credentials,
     nego_flags: rdp_proto::NegotiationRequestFlags,
-) -> impl Future<Item = Framed<TlsStream<TcpStream>, TsRequestTransport>, Error = io::Error> + Send {
+) -> impl Future<Item = Framed<RelayTlsStream<TcpStream>, TsRequestTransport>, Error = io::Error> + Send {
     future::lazy(move || {
         let client_public_key = get_tls_peer_pubkey(&server_tls)?;
         let server_transport = TsRequestTransport::new().framed(server_tls);
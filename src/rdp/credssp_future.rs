@@ -3,10 +3,10 @@ use std::io;
 use futures::{try_ready, Async, Future, Poll, Stream};
 use tokio::{codec::Framed, prelude::*};
 use tokio_tcp::TcpStream;
-use tokio_tls::TlsStream;
 
 use crate::{
     rdp::identities_proxy::{RdpIdentity, RdpIdentityGetter},
+    relay_tls::RelayTlsStream,
     transport::tsrequest::TsRequestTransport,
 };
 use rdp_proto::CredSsp;
@@ -14,16 +14,16 @@ use rdp_proto::CredSsp;
 pub struct CredSspClientFuture {
     cred_ssp_client: rdp_proto::CredSspClient,
     ts_request: Option<rdp_proto::TsRequest>,
-    stream: Option<Framed<tokio_tls::TlsStream<TcpStream>, TsRequestTransport>>,
-    send_future: Option<futures::sink::Send<Framed<tokio_tls::TlsStream<TcpStream>, TsRequestTransport>>>,
+    stream: Option<Framed<RelayTlsStream<TcpStream>, TsRequestTransport>>,
+    send_future: Option<futures::sink::Send<Framed<RelayTlsStream<TcpStream>, TsRequestTransport>>>,
     state: CredSspFutureState,
 }
 
 pub struct CredSspServerFuture<C: rdp_proto::CredentialsProxy + RdpIdentityGetter> {
     cred_ssp_server: rdp_proto::CredSspServer<C>,
     ts_request: Option<rdp_proto::TsRequest>,
-    stream: Option<Framed<tokio_tls::TlsStream<TcpStream>, TsRequestTransport>>,
-    send_future: Option<futures::sink::Send<Framed<tokio_tls::TlsStream<TcpStream>, TsRequestTransport>>>,
+    stream: Option<Framed<RelayTlsStream<TcpStream>, TsRequestTransport>>,
+    send_future: Option<futures::sink::Send<Framed<RelayTlsStream<TcpStream>, TsRequestTransport>>>,
     state: CredSspFutureState,
     client_credentials: Option<rdp_proto::Credentials>,
 }
@@ -40,7 +40,7 @@ enum CredSspFutureState {
 
 impl CredSspClientFuture {
     pub fn new(
-        stream: Framed<tokio_tls::TlsStream<TcpStream>, TsRequestTransport>,
+        stream: Framed<RelayTlsStream<TcpStream>, TsRequestTransport>,
         cred_ssp_context: rdp_proto::CredSspClient,
     ) -> Self {
         Self {
@@ -55,7 +55,7 @@ impl CredSspClientFuture {
 
 impl<C: rdp_proto::CredentialsProxy + RdpIdentityGetter> CredSspServerFuture<C> {
     pub fn new(
-        stream: Framed<TlsStream<TcpStream>, TsRequestTransport>,
+        stream: Framed<RelayTlsStream<TcpStream>, TsRequestTransport>,
         cred_ssp_server: rdp_proto::CredSspServer<C>,
     ) -> Self {
         Self {
@@ -70,7 +70,7 @@ impl<C: rdp_proto::CredentialsProxy + RdpIdentityGetter> CredSspServerFuture<C>
 }
 
 impl Future for CredSspClientFuture {
-    type Item = Framed<TlsStream<TcpStream>, TsRequestTransport>;
+    type Item = Framed<RelayTlsStream<TcpStream>, TsRequestTransport>;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -134,7 +134,7 @@ impl Future for CredSspClientFuture {
 
 impl<C: rdp_proto::CredentialsProxy + RdpIdentityGetter> Future for CredSspServerFuture<C> {
     type Item = (
-        Framed<TlsStream<TcpStream>, TsRequestTransport>,
+        Framed<RelayTlsStream<TcpStream>, TsRequestTransport>,
         RdpIdentity,
         rdp_proto::Credentials,
     );
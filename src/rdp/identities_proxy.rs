@@ -2,6 +2,10 @@ use std::{fs::File, io, io::prelude::*};
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::outbound_proxy::OutboundProxy;
+use crate::source_filter::SourceRules;
+use crate::tls_verification::TlsVerificationConfig;
+
 pub trait RdpIdentityGetter {
     fn get_rdp_identity(&self) -> RdpIdentity;
 }
@@ -11,6 +15,21 @@ pub struct RdpIdentity {
     pub proxy: rdp_proto::Credentials,
     pub target: rdp_proto::Credentials,
     pub destination: String,
+    /// Verification of the certificate of the destination
+    #[serde(default)]
+    pub tls_verification: Option<TlsVerificationConfig>,
+    /// Host name expected in the certificate of the destination, which is otherwise verified against its IP address
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+    /// Proxy through which the destination is reached. Format: socks5://... or http://...
+    #[serde(default)]
+    pub outbound_proxy: Option<String>,
+    /// Networks allowed to use the identity, in addition to the rules of the listener
+    #[serde(default)]
+    pub source_allow: Vec<String>,
+    /// Networks denied to use the identity
+    #[serde(default)]
+    pub source_deny: Vec<String>,
 }
 
 pub struct IdentitiesProxy {
@@ -19,7 +38,21 @@ pub struct IdentitiesProxy {
 }
 
 impl RdpIdentity {
-    fn from_file(filename: &str) -> io::Result<Vec<Self>> {
+    /// Proxy through which the destination is reached: the proxy of the identity, or `default_proxy` if not set.
+    pub fn outbound_proxy(&self, default_proxy: Option<&OutboundProxy>) -> io::Result<Option<OutboundProxy>> {
+        match &self.outbound_proxy {
+            Some(proxy) => OutboundProxy::parse(proxy)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Ok(default_proxy.cloned()),
+        }
+    }
+
+    pub fn source_rules(&self) -> io::Result<SourceRules> {
+        SourceRules::parse(&self.source_allow, &self.source_deny)
+    }
+
+    pub fn from_file(filename: &str) -> io::Result<Vec<Self>> {
         let mut f = File::open(filename)?;
         let mut contents = String::new();
         f.read_to_string(&mut contents)?;
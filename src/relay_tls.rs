@@ -0,0 +1,315 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures::{Future, Poll};
+use lazy_static::lazy_static;
+use log::error;
+use native_tls::Identity;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+lazy_static! {
+    static ref KEY_LOG: RwLock<Option<Arc<KeyLog>>> = RwLock::new(None);
+}
+
+/// NSS key log file receiving the secrets of the TLS sessions, in the format written by the browsers when
+/// SSLKEYLOGFILE is set. Like SSLKEYLOGFILE, it applies to the whole process.
+pub struct KeyLog {
+    filename: String,
+    file: Mutex<File>,
+}
+
+impl KeyLog {
+    /// Appends the secrets of every TLS session terminated or originated by the relay to `filename`. Only the TLS
+    /// sessions set up after this call are logged.
+    pub fn install(filename: &str) -> io::Result<()> {
+        if cfg!(not(target_os = "linux")) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "TLS key logging is only supported on Linux",
+            ));
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to open the key log file {}: {}", filename, e)))?;
+        *KEY_LOG.write().unwrap() = Some(Arc::new(KeyLog {
+            filename: filename.to_string(),
+            file: Mutex::new(file),
+        }));
+        Ok(())
+    }
+
+    fn current() -> Option<Arc<KeyLog>> {
+        KEY_LOG.read().unwrap().clone()
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!("Failed to write in the key log file {}: {}", self.filename, e);
+        }
+    }
+}
+
+/// Whether the TLS sessions are set up with OpenSSL to log their keys, native-tls doesn't expose them
+pub fn logs_keys() -> bool {
+    KeyLog::current().is_some()
+}
+
+/// Writes the secrets of the TLS sessions of an OpenSSL context in the key log file, if one is installed
+#[cfg(target_os = "linux")]
+pub fn set_key_log_callback(builder: &mut openssl::ssl::SslContextBuilder) {
+    if let Some(key_log) = KeyLog::current() {
+        builder.set_keylog_callback(move |_, line| key_log.write_line(line));
+    }
+}
+
+/// Sets the private key, certificate and chain of a PKCS#12 archive as the identity of an OpenSSL context
+#[cfg(target_os = "linux")]
+pub fn set_pkcs12_identity(
+    builder: &mut openssl::ssl::SslContextBuilder,
+    der: &[u8],
+    password: &str,
+) -> io::Result<()> {
+    let pkcs12 = openssl::pkcs12::Pkcs12::from_der(der)?.parse(password)?;
+    builder.set_private_key(&pkcs12.pkey)?;
+    builder.set_certificate(&pkcs12.cert)?;
+    for certificate in pkcs12.chain.into_iter().flatten() {
+        builder.add_extra_chain_cert(certificate)?;
+    }
+    Ok(())
+}
+
+/// OpenSSL acceptor presenting the identity of a PKCS#12 archive, with the same settings as the native-tls acceptor
+#[cfg(target_os = "linux")]
+pub fn acceptor_builder(der: &[u8], password: &str) -> io::Result<openssl::ssl::SslAcceptorBuilder> {
+    use openssl::ssl::{SslAcceptor, SslMethod};
+
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    set_pkcs12_identity(&mut builder, der, password)?;
+    set_key_log_callback(&mut builder);
+    Ok(builder)
+}
+
+#[cfg(target_os = "linux")]
+fn handshake_error<S>(e: openssl::ssl::HandshakeError<S>) -> io::Error {
+    use openssl::ssl::HandshakeError;
+
+    let message = match e {
+        HandshakeError::SetupFailure(e) => e.to_string(),
+        HandshakeError::Failure(stream) | HandshakeError::WouldBlock(stream) => stream.error().to_string(),
+    };
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// TLS stream terminated or originated by the relay. Streams are set up with OpenSSL when their keys are logged.
+pub enum RelayTlsStream<S> {
+    Native(tokio_tls::TlsStream<S>),
+    #[cfg(target_os = "linux")]
+    OpenSsl(tokio_openssl::SslStream<S>),
+}
+
+impl<S: Read + Write> RelayTlsStream<S> {
+    pub fn get_ref(&self) -> &S {
+        match self {
+            RelayTlsStream::Native(stream) => stream.get_ref().get_ref(),
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => stream.get_ref().get_ref(),
+        }
+    }
+
+    /// DER certificate presented by the peer
+    pub fn peer_certificate_der(&self) -> io::Result<Option<Vec<u8>>> {
+        let to_der_error = |e: String| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to convert the peer certificate to der: {}", e),
+            )
+        };
+
+        match self {
+            RelayTlsStream::Native(stream) => {
+                let certificate = stream.get_ref().peer_certificate().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Failed to get the peer certificate: {}", e),
+                    )
+                })?;
+                certificate
+                    .map(|certificate| certificate.to_der().map_err(|e| to_der_error(e.to_string())))
+                    .transpose()
+            }
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => stream
+                .get_ref()
+                .ssl()
+                .peer_certificate()
+                .map(|certificate| certificate.to_der().map_err(|e| to_der_error(e.to_string())))
+                .transpose(),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for RelayTlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            RelayTlsStream::Native(stream) => stream.read(buf),
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl<S: Read + Write> Write for RelayTlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            RelayTlsStream::Native(stream) => stream.write(buf),
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RelayTlsStream::Native(stream) => stream.flush(),
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => stream.flush(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for RelayTlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for RelayTlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            RelayTlsStream::Native(stream) => AsyncWrite::shutdown(stream),
+            #[cfg(target_os = "linux")]
+            RelayTlsStream::OpenSsl(stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
+
+/// Acceptor of the TLS connections of the clients, logging their keys when a key log file is installed.
+#[derive(Clone)]
+pub enum RelayTlsAcceptor {
+    Native(tokio_tls::TlsAcceptor),
+    #[cfg(target_os = "linux")]
+    OpenSsl(openssl::ssl::SslAcceptor),
+}
+
+impl RelayTlsAcceptor {
+    pub fn from_pkcs12(der: &[u8], password: &str) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        {
+            if logs_keys() {
+                return Ok(RelayTlsAcceptor::OpenSsl(acceptor_builder(der, password)?.build()));
+            }
+        }
+
+        let identity =
+            Identity::from_pkcs12(der, password).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let acceptor = native_tls::TlsAcceptor::builder(identity)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(RelayTlsAcceptor::Native(tokio_tls::TlsAcceptor::from(acceptor)))
+    }
+
+    pub fn accept<S>(&self, stream: S) -> Box<dyn Future<Item = RelayTlsStream<S>, Error = io::Error> + Send>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        match self {
+            RelayTlsAcceptor::Native(acceptor) => Box::new(
+                acceptor
+                    .accept(stream)
+                    .map(RelayTlsStream::Native)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            ),
+            #[cfg(target_os = "linux")]
+            RelayTlsAcceptor::OpenSsl(acceptor) => {
+                use tokio_openssl::SslAcceptorExt;
+
+                Box::new(
+                    acceptor
+                        .accept_async(stream)
+                        .map(RelayTlsStream::OpenSsl)
+                        .map_err(handshake_error),
+                )
+            }
+        }
+    }
+}
+
+/// Connector of the TLS connections to the servers, logging their keys when a key log file is installed.
+pub enum RelayTlsConnector {
+    Native(tokio_tls::TlsConnector),
+    #[cfg(target_os = "linux")]
+    OpenSsl {
+        connector: openssl::ssl::SslConnector,
+        verify_hostname: bool,
+    },
+}
+
+impl RelayTlsConnector {
+    pub fn connect<S>(
+        &self,
+        domain: &str,
+        stream: S,
+    ) -> Box<dyn Future<Item = RelayTlsStream<S>, Error = io::Error> + Send>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        match self {
+            RelayTlsConnector::Native(connector) => Box::new(
+                connector
+                    .connect(domain, stream)
+                    .map(RelayTlsStream::Native)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+            ),
+            #[cfg(target_os = "linux")]
+            RelayTlsConnector::OpenSsl {
+                connector,
+                verify_hostname,
+            } => {
+                use tokio_openssl::ConnectConfigurationExt;
+
+                match connector.configure() {
+                    Ok(configuration) => Box::new(
+                        configuration
+                            .verify_hostname(*verify_hostname)
+                            .connect_async(domain, stream)
+                            .map(RelayTlsStream::OpenSsl)
+                            .map_err(handshake_error),
+                    ),
+                    Err(e) => Box::new(futures::future::err(io::Error::from(e))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_log_lines_are_appended() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "CLIENT_RANDOM 00 11\n").unwrap();
+
+        let key_log = KeyLog {
+            filename: file.path().display().to_string(),
+            file: Mutex::new(OpenOptions::new().append(true).open(file.path()).unwrap()),
+        };
+        key_log.write_line("CLIENT_HANDSHAKE_TRAFFIC_SECRET 22 33");
+
+        assert_eq!(
+            std::fs::read_to_string(file.path()).unwrap(),
+            "CLIENT_RANDOM 00 11\nCLIENT_HANDSHAKE_TRAFFIC_SECRET 22 33\n"
+        );
+    }
+}
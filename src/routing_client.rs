@@ -2,36 +2,53 @@ use std::io;
 
 use futures::Future;
 use tokio::runtime::TaskExecutor;
-use url::Url;
 
 use crate::config::Config;
-use crate::transport::tcp::TcpTransport;
 use crate::transport::Transport;
+use crate::upstream::JetUpstreamTargets;
 use crate::Proxy;
 
 pub struct Client {
-    routing_url: Url,
+    targets: JetUpstreamTargets,
     config: Config,
+    identity: Option<String>,
     _executor_handle: TaskExecutor,
 }
 
 impl Client {
-    pub fn new(routing_url: Url, config: Config, executor_handle: TaskExecutor) -> Self {
+    pub fn new(targets: JetUpstreamTargets, config: Config, executor_handle: TaskExecutor) -> Self {
         Client {
-            routing_url,
+            targets,
             config,
+            identity: None,
             _executor_handle: executor_handle,
         }
     }
 
+    /// Subject of the certificate presented by the client, written in the capture of the session
+    pub fn with_identity(mut self, identity: Option<String>) -> Self {
+        self.identity = identity;
+        self
+    }
+
     pub fn serve<T: 'static + Transport + Send>(
         self,
         client_transport: T,
     ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
-        let server_conn = TcpTransport::connect(&self.routing_url);
+        let server_conn = self.targets.connect();
 
-        Box::new(server_conn.and_then(move |server_transport| {
-            Proxy::new(self.config.clone()).build(server_transport, client_transport)
+        Box::new(server_conn.and_then(move |(server_transport, target_session)| {
+            let mut proxy = Proxy::new(self.config.clone()).with_route(target_session.url().to_string());
+            if let Some(identity) = self.identity {
+                proxy = proxy.with_identity(identity);
+            }
+            proxy
+                .build(server_transport, client_transport)
+                // The session counts for the load balancing until the proxy ends
+                .then(move |result| {
+                    drop(target_session);
+                    result
+                })
         }))
     }
 }
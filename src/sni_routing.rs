@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::sync::Arc;
+
+use futures::{future, Future};
+use log::info;
+use serde_derive::Deserialize;
+use tokio::runtime::TaskExecutor;
+use tokio_tcp::TcpStream;
+use url::Url;
+
+use crate::config::Config;
+use crate::mutual_tls::{ClientAuthentication, JetTlsAcceptor};
+use crate::outbound_proxy::OutboundProxy;
+use crate::routing_client::Client;
+use crate::source_filter::{self, SourceRules};
+use crate::tls_verification::{TlsVerification, TlsVerificationConfig};
+use crate::transport::tcp::TcpTransport;
+use crate::upstream::{JetUpstreamTargets, UpstreamTargets};
+
+/// Entry of the TLS routes file.
+#[derive(Deserialize)]
+struct TlsRouteConfig {
+    /// Host name sent by the client (SNI). `*.example.com` matches the direct subdomains of example.com. The route
+    /// without server name is the default route.
+    server_name: Option<String>,
+    destination: String,
+    /// Forward the TLS stream unchanged instead of terminating TLS
+    #[serde(default)]
+    passthrough: bool,
+    /// PKCS#12 file with the certificate presented to the clients. The built-in certificate is used if not set.
+    certificate: Option<String>,
+    #[serde(default)]
+    certificate_password: String,
+    /// Verification of the certificate of a tls:// destination. The upstream_tls options are used if not set.
+    tls_verification: Option<TlsVerificationConfig>,
+    /// Proxy through which the destination is reached. Format: socks5://... or http://... The upstream_proxy option is
+    /// used if not set.
+    outbound_proxy: Option<String>,
+    /// Networks allowed to use the route, in addition to the rules of the listener
+    #[serde(default)]
+    source_allow: Vec<String>,
+    /// Networks denied to use the route
+    #[serde(default)]
+    source_deny: Vec<String>,
+}
+
+enum TlsRouteAction {
+    Passthrough,
+    Terminate(JetTlsAcceptor),
+}
+
+pub struct TlsRoute {
+    destination: JetUpstreamTargets,
+    action: TlsRouteAction,
+    source_rules: SourceRules,
+}
+
+impl TlsRoute {
+    /// Route terminating TLS with the given certificate and forwarding the data to the destination.
+    pub fn terminate(destination: JetUpstreamTargets, acceptor: JetTlsAcceptor) -> Self {
+        TlsRoute {
+            destination,
+            action: TlsRouteAction::Terminate(acceptor),
+            source_rules: SourceRules::default(),
+        }
+    }
+
+    /// Route forwarding the TLS stream unchanged to the destination.
+    pub fn passthrough(destination: JetUpstreamTargets) -> Self {
+        TlsRoute {
+            destination,
+            action: TlsRouteAction::Passthrough,
+            source_rules: SourceRules::default(),
+        }
+    }
+
+    /// Networks allowed and denied to use the route
+    pub fn with_source_rules(mut self, source_rules: SourceRules) -> Self {
+        self.source_rules = source_rules;
+        self
+    }
+
+    pub fn destination(&self) -> &UpstreamTargets {
+        &self.destination
+    }
+
+    pub fn serve(
+        &self,
+        conn: TcpStream,
+        config: Config,
+        executor_handle: TaskExecutor,
+    ) -> Box<dyn Future<Item = (), Error = io::Error> + Send> {
+        let scope = format!("TLS route to {}", self.destination);
+        if let Err(e) = source_filter::check_client(conn.peer_addr().ok(), &self.source_rules, &scope) {
+            return Box::new(future::err(e));
+        }
+
+        let client = Client::new(self.destination.clone(), config, executor_handle);
+        match &self.action {
+            TlsRouteAction::Passthrough => client.serve(TcpTransport::new(conn)),
+            TlsRouteAction::Terminate(acceptor) => Box::new(acceptor.accept(conn).and_then(move |transport| {
+                let client = client.with_identity(transport.peer_certificate_subject());
+                client.serve(transport)
+            })),
+        }
+    }
+}
+
+pub type JetSniRoutingTable = Arc<SniRoutingTable>;
+
+/// Routes of the TLS connections, chosen by the server name indication (SNI) of their ClientHello.
+pub struct SniRoutingTable {
+    routes: HashMap<String, Arc<TlsRoute>>,
+    wildcard_routes: Vec<(String, Arc<TlsRoute>)>,
+    default_route: Option<Arc<TlsRoute>>,
+}
+
+impl SniRoutingTable {
+    /// Loads the routes file. Routes terminating TLS without their own certificate use `default_acceptor`, and routes
+    /// without their own verification settings or proxy use `default_verification` and `default_proxy`. Routes with
+    /// their own certificate require a client certificate when `client_authentication` is set.
+    pub fn load(
+        path: &str,
+        default_acceptor: &JetTlsAcceptor,
+        default_verification: &TlsVerification,
+        default_proxy: Option<&OutboundProxy>,
+        client_authentication: Option<&ClientAuthentication>,
+    ) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let route_configs: Vec<TlsRouteConfig> =
+            serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut table = SniRoutingTable {
+            routes: HashMap::new(),
+            wildcard_routes: Vec::new(),
+            default_route: None,
+        };
+
+        for route_config in route_configs {
+            let destination = Url::parse(&route_config.destination).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid destination {}: {}", route_config.destination, e),
+                )
+            })?;
+            let verification = match &route_config.tls_verification {
+                Some(verification) => TlsVerification::from_config(verification)?,
+                None => default_verification.clone(),
+            };
+            let proxy = match &route_config.outbound_proxy {
+                Some(proxy) => {
+                    Some(OutboundProxy::parse(proxy).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+                }
+                None => default_proxy.cloned(),
+            };
+            let destination = Arc::new(UpstreamTargets::single(destination, verification).with_proxy(proxy));
+
+            let route = if route_config.passthrough {
+                if destination.targets()[0].url().scheme() != "tcp" {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Passthrough destination {} must use the tcp scheme", destination),
+                    ));
+                }
+                TlsRoute::passthrough(destination)
+            } else {
+                let acceptor = match &route_config.certificate {
+                    Some(certificate) => {
+                        load_acceptor(certificate, &route_config.certificate_password, client_authentication)?
+                    }
+                    None => default_acceptor.clone(),
+                };
+                TlsRoute::terminate(destination, acceptor)
+            };
+            let source_rules = SourceRules::parse(&route_config.source_allow, &route_config.source_deny)?;
+            let route = Arc::new(route.with_source_rules(source_rules));
+
+            match route_config.server_name {
+                Some(server_name) => {
+                    let server_name = server_name.to_lowercase();
+                    if server_name.starts_with("*.") {
+                        table.wildcard_routes.push((server_name[1..].to_string(), route));
+                    } else {
+                        table.routes.insert(server_name, route);
+                    }
+                }
+                None => table.default_route = Some(route),
+            }
+        }
+
+        info!(
+            "{} TLS routes loaded from {}",
+            table.routes.len() + table.wildcard_routes.len() + table.default_route.iter().count(),
+            path
+        );
+        Ok(table)
+    }
+
+    /// Route of a connection. An exact server name is preferred to a wildcard, and the default route is used when
+    /// nothing matches or the client sent no server name.
+    pub fn route(&self, server_name: Option<&str>) -> Option<Arc<TlsRoute>> {
+        let route = server_name.map(str::to_lowercase).and_then(|server_name| {
+            self.routes.get(&server_name).cloned().or_else(|| {
+                self.wildcard_routes
+                    .iter()
+                    .find(|(suffix, _)| matches_wildcard(suffix, &server_name))
+                    .map(|(_, route)| route.clone())
+            })
+        });
+        route.or_else(|| self.default_route.clone())
+    }
+}
+
+/// `suffix` is a wildcard without its `*`, like `.example.com`. It only matches one more label.
+pub fn matches_wildcard(suffix: &str, server_name: &str) -> bool {
+    server_name.len() > suffix.len()
+        && server_name.ends_with(suffix)
+        && !server_name[..server_name.len() - suffix.len()].contains('.')
+}
+
+fn load_acceptor(
+    certificate: &str,
+    password: &str,
+    client_authentication: Option<&ClientAuthentication>,
+) -> io::Result<JetTlsAcceptor> {
+    let der = fs::read(certificate)?;
+    JetTlsAcceptor::from_pkcs12(&der, password, client_authentication).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid certificate {}: {}", certificate, e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_matches_one_label() {
+        assert!(matches_wildcard(".example.com", "rdp.example.com"));
+        assert!(!matches_wildcard(".example.com", "example.com"));
+        assert!(!matches_wildcard(".example.com", "a.rdp.example.com"));
+        assert!(!matches_wildcard(".example.com", "rdpexample.com"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use futures::{try_ready, Async, Future};
+use tokio::timer::Delay;
+use tokio_tcp::TcpStream;
+
+const TPKT_VERSION: u8 = 0x03;
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_RECORD_HEADER_SIZE: usize = 5;
+const TLS_SERVER_NAME_EXTENSION: u16 = 0x0000;
+const TLS_HOST_NAME: u8 = 0x00;
+
+/// Large enough for a TLS record, where the server name of a ClientHello is found.
+const SNIFF_BUFFER_SIZE: usize = TLS_RECORD_HEADER_SIZE + 0x4000;
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+const SNIFF_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+pub enum SniffedProtocol {
+    Jet,
+    /// TPKT header of an X.224 connection request
+    Rdp,
+    /// TLS ClientHello, with the server name indication if the client sent one
+    Tls {
+        server_name: Option<String>,
+    },
+    Unknown,
+}
+
+/// Identifies the protocol from the first bytes sent by a client. Returns `None` if more bytes are needed.
+pub fn sniff(data: &[u8]) -> Option<SniffedProtocol> {
+    let jet_signature = jet_proto::JET_MSG_SIGNATURE.to_le_bytes();
+    if data.len() < jet_signature.len() && jet_signature.starts_with(data) {
+        return None;
+    }
+    if data.starts_with(&jet_signature) {
+        return Some(SniffedProtocol::Jet);
+    }
+
+    match data {
+        [TPKT_VERSION, 0x00, ..] => Some(SniffedProtocol::Rdp),
+        [TLS_HANDSHAKE_RECORD] | [TLS_HANDSHAKE_RECORD, 0x03, ..] => {
+            if data.len() <= TLS_RECORD_HEADER_SIZE {
+                return None;
+            }
+            if data[TLS_RECORD_HEADER_SIZE] != TLS_CLIENT_HELLO {
+                return Some(SniffedProtocol::Unknown);
+            }
+
+            // The server name can only be read once the whole record has been received
+            let record_len = (&data[3..5]).read_u16::<BigEndian>().ok()? as usize;
+            let record_end = TLS_RECORD_HEADER_SIZE + record_len;
+            if data.len() < record_end {
+                return None;
+            }
+            Some(SniffedProtocol::Tls {
+                server_name: parse_server_name(&data[TLS_RECORD_HEADER_SIZE..record_end]),
+            })
+        }
+        [] | [TPKT_VERSION] => None,
+        _ => Some(SniffedProtocol::Unknown),
+    }
+}
+
+/// Reads the host name of the server name extension of a ClientHello handshake message.
+fn parse_server_name(mut handshake: &[u8]) -> Option<String> {
+    fn skip(data: &mut &[u8], len: usize) -> Option<()> {
+        if data.len() < len {
+            return None;
+        }
+        *data = &data[len..];
+        Some(())
+    }
+
+    // Handshake type and length, client version and random
+    skip(&mut handshake, 4 + 2 + 32)?;
+    let session_id_len = handshake.read_u8().ok()? as usize;
+    skip(&mut handshake, session_id_len)?;
+    let cipher_suites_len = handshake.read_u16::<BigEndian>().ok()? as usize;
+    skip(&mut handshake, cipher_suites_len)?;
+    let compression_methods_len = handshake.read_u8().ok()? as usize;
+    skip(&mut handshake, compression_methods_len)?;
+
+    let extensions_len = handshake.read_u16::<BigEndian>().ok()? as usize;
+    let mut extensions = handshake.get(..extensions_len)?;
+    while !extensions.is_empty() {
+        let extension_type = extensions.read_u16::<BigEndian>().ok()?;
+        let extension_len = extensions.read_u16::<BigEndian>().ok()? as usize;
+        let mut extension = extensions.get(..extension_len)?;
+        skip(&mut extensions, extension_len)?;
+        if extension_type != TLS_SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let list_len = extension.read_u16::<BigEndian>().ok()? as usize;
+        let mut list = extension.get(..list_len)?;
+        while !list.is_empty() {
+            let name_type = list.read_u8().ok()?;
+            let name_len = list.read_u16::<BigEndian>().ok()? as usize;
+            let name = list.get(..name_len)?;
+            skip(&mut list, name_len)?;
+            if name_type == TLS_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// Peeks at the first bytes received on a connection until its protocol is known. The bytes are left in the socket for
+/// the handler of that protocol.
+pub struct Sniffer {
+    stream: Option<TcpStream>,
+    buffer: Vec<u8>,
+    peeked: usize,
+    retry: Option<Delay>,
+    timeout: Delay,
+}
+
+impl Sniffer {
+    pub fn new(stream: TcpStream) -> Self {
+        Sniffer {
+            stream: Some(stream),
+            buffer: vec![0; SNIFF_BUFFER_SIZE],
+            peeked: 0,
+            retry: None,
+            timeout: Delay::new(Instant::now() + SNIFF_TIMEOUT),
+        }
+    }
+}
+
+impl Future for Sniffer {
+    type Item = (TcpStream, SniffedProtocol, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<<Self as Future>::Item>, <Self as Future>::Error> {
+        loop {
+            if let Async::Ready(()) = self
+                .timeout
+                .poll()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Protocol not identified after {} bytes", self.peeked),
+                ));
+            }
+
+            // Peeking doesn't consume the data, so the socket stays readable until more data arrives
+            if let Some(retry) = self.retry.as_mut() {
+                try_ready!(retry.poll().map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+                self.retry = None;
+            }
+
+            let stream = self.stream.as_mut().expect("Sniffer polled after completion");
+            let len = try_ready!(stream.poll_peek(&mut self.buffer));
+            if len == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before the protocol was identified",
+                ));
+            }
+            self.peeked = len;
+
+            let data = &self.buffer[..len];
+            let protocol = match sniff(data) {
+                Some(protocol) => protocol,
+                // A TLS record bigger than the buffer can't be read completely
+                None if len == self.buffer.len() => SniffedProtocol::Tls { server_name: None },
+                None => {
+                    self.retry = Some(Delay::new(Instant::now() + SNIFF_RETRY_DELAY));
+                    continue;
+                }
+            };
+
+            let prefix = data[..len.min(16)].to_vec();
+            return Ok(Async::Ready((self.stream.take().unwrap(), protocol, prefix)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_HELLO: [u8; 64] = [
+        0x16, 0x03, 0x01, 0x00, 0x3B, // record header
+        0x01, 0x00, 0x00, 0x37, // ClientHello, length
+        0x03, 0x03, // version
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // random
+        0x00, // session id
+        0x00, 0x02, 0x13, 0x01, // cipher suites
+        0x01, 0x00, // compression methods
+        0x00, 0x0C, // extensions
+        0x00, 0x00, 0x00, 0x08, 0x00, 0x06, 0x00, 0x00, 0x03, b'j', b'e', b't', // server name
+    ];
+
+    #[test]
+    fn sniffs_jet_signature() {
+        let mut packet = Vec::new();
+        jet_proto::JetPacket::new(0, 0).write_to(&mut packet).unwrap();
+        assert_eq!(sniff(&packet[..2]), None);
+        assert_eq!(sniff(&packet), Some(SniffedProtocol::Jet));
+    }
+
+    #[test]
+    fn sniffs_tpkt_header() {
+        assert_eq!(sniff(&[0x03]), None);
+        assert_eq!(sniff(&[0x03, 0x00, 0x00, 0x13]), Some(SniffedProtocol::Rdp));
+    }
+
+    #[test]
+    fn sniffs_client_hello_server_name() {
+        assert_eq!(sniff(&CLIENT_HELLO[..20]), None);
+        assert_eq!(
+            sniff(&CLIENT_HELLO),
+            Some(SniffedProtocol::Tls {
+                server_name: Some("jet".to_string())
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n"), Some(SniffedProtocol::Unknown));
+        assert_eq!(
+            sniff(&[0x16, 0x03, 0x01, 0x00, 0x10, 0x02]),
+            Some(SniffedProtocol::Unknown)
+        );
+    }
+}
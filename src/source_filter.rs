@@ -0,0 +1,180 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+use log::warn;
+
+use crate::REJECTED_CONNECTION_COUNT;
+
+/// IPv4 or IPv6 network. Format: `<address>/<prefix length>`, or an address alone for a single host.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid network ({}). Format: <address>/<prefix length>", value);
+        let mut fields = value.splitn(2, '/');
+        let address = fields
+            .next()
+            .and_then(|address| address.parse::<IpAddr>().ok())
+            .ok_or_else(invalid)?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match fields.next() {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(invalid)?,
+            None => max_prefix_len,
+        };
+
+        // An IPv4-mapped network is stored as the IPv4 network
+        let (address, prefix_len) = match canonical(address) {
+            IpAddr::V4(v4) if address.is_ipv6() => (IpAddr::V4(v4), prefix_len.saturating_sub(96)),
+            address => (address, prefix_len),
+        };
+
+        Ok(IpNetwork { address, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = (!0u32).checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = (!0u128).checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// IPv4 clients of a dual-stack listener have IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xFFFF, _, _] => IpAddr::V4(v6.to_ipv4().expect("This should never happend")),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// Networks allowed and denied to reach a listener, a route or an identity.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceRules {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl SourceRules {
+    pub fn new(allow: Vec<IpNetwork>, deny: Vec<IpNetwork>) -> Self {
+        SourceRules { allow, deny }
+    }
+
+    /// Parses the `source_allow` and `source_deny` lists of a routes or identities file
+    pub fn parse(allow: &[String], deny: &[String]) -> io::Result<Self> {
+        let parse = |networks: &[String]| {
+            networks
+                .iter()
+                .map(|network| IpNetwork::parse(network))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        Ok(SourceRules::new(parse(allow)?, parse(deny)?))
+    }
+
+    /// A denied network wins over an allowed one. Every address is allowed when no network is allowed.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|network| network.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// Checks the address of a client against the rules of `scope`. Rejected clients are counted and logged. A client
+/// without known address is rejected by non-empty rules.
+pub fn check_client(addr: Option<SocketAddr>, rules: &SourceRules, scope: &str) -> io::Result<()> {
+    if rules.is_empty() || addr.map(|addr| rules.is_allowed(addr.ip())).unwrap_or(false) {
+        return Ok(());
+    }
+
+    REJECTED_CONNECTION_COUNT.fetch_add(1, Ordering::Relaxed);
+    let addr = addr
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    warn!(
+        "Connection from {} rejected by the source address rules of the {}",
+        addr, scope
+    );
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Source address {} not allowed by the {}", addr, scope),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(allow: &[&str], deny: &[&str]) -> SourceRules {
+        let networks = |values: &[&str]| values.iter().map(|value| IpNetwork::parse(value).unwrap()).collect();
+        SourceRules::new(networks(allow), networks(deny))
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert!(IpNetwork::parse("10.0.0.0/8").is_ok());
+        assert!(IpNetwork::parse("10.0.0.5").is_ok());
+        assert!(IpNetwork::parse("fd00::/8").is_ok());
+        assert!(IpNetwork::parse("10.0.0.0/33").is_err());
+        assert!(IpNetwork::parse("example.com/24").is_err());
+        assert!(IpNetwork::parse("10.0.0.0/").is_err());
+    }
+
+    #[test]
+    fn matches_networks() {
+        let network = IpNetwork::parse("192.168.1.0/24").unwrap();
+        assert!(network.contains(ip("192.168.1.200")));
+        assert!(!network.contains(ip("192.168.2.1")));
+        assert!(network.contains(ip("::ffff:192.168.1.7")));
+        assert!(!network.contains(ip("fd00::1")));
+
+        assert!(IpNetwork::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(IpNetwork::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(IpNetwork::parse("::ffff:10.0.0.0/104")
+            .unwrap()
+            .contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn denied_networks_win() {
+        let restricted = rules(&["10.0.0.0/8"], &["10.0.5.0/24"]);
+        assert!(restricted.is_allowed(ip("10.0.4.1")));
+        assert!(!restricted.is_allowed(ip("10.0.5.1")));
+        assert!(!restricted.is_allowed(ip("192.168.1.1")));
+
+        let open = rules(&[], &["203.0.113.0/24"]);
+        assert!(open.is_allowed(ip("192.168.1.1")));
+        assert!(!open.is_allowed(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn rejects_unknown_addresses_only_with_rules() {
+        assert!(check_client(None, &SourceRules::default(), "listener").is_ok());
+        assert!(check_client(None, &rules(&[], &["203.0.113.0/24"]), "listener").is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::fs;
+use std::io;
+
+use futures::{future, Future};
+use log::error;
+use native_tls::{Certificate, Identity, TlsConnector};
+use serde_derive::{Deserialize, Serialize};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::relay_tls::{RelayTlsConnector, RelayTlsStream};
+use crate::utils::{get_der_cert_from_stream, get_spki_der};
+
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Verification of the certificates presented by upstream TLS servers, as found in the configuration files.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TlsVerificationConfig {
+    /// PEM file with the CA certificates trusted in addition to the system roots
+    pub ca_file: Option<String>,
+    /// `cert-sha256:<hex>` or `spki-sha256:<hex>`. The server certificate must match one of the pins.
+    #[serde(default)]
+    pub pins: Vec<String>,
+    /// Accept any certificate and host name
+    #[serde(default)]
+    pub insecure: bool,
+    /// PKCS#12 file with the client certificate presented to servers requesting one
+    pub client_certificate: Option<String>,
+    #[serde(default)]
+    pub client_certificate_password: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CertificatePin {
+    /// SHA-256 of the DER certificate
+    Certificate(Vec<u8>),
+    /// SHA-256 of the DER SubjectPublicKeyInfo of the certificate
+    PublicKey(Vec<u8>),
+}
+
+impl CertificatePin {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut fields = value.splitn(2, ':');
+        let (kind, digest) = match (fields.next(), fields.next()) {
+            (Some(kind), Some(digest)) => (kind, digest),
+            _ => {
+                return Err(format!(
+                    "Invalid pin ({}). Format: cert-sha256:<hex> or spki-sha256:<hex>",
+                    value
+                ))
+            }
+        };
+
+        // Fingerprints are often written with colons between the bytes
+        let digest = digest.replace(':', "");
+        if digest.len() != 64 || !digest.is_ascii() {
+            return Err(format!("Invalid pin ({}): a SHA-256 digest has 64 hex digits", value));
+        }
+        let digest = (0..digest.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digest[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|e| format!("Invalid pin ({}): {}", value, e))?;
+
+        match kind.to_lowercase().as_str() {
+            "cert-sha256" => Ok(CertificatePin::Certificate(digest)),
+            "spki-sha256" => Ok(CertificatePin::PublicKey(digest)),
+            _ => Err(format!("Unsupported pin type: {}", kind)),
+        }
+    }
+}
+
+/// Client certificate presented to upstream servers. The PKCS#12 archive is kept for the OpenSSL connectors logging the
+/// TLS keys.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    identity: Identity,
+    pkcs12: Vec<u8>,
+    password: String,
+}
+
+/// How the certificate of an upstream TLS server is verified.
+#[derive(Clone, Default)]
+pub struct TlsVerification {
+    ca_file: Option<String>,
+    roots: Vec<Certificate>,
+    pins: Vec<CertificatePin>,
+    insecure: bool,
+    client_certificate: Option<ClientCertificate>,
+}
+
+impl TlsVerification {
+    /// Accepts any certificate and host name
+    pub fn insecure() -> Self {
+        TlsVerification {
+            insecure: true,
+            ..TlsVerification::default()
+        }
+    }
+
+    pub fn from_config(config: &TlsVerificationConfig) -> io::Result<Self> {
+        let roots = match &config.ca_file {
+            Some(ca_file) => load_certificates(ca_file)?,
+            None => Vec::new(),
+        };
+        let pins = config
+            .pins
+            .iter()
+            .map(|pin| CertificatePin::parse(pin))
+            .collect::<Result<_, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let client_certificate = match &config.client_certificate {
+            Some(client_certificate) => Some(load_identity(client_certificate, &config.client_certificate_password)?),
+            None => None,
+        };
+
+        Ok(TlsVerification {
+            ca_file: config.ca_file.clone(),
+            roots,
+            pins,
+            insecure: config.insecure,
+            client_certificate,
+        })
+    }
+
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_ref()
+    }
+
+    /// Client certificate presented to servers requesting one
+    pub fn with_client_certificate(mut self, client_certificate: Option<ClientCertificate>) -> Self {
+        self.client_certificate = client_certificate;
+        self
+    }
+
+    /// The certificate chain and host name are verified unless the verification is insecure or only relies on pins.
+    fn verifies_chain(&self) -> bool {
+        !self.insecure && (self.pins.is_empty() || self.ca_file.is_some())
+    }
+
+    fn describe(&self) -> String {
+        let mut checks = Vec::new();
+        if self.verifies_chain() {
+            match &self.ca_file {
+                Some(ca_file) => checks.push(format!("system roots and {}", ca_file)),
+                None => checks.push("system roots".to_string()),
+            }
+        }
+        if !self.pins.is_empty() {
+            checks.push(format!("{} certificate pins", self.pins.len()));
+        }
+        if checks.is_empty() {
+            "no verification".to_string()
+        } else {
+            checks.join(" and ")
+        }
+    }
+
+    pub fn connector(&self) -> io::Result<RelayTlsConnector> {
+        #[cfg(target_os = "linux")]
+        {
+            if crate::relay_tls::logs_keys() {
+                return self.openssl_connector();
+            }
+        }
+
+        let mut builder = TlsConnector::builder();
+        for root in &self.roots {
+            builder.add_root_certificate(root.clone());
+        }
+        if !self.verifies_chain() {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        if let Some(client_certificate) = &self.client_certificate {
+            builder.identity(client_certificate.identity.clone());
+        }
+        let connector = builder.build().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(RelayTlsConnector::Native(tokio_tls::TlsConnector::from(connector)))
+    }
+
+    /// Same verification as the native-tls connector, with OpenSSL to log the TLS keys
+    #[cfg(target_os = "linux")]
+    fn openssl_connector(&self) -> io::Result<RelayTlsConnector> {
+        use crate::relay_tls::{set_key_log_callback, set_pkcs12_identity};
+        use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+        use openssl::x509::X509;
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        for root in &self.roots {
+            let der = root
+                .to_der()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            builder.cert_store_mut().add_cert(X509::from_der(&der)?)?;
+        }
+        if !self.verifies_chain() {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+        if let Some(client_certificate) = &self.client_certificate {
+            set_pkcs12_identity(&mut builder, &client_certificate.pkcs12, &client_certificate.password)?;
+        }
+        set_key_log_callback(&mut builder);
+
+        Ok(RelayTlsConnector::OpenSsl {
+            connector: builder.build(),
+            verify_hostname: self.verifies_chain(),
+        })
+    }
+
+    /// TLS handshake with an upstream server, followed by the verification of the pins.
+    pub fn connect<S>(&self, domain: &str, stream: S) -> impl Future<Item = RelayTlsStream<S>, Error = io::Error> + Send
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let verification = self.clone();
+        let domain = domain.to_string();
+        future::result(self.connector()).and_then(move |connector| {
+            connector.connect(&domain, stream).then(move |result| match result {
+                Ok(tls_stream) => {
+                    verification.check_pins(&domain, &tls_stream)?;
+                    Ok(tls_stream)
+                }
+                Err(e) => {
+                    error!(
+                        "TLS handshake with {} failed (verification: {}): {}",
+                        domain,
+                        verification.describe(),
+                        e
+                    );
+                    Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        format!("TLS handshake with {} failed: {}", domain, e),
+                    ))
+                }
+            })
+        })
+    }
+
+    fn check_pins<S>(&self, domain: &str, tls_stream: &RelayTlsStream<S>) -> io::Result<()>
+    where
+        S: io::Read + io::Write,
+    {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        let der = get_der_cert_from_stream(tls_stream)?;
+        let certificate_digest = rdp_proto::compute_sha256(&der).to_vec();
+        let public_key_digest = rdp_proto::compute_sha256(&get_spki_der(&der)?).to_vec();
+        let matches = self.pins.iter().any(|pin| match pin {
+            CertificatePin::Certificate(digest) => *digest == certificate_digest,
+            CertificatePin::PublicKey(digest) => *digest == public_key_digest,
+        });
+
+        if matches {
+            Ok(())
+        } else {
+            error!(
+                "Certificate of {} matches no pin (cert-sha256:{}, spki-sha256:{})",
+                domain,
+                to_hex(&certificate_digest),
+                to_hex(&public_key_digest)
+            );
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Certificate of {} matches no pin", domain),
+            ))
+        }
+    }
+}
+
+/// Loads every certificate of a PEM bundle.
+fn load_certificates(filename: &str) -> io::Result<Vec<Certificate>> {
+    let pem = fs::read_to_string(filename)?;
+    let certificates = pem
+        .split_terminator(PEM_CERTIFICATE_END)
+        .filter(|block| !block.trim().is_empty())
+        .map(|block| {
+            Certificate::from_pem(format!("{}{}", block, PEM_CERTIFICATE_END).as_bytes()).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid certificate in {}: {}", filename, e),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificate found in {}", filename),
+        ));
+    }
+    Ok(certificates)
+}
+
+fn load_identity(filename: &str, password: &str) -> io::Result<ClientCertificate> {
+    let der = fs::read(filename)?;
+    let identity = Identity::from_pkcs12(&der, password).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid client certificate {}: {}", filename, e),
+        )
+    })?;
+    Ok(ClientCertificate {
+        identity,
+        pkcs12: der,
+        password: password.to_string(),
+    })
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn parses_pins() {
+        match CertificatePin::parse(&format!("spki-sha256:{}", DIGEST)).unwrap() {
+            CertificatePin::PublicKey(digest) => assert_eq!(to_hex(&digest), DIGEST),
+            pin => panic!("Unexpected pin: {:?}", pin),
+        }
+
+        // Fingerprint as printed by openssl x509 -fingerprint -sha256
+        let fingerprint = DIGEST
+            .as_bytes()
+            .chunks(2)
+            .map(|byte| std::str::from_utf8(byte).unwrap().to_uppercase())
+            .collect::<Vec<_>>()
+            .join(":");
+        match CertificatePin::parse(&format!("cert-sha256:{}", fingerprint)).unwrap() {
+            CertificatePin::Certificate(digest) => assert_eq!(to_hex(&digest), DIGEST),
+            pin => panic!("Unexpected pin: {:?}", pin),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_pins() {
+        assert!(CertificatePin::parse(DIGEST).is_err());
+        assert!(CertificatePin::parse("cert-sha256:abcd").is_err());
+        assert!(CertificatePin::parse(&format!("md5:{}", DIGEST)).is_err());
+    }
+}
@@ -32,6 +32,19 @@ impl JetTransport {
     pub fn new_tcp(stream: TcpStream) -> Self {
         JetTransport::Tcp(TcpTransport::new(stream))
     }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            JetTransport::Tcp(tcp_transport) => tcp_transport.peer_addr(),
+        }
+    }
+
+    /// Subject of the client certificate of the peer, on listeners requiring one
+    pub fn peer_certificate_subject(&self) -> Option<String> {
+        match self {
+            JetTransport::Tcp(tcp_transport) => tcp_transport.peer_certificate_subject(),
+        }
+    }
 }
 
 impl Clone for JetTransport {
@@ -43,12 +56,11 @@ impl Clone for JetTransport {
 }
 
 impl Transport for JetTransport {
-    fn connect(_url: &Url) -> JetFuture<Self>
+    fn connect(url: &Url) -> JetFuture<Self>
     where
         Self: Sized,
     {
-        // TODO
-        unimplemented!()
+        Box::new(TcpTransport::connect(url).map(JetTransport::Tcp))
     }
 
     fn message_sink(&self) -> JetSinkType<Vec<u8>> {
@@ -98,7 +110,8 @@ pub trait JetStream: Stream {
     fn shutdown(&self) -> std::io::Result<()>;
     fn peer_addr(&self) -> std::io::Result<SocketAddr>;
     fn nb_bytes_read(&self) -> u64;
-    fn set_packet_interceptor(&mut self, interceptor: Box<dyn PacketInterceptor>);
+    /// Interceptors are called in the order they were added
+    fn add_packet_interceptor(&mut self, interceptor: Box<dyn PacketInterceptor>);
 }
 
 pub trait JetSink: Sink {
@@ -1,36 +1,46 @@
-use futures::{Async, AsyncSink, Future, Sink, Stream};
+use futures::{future, Async, AsyncSink, Future, Sink, Stream};
 use log::{debug, error};
-use native_tls::TlsConnector;
 use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 use tokio::io;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_tcp::TcpStream;
-use tokio_tls::TlsStream;
 use url::Url;
 
+use crate::forward_proxy::Destination;
 use crate::interceptor::PacketInterceptor;
+#[cfg(target_os = "linux")]
+use crate::mutual_tls::{self, MutualTlsStream};
+use crate::outbound_proxy::{self, OutboundProxy};
+use crate::relay_tls::RelayTlsStream;
+use crate::tls_verification::TlsVerification;
 use crate::transport::{JetFuture, JetSink, JetSinkType, JetStream, JetStreamType, Transport};
-use crate::utils::url_to_socket_arr;
 
 pub enum TcpStreamWrapper {
     Plain(TcpStream),
-    Tls(TlsStream<TcpStream>),
+    Tls(RelayTlsStream<TcpStream>),
+    /// TLS stream of a listener requiring a client certificate
+    #[cfg(target_os = "linux")]
+    MutualTls(MutualTlsStream),
 }
 
 impl TcpStreamWrapper {
     fn peer_addr(&self) -> std::io::Result<SocketAddr> {
         match self {
             TcpStreamWrapper::Plain(stream) => stream.peer_addr(),
-            TcpStreamWrapper::Tls(stream) => stream.get_ref().get_ref().peer_addr(),
+            TcpStreamWrapper::Tls(stream) => stream.get_ref().peer_addr(),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(stream) => stream.get_ref().get_ref().peer_addr(),
         }
     }
 
     pub fn shutdown(&self) -> std::io::Result<()> {
         match self {
             TcpStreamWrapper::Plain(stream) => TcpStream::shutdown(stream, std::net::Shutdown::Both),
-            TcpStreamWrapper::Tls(stream) => stream.get_ref().get_ref().shutdown(std::net::Shutdown::Both),
+            TcpStreamWrapper::Tls(stream) => stream.get_ref().shutdown(std::net::Shutdown::Both),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(stream) => stream.get_ref().get_ref().shutdown(std::net::Shutdown::Both),
         }
     }
 
@@ -38,6 +48,8 @@ impl TcpStreamWrapper {
         match self {
             TcpStreamWrapper::Plain(stream) => AsyncWrite::shutdown(stream),
             TcpStreamWrapper::Tls(stream) => AsyncWrite::shutdown(stream),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(stream) => AsyncWrite::shutdown(stream),
         }
     }
 }
@@ -47,6 +59,8 @@ impl Read for TcpStreamWrapper {
         match *self {
             TcpStreamWrapper::Plain(ref mut stream) => stream.read(&mut buf),
             TcpStreamWrapper::Tls(ref mut stream) => stream.read(&mut buf),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(ref mut stream) => stream.read(&mut buf),
         }
     }
 }
@@ -56,12 +70,16 @@ impl Write for TcpStreamWrapper {
         match *self {
             TcpStreamWrapper::Plain(ref mut stream) => stream.write(&buf),
             TcpStreamWrapper::Tls(ref mut stream) => stream.write(&buf),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(ref mut stream) => stream.write(&buf),
         }
     }
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             TcpStreamWrapper::Plain(ref mut stream) => stream.flush(),
             TcpStreamWrapper::Tls(ref mut stream) => stream.flush(),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(ref mut stream) => stream.flush(),
         }
     }
 }
@@ -72,6 +90,8 @@ impl AsyncWrite for TcpStreamWrapper {
         match *self {
             TcpStreamWrapper::Plain(ref mut stream) => AsyncWrite::shutdown(stream),
             TcpStreamWrapper::Tls(ref mut stream) => AsyncWrite::shutdown(stream),
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(ref mut stream) => AsyncWrite::shutdown(stream),
         }
     }
 }
@@ -95,11 +115,64 @@ impl TcpTransport {
         }
     }
 
-    pub fn new_tls(stream: TlsStream<TcpStream>) -> Self {
+    pub fn new_tls(stream: RelayTlsStream<TcpStream>) -> Self {
         TcpTransport {
             stream: Arc::new(Mutex::new(TcpStreamWrapper::Tls(stream))),
         }
     }
+
+    #[cfg(target_os = "linux")]
+    pub fn new_mutual_tls(stream: MutualTlsStream) -> Self {
+        TcpTransport {
+            stream: Arc::new(Mutex::new(TcpStreamWrapper::MutualTls(stream))),
+        }
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.lock().ok()?.peer_addr().ok()
+    }
+
+    /// Subject of the client certificate, if the peer presented one to a listener requiring it
+    pub fn peer_certificate_subject(&self) -> Option<String> {
+        match &*self.stream.lock().ok()? {
+            #[cfg(target_os = "linux")]
+            TcpStreamWrapper::MutualTls(stream) => mutual_tls::peer_certificate_subject(stream),
+            _ => None,
+        }
+    }
+
+    /// Connects to a tcp:// or tls:// url. The certificate of a TLS server is checked with `verification`.
+    pub fn connect_with_verification(url: &Url, verification: &TlsVerification) -> JetFuture<Self> {
+        TcpTransport::connect_through(url, verification, None)
+    }
+
+    /// Connects to a tcp:// or tls:// url through `proxy` when set. The TLS handshake happens inside the tunnel.
+    pub fn connect_through(
+        url: &Url,
+        verification: &TlsVerification,
+        proxy: Option<&OutboundProxy>,
+    ) -> JetFuture<Self> {
+        let destination = match Destination::from_url(url) {
+            Ok(destination) => destination,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        match url.scheme() {
+            "tcp" => Box::new(outbound_proxy::connect(destination, proxy).map(TcpTransport::new)) as JetFuture<Self>,
+            "tls" => {
+                let socket = outbound_proxy::connect(destination, proxy);
+                let verification = verification.clone();
+                let url_clone = url.clone();
+                let tls_handshake =
+                    socket.and_then(move |socket| verification.connect(url_clone.host_str().unwrap_or(""), socket));
+                let request = tls_handshake.map(TcpTransport::new_tls);
+                Box::new(request) as JetFuture<Self>
+            }
+
+            scheme => {
+                panic!("Unsuported scheme: {}", scheme);
+            }
+        }
+    }
 }
 
 impl Read for TcpTransport {
@@ -149,38 +222,14 @@ impl Transport for TcpTransport {
     where
         Self: Sized,
     {
-        let socket_addr = url_to_socket_arr(&url);
-        match url.scheme() {
-            "tcp" => Box::new(TcpStream::connect(&socket_addr).map(TcpTransport::new)) as JetFuture<Self>,
-            "tls" => {
-                let socket = TcpStream::connect(&socket_addr);
-                let cx = TlsConnector::builder()
-                    .danger_accept_invalid_certs(true)
-                    .danger_accept_invalid_hostnames(true)
-                    .build()
-                    .unwrap();
-                let cx = tokio_tls::TlsConnector::from(cx);
-
-                let url_clone = url.clone();
-                let tls_handshake = socket.and_then(move |socket| {
-                    cx.connect(url_clone.host_str().unwrap_or(""), socket)
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-                });
-                let request = tls_handshake.map(TcpTransport::new_tls);
-                Box::new(request) as JetFuture<Self>
-            }
-
-            scheme => {
-                panic!("Unsuported scheme: {}", scheme);
-            }
-        }
+        TcpTransport::connect_with_verification(url, &TlsVerification::default())
     }
 }
 
 struct TcpJetStream {
     stream: Arc<Mutex<TcpStreamWrapper>>,
     nb_bytes_read: u64,
-    packet_interceptor: Option<Box<dyn PacketInterceptor>>,
+    packet_interceptors: Vec<Box<dyn PacketInterceptor>>,
 }
 
 impl TcpJetStream {
@@ -188,7 +237,7 @@ impl TcpJetStream {
         TcpJetStream {
             stream,
             nb_bytes_read: 0,
-            packet_interceptor: None,
+            packet_interceptors: Vec::new(),
         }
     }
 }
@@ -208,7 +257,7 @@ impl Stream for TcpJetStream {
                     self.nb_bytes_read += len as u64;
                     debug!("{} bytes read on {}", len, stream.peer_addr().unwrap());
 
-                    if let Some(interceptor) = self.packet_interceptor.as_mut() {
+                    for interceptor in &mut self.packet_interceptors {
                         interceptor.on_new_packet(stream.peer_addr().ok(), &v);
                     }
 
@@ -241,8 +290,8 @@ impl JetStream for TcpJetStream {
         self.nb_bytes_read
     }
 
-    fn set_packet_interceptor(&mut self, interceptor: Box<dyn PacketInterceptor>) {
-        self.packet_interceptor = Some(interceptor);
+    fn add_packet_interceptor(&mut self, interceptor: Box<dyn PacketInterceptor>) {
+        self.packet_interceptors.push(interceptor);
     }
 }
 
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{err, loop_fn, Either, Loop};
+use futures::Future;
+use log::{info, warn};
+use url::Url;
+
+use crate::config::{Config, LoadBalancing};
+use crate::outbound_proxy::OutboundProxy;
+use crate::tls_verification::TlsVerification;
+use crate::transport::tcp::TcpTransport;
+use crate::transport::JetFuture;
+
+pub type JetUpstreamTargets = Arc<UpstreamTargets>;
+
+pub struct UpstreamTarget {
+    url: Url,
+    sessions: AtomicUsize,
+    failed_until: Mutex<Option<Instant>>,
+}
+
+impl UpstreamTarget {
+    fn new(url: Url) -> Self {
+        UpstreamTarget {
+            url,
+            sessions: AtomicUsize::new(0),
+            failed_until: Mutex::new(None),
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Number of sessions currently relayed to the target
+    pub fn sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Whether the target is out of rotation after a failed connection
+    pub fn is_cooling_down(&self) -> bool {
+        let mut failed_until = self.failed_until.lock().unwrap();
+        match *failed_until {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                *failed_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn mark_failed(&self, cooldown: Duration) {
+        *self.failed_until.lock().unwrap() = Some(Instant::now() + cooldown);
+    }
+
+    /// Puts the target back in rotation
+    pub fn mark_healthy(&self) {
+        *self.failed_until.lock().unwrap() = None;
+    }
+}
+
+/// Counts a session relayed to a target until it is dropped.
+pub struct TargetSession {
+    target: Arc<UpstreamTarget>,
+}
+
+impl TargetSession {
+    fn new(target: Arc<UpstreamTarget>) -> Self {
+        target.sessions.fetch_add(1, Ordering::SeqCst);
+        TargetSession { target }
+    }
+
+    /// Url of the target relaying the session
+    pub fn url(&self) -> &Url {
+        self.target.url()
+    }
+}
+
+impl Drop for TargetSession {
+    fn drop(&mut self) {
+        self.target.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Upstream targets of the relayed sessions, with their load balancing and passive health tracking.
+pub struct UpstreamTargets {
+    targets: Vec<Arc<UpstreamTarget>>,
+    load_balancing: LoadBalancing,
+    failure_cooldown: Duration,
+    verification: TlsVerification,
+    proxy: Option<OutboundProxy>,
+    next: AtomicUsize,
+}
+
+impl UpstreamTargets {
+    pub fn new(urls: Vec<Url>, load_balancing: LoadBalancing, failure_cooldown: Duration) -> Self {
+        UpstreamTargets {
+            targets: urls.into_iter().map(|url| Arc::new(UpstreamTarget::new(url))).collect(),
+            load_balancing,
+            failure_cooldown,
+            verification: TlsVerification::default(),
+            proxy: None,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn from_config(urls: Vec<Url>, config: &Config) -> Self {
+        Self::new(
+            urls,
+            config.load_balancing(),
+            Duration::from_secs(u64::from(config.target_failure_cooldown())),
+        )
+        .with_verification(config.upstream_tls_verification().clone())
+        .with_proxy(config.upstream_proxy().cloned())
+    }
+
+    /// A single target, always tried even after a failure.
+    pub fn single(url: Url, verification: TlsVerification) -> Self {
+        Self::new(vec![url], LoadBalancing::FirstAvailable, Duration::from_secs(0)).with_verification(verification)
+    }
+
+    /// Verification of the certificates of the tls:// targets
+    pub fn with_verification(mut self, verification: TlsVerification) -> Self {
+        self.verification = verification;
+        self
+    }
+
+    /// Proxy through which the targets are reached. They are reached directly if not set.
+    pub fn with_proxy(mut self, proxy: Option<OutboundProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    pub fn proxy(&self) -> Option<&OutboundProxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn targets(&self) -> &[Arc<UpstreamTarget>] {
+        &self.targets
+    }
+
+    /// Takes a target out of rotation for the failure cool-down
+    pub fn mark_failed(&self, target: &UpstreamTarget) {
+        mark_failed(target, self.failure_cooldown);
+    }
+
+    /// Targets in the order they are tried for a new session. Targets in their cool-down are left out, unless every
+    /// target is in its cool-down: they are then all tried rather than failing the session without trying any.
+    fn candidates(&self) -> Vec<Arc<UpstreamTarget>> {
+        let available: Vec<_> = self
+            .targets
+            .iter()
+            .filter(|target| !target.is_cooling_down())
+            .cloned()
+            .collect();
+        let mut candidates = if available.is_empty() {
+            self.targets.clone()
+        } else {
+            available
+        };
+        match self.load_balancing {
+            LoadBalancing::RoundRobin if !candidates.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::SeqCst) % candidates.len();
+                candidates.rotate_left(start);
+            }
+            LoadBalancing::LeastSessions => candidates.sort_by_key(|target| target.sessions()),
+            _ => {}
+        }
+        candidates
+    }
+
+    /// Connects to the first target accepting the connection. The session is counted on that target until the returned
+    /// `TargetSession` is dropped.
+    pub fn connect(&self) -> JetFuture<(TcpTransport, TargetSession)> {
+        let failure_cooldown = self.failure_cooldown;
+        let verification = self.verification.clone();
+        let proxy = self.proxy.clone();
+        Box::new(loop_fn(
+            (self.candidates().into_iter(), None),
+            move |(mut candidates, last_error): (_, Option<io::Error>)| match candidates.next() {
+                Some(target) => Either::A(
+                    TcpTransport::connect_through(target.url(), &verification, proxy.as_ref()).then(move |result| {
+                        match result {
+                            Ok(transport) => {
+                                target.mark_healthy();
+                                let session = TargetSession::new(target);
+                                Ok(Loop::Break((transport, session)))
+                            }
+                            Err(e) => {
+                                warn!("Failed to connect to upstream target {}: {}", target.url(), e);
+                                mark_failed(&target, failure_cooldown);
+                                Ok(Loop::Continue((candidates, Some(e))))
+                            }
+                        }
+                    }),
+                ),
+                None => {
+                    Either::B(err(last_error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::Other, "No upstream target")
+                    })))
+                }
+            },
+        ))
+    }
+}
+
+fn mark_failed(target: &UpstreamTarget, cooldown: Duration) {
+    if !target.is_cooling_down() {
+        info!(
+            "Upstream target {} out of rotation for {} seconds",
+            target.url(),
+            cooldown.as_secs()
+        );
+    }
+    target.mark_failed(cooldown);
+}
+
+impl fmt::Display for UpstreamTargets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, target) in self.targets.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", target.url())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(load_balancing: LoadBalancing) -> UpstreamTargets {
+        let urls = ["tcp://10.0.0.1:4489", "tcp://10.0.0.2:4489", "tcp://10.0.0.3:4489"]
+            .iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+        UpstreamTargets::new(urls, load_balancing, Duration::from_secs(30))
+    }
+
+    fn hosts(candidates: &[Arc<UpstreamTarget>]) -> Vec<String> {
+        candidates
+            .iter()
+            .map(|target| target.url().host_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_takes_targets_in_turn() {
+        let targets = targets(LoadBalancing::RoundRobin);
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.2", "10.0.0.3", "10.0.0.1"]);
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.3", "10.0.0.1", "10.0.0.2"]);
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn least_sessions_prefers_idle_targets() {
+        let targets = targets(LoadBalancing::LeastSessions);
+        let _first = TargetSession::new(targets.targets()[0].clone());
+        let _second = TargetSession::new(targets.targets()[0].clone());
+        let _third = TargetSession::new(targets.targets()[1].clone());
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.3", "10.0.0.2", "10.0.0.1"]);
+    }
+
+    #[test]
+    fn sessions_are_counted_until_dropped() {
+        let targets = targets(LoadBalancing::LeastSessions);
+        let session = TargetSession::new(targets.targets()[0].clone());
+        assert_eq!(targets.targets()[0].sessions(), 1);
+        drop(session);
+        assert_eq!(targets.targets()[0].sessions(), 0);
+    }
+
+    #[test]
+    fn failed_targets_are_out_of_rotation() {
+        let targets = targets(LoadBalancing::FirstAvailable);
+        targets.targets()[0].mark_failed(Duration::from_secs(30));
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.2", "10.0.0.3"]);
+
+        targets.targets()[0].mark_failed(Duration::from_secs(0));
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn every_target_is_tried_when_all_failed() {
+        let targets = targets(LoadBalancing::FirstAvailable);
+        for target in targets.targets() {
+            target.mark_failed(Duration::from_secs(30));
+        }
+        assert_eq!(hosts(&targets.candidates()), ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+    }
+}
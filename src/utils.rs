@@ -1,16 +1,9 @@
-use std::{io, net::SocketAddr};
+use std::io;
 
-use url::Url;
+use crate::relay_tls::RelayTlsStream;
 
 const TLS_PUBLIC_KEY_HEADER: usize = 24;
 
-pub fn url_to_socket_arr(url: &Url) -> SocketAddr {
-    let host = url.host_str().unwrap().to_string();
-    let port = url.port().map(|port| port.to_string()).unwrap();
-
-    format!("{}:{}", host, port).parse::<SocketAddr>().unwrap()
-}
-
 macro_rules! io_try {
     ($e:expr) => {
         match $e {
@@ -51,7 +44,7 @@ pub fn get_tls_pubkey(der: &[u8], pass: &str) -> io::Result<Vec<u8>> {
 }
 
 #[cfg(target_os = "linux")]
-pub fn get_tls_peer_pubkey<S>(stream: &tokio_tls::TlsStream<S>) -> io::Result<Vec<u8>>
+pub fn get_tls_peer_pubkey<S>(stream: &RelayTlsStream<S>) -> io::Result<Vec<u8>>
 where
     S: io::Read + io::Write,
 {
@@ -62,7 +55,7 @@ where
 }
 
 #[cfg(target_os = "windows")]
-pub fn get_tls_peer_pubkey<S>(stream: &tokio_tls::TlsStream<S>) -> io::Result<Vec<u8>>
+pub fn get_tls_peer_pubkey<S>(stream: &RelayTlsStream<S>) -> io::Result<Vec<u8>>
 where
     S: io::Read + io::Write,
 {
@@ -72,27 +65,27 @@ where
     get_tls_pubkey_from_cert(cert)
 }
 
-fn get_der_cert_from_stream<S>(stream: &tokio_tls::TlsStream<S>) -> io::Result<Vec<u8>>
+pub fn get_der_cert_from_stream<S>(stream: &RelayTlsStream<S>) -> io::Result<Vec<u8>>
 where
     S: io::Read + io::Write,
 {
     stream
-        .get_ref()
-        .peer_certificate()
-        .map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to get the peer certificate: {}", e),
-            )
-        })?
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "A server must provide the certificate"))?
-        .to_der()
-        .map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to convert the peer certificate to der: {}", e),
-            )
-        })
+        .peer_certificate_der()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "A server must provide the certificate"))
+}
+
+/// DER SubjectPublicKeyInfo of a DER certificate
+#[cfg(target_os = "linux")]
+pub fn get_spki_der(der: &[u8]) -> io::Result<Vec<u8>> {
+    let cert = openssl::x509::X509::from_der(der)?;
+    Ok(cert.public_key()?.public_key_to_der()?)
+}
+
+/// DER SubjectPublicKeyInfo of a DER certificate
+#[cfg(target_os = "windows")]
+pub fn get_spki_der(der: &[u8]) -> io::Result<Vec<u8>> {
+    let cert = schannel::cert_context::CertContext::new(der)?;
+    cert.subject_public_key_info_der()
 }
 
 #[cfg(target_os = "linux")]
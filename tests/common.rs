@@ -1,6 +1,14 @@
+// Every test only uses some of the helpers
+#![allow(dead_code)]
+
+use jet_proto::JetPacket;
 use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::path::PathBuf;
 use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
 
 fn bin() -> PathBuf {
     let mut me = env::current_exe().unwrap();
@@ -22,11 +30,57 @@ impl Drop for KillOnDrop {
     }
 }
 
+/// Port of the http server of the instance listening on `listener_url`
+fn http_port(listener_url: &str) -> u16 {
+    let port: u16 = listener_url.rsplit(':').next().unwrap().parse().unwrap();
+    port + 2000
+}
+
 pub fn run_proxy(proxy_addr: &str, routing_url: Option<&str>, identities_file: Option<&str>) -> KillOnDrop {
+    run_proxy_with_args(proxy_addr, routing_url, identities_file, &[])
+}
+
+pub fn run_proxy_with_args(
+    proxy_addr: &str,
+    routing_url: Option<&str>,
+    identities_file: Option<&str>,
+    args: &[&str],
+) -> KillOnDrop {
+    run_proxy_with_listener(&format!("tcp://{}", proxy_addr), routing_url, identities_file, args)
+}
+
+pub fn run_proxy_with_listener(
+    listener_url: &str,
+    routing_url: Option<&str>,
+    identities_file: Option<&str>,
+    args: &[&str],
+) -> KillOnDrop {
+    KillOnDrop(
+        proxy_command(listener_url, routing_url, identities_file, args)
+            .spawn()
+            .unwrap(),
+    )
+}
+
+/// Runs a proxy named `instance` with the JET_INSTANCE environment variable
+pub fn run_proxy_instance(proxy_addr: &str, instance: &str, args: &[&str]) -> KillOnDrop {
+    KillOnDrop(
+        proxy_command(&format!("tcp://{}", proxy_addr), None, None, args)
+            .env("JET_INSTANCE", instance)
+            .spawn()
+            .unwrap(),
+    )
+}
+
+fn proxy_command(
+    listener_url: &str,
+    routing_url: Option<&str>,
+    identities_file: Option<&str>,
+    args: &[&str],
+) -> Command {
     let mut proxy_command = Command::new(bin());
 
-    let cmd_line_arg = format!("-urltcp://{}", proxy_addr);
-    proxy_command.arg(cmd_line_arg);
+    proxy_command.arg("--url").arg(listener_url);
 
     if routing_url.is_some() {
         proxy_command.arg("--routing_url").arg(routing_url.unwrap());
@@ -36,7 +90,36 @@ pub fn run_proxy(proxy_addr: &str, routing_url: Option<&str>, identities_file: O
         proxy_command.arg("--identities_file").arg(identities_file.unwrap());
     }
 
-    let proxy = proxy_command.spawn().unwrap();
+    proxy_command.args(args);
+
+    // Every instance gets its own http server port, so the tests running in parallel don't compete for the default one
+    if !args.contains(&"--http_port") {
+        proxy_command
+            .arg("--http_port")
+            .arg(http_port(listener_url).to_string());
+    }
+
+    proxy_command
+}
+
+/// Connects to an instance, waiting for it to listen
+pub fn connect_to_proxy(proxy_addr: &str) -> TcpStream {
+    loop {
+        match TcpStream::connect(proxy_addr) {
+            Ok(stream) => return stream,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Sends a JET request and returns the response
+pub fn send_jet_request(stream: &mut TcpStream, jet_packet: &JetPacket) -> JetPacket {
+    let mut v: Vec<u8> = Vec::new();
+    jet_packet.write_to(&mut v).unwrap();
+    stream.write_all(&v).unwrap();
+    stream.flush().unwrap();
 
-    KillOnDrop(proxy)
+    let mut buffer = [0u8; 1024];
+    let n = stream.read(&mut buffer).unwrap();
+    JetPacket::read_from(&mut &buffer[..n]).unwrap()
 }
@@ -0,0 +1,94 @@
+mod common;
+
+use bytes::BytesMut;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::run_proxy_with_args;
+
+const PROXY_ADDR: &str = "127.0.0.1:8114";
+const HTTP_SERVER_ADDR: &str = "127.0.0.1:10114";
+const RDP_TARGET_ADDR: &str = "127.0.0.1:8115";
+const UNRESOLVABLE_ROUTING_URL: &str = "tcp://unresolvable.invalid:3389";
+
+/// Answers every X.224 Connection Request with a negotiation response selecting HYBRID
+fn run_x224_responder(listener: TcpListener) {
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut request = [0u8; 1024];
+        if stream.read(&mut request).unwrap_or(0) == 0 {
+            continue;
+        }
+
+        let mut response_data = BytesMut::new();
+        response_data.resize(rdp_proto::NEGOTIATION_RESPONSE_LEN, 0);
+        rdp_proto::write_negotiation_response(
+            response_data.as_mut(),
+            rdp_proto::NegotiationResponseFlags::default(),
+            rdp_proto::SecurityProtocol::HYBRID,
+        )
+        .unwrap();
+        let mut response = BytesMut::new();
+        response.resize(rdp_proto::TPDU_REQUEST_LENGTH, 0);
+        rdp_proto::encode_x224(rdp_proto::X224TPDUType::ConnectionConfirm, response_data, &mut response).unwrap();
+        let _ = stream.write_all(response.as_ref());
+    }
+}
+
+fn get_targets() -> Option<String> {
+    let mut stream = TcpStream::connect(HTTP_SERVER_ADDR).ok()?;
+    let request = format!(
+        "GET /targets HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        HTTP_SERVER_ADDR
+    );
+    stream.write_all(request.as_bytes()).ok()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    Some(response)
+}
+
+#[test]
+fn targets_are_probed() {
+    let listener = TcpListener::bind(RDP_TARGET_ADDR).unwrap();
+    thread::spawn(move || run_x224_responder(listener));
+
+    let mut identities_file = tempfile::NamedTempFile::new().unwrap();
+    write!(
+        identities_file,
+        r#"[{{"proxy":{{"username":"proxy_user","password":"proxy_password","domain":null}},"target":{{"username":"target_user","password":"target_password","domain":null}},"destination":"{}"}}]"#,
+        RDP_TARGET_ADDR
+    )
+    .unwrap();
+
+    //Spawn our proxy and wait for the first health checks
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        Some(UNRESOLVABLE_ROUTING_URL),
+        Some(identities_file.path().to_str().unwrap()),
+        &["--health_check_interval", "1", "--health_check_timeout", "2"],
+    );
+
+    let rdp_target = format!(r#""target":"{}","probe":"rdp","reachable":true"#, RDP_TARGET_ADDR);
+    let routing_target = format!(
+        r#""target":"{}","probe":"tcp","reachable":false"#,
+        UNRESOLVABLE_ROUTING_URL
+    );
+    for _ in 0..200 {
+        if let Some(response) = get_targets() {
+            if response.contains(&rdp_target) && response.contains(&routing_target) {
+                assert!(
+                    response.starts_with("HTTP/1.1 200"),
+                    "Unexpected response: {}",
+                    response
+                );
+                assert!(response.contains(r#""security_protocol":"HYBRID""#));
+                return;
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("The targets were not probed: {:?}", get_targets());
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{connect_to_proxy, run_proxy_with_listener};
+
+const PROXY_ADDR: &str = "127.0.0.1:8095";
+const SERVER_ADDR: &str = "127.0.0.1:8096";
+const DENIED_ADDR: &str = "127.0.0.1:8097";
+const CAPTURE_PROXY_ADDR: &str = "127.0.0.1:8116";
+const CAPTURE_SERVER_ADDR: &str = "127.0.0.1:8117";
+/// alice:secret
+const CREDENTIALS: &str = "YWxpY2U6c2VjcmV0";
+const SERVER_DATA: &str = "Server Response";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Sends a CONNECT request and returns the head of the response
+fn request_connection(stream: &mut TcpStream, destination: &str, credentials: Option<&str>) -> String {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", destination, destination);
+    if let Some(credentials) = credentials {
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response_head(stream)
+}
+
+fn read_response_head(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    while !response.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[test]
+fn http_connect_listener() {
+    let server = TcpListener::bind(SERVER_ADDR).unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_listener(
+        &format!("http-connect://{}", PROXY_ADDR),
+        None,
+        None,
+        &[
+            "--proxy_listener_allow",
+            SERVER_ADDR,
+            "--proxy_listener_user",
+            "alice:secret",
+        ],
+    );
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = request_connection(&mut client, SERVER_ADDR, None);
+    assert!(response.starts_with("HTTP/1.1 407 "));
+    assert!(response.contains("Proxy-Authenticate: Basic"));
+
+    // Destinations that are not allowed are refused by the ruleset
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = request_connection(&mut client, DENIED_ADDR, Some(CREDENTIALS));
+    assert!(response.starts_with("HTTP/1.1 403 "));
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = request_connection(&mut client, SERVER_ADDR, Some(CREDENTIALS));
+    assert_eq!(response, "HTTP/1.1 200 Connection Established\r\n\r\n");
+    let (mut server_stream, _) = server.accept().unwrap();
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server_stream.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    server_stream.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; SERVER_DATA.len()];
+    client.read_exact(&mut data).unwrap();
+    assert_eq!(data, SERVER_DATA.as_bytes());
+}
+
+#[test]
+fn data_sent_with_the_request_is_relayed_and_captured() {
+    let server = TcpListener::bind(CAPTURE_SERVER_ADDR).unwrap();
+    let captures = tempfile::tempdir().expect("Failed to create a temporary directory");
+    let capture = captures.path().join("capture.pcap");
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_listener(
+        &format!("http-connect://{}", CAPTURE_PROXY_ADDR),
+        None,
+        None,
+        &[
+            "--proxy_listener_allow",
+            CAPTURE_SERVER_ADDR,
+            "--pcap_file",
+            capture.to_str().unwrap(),
+        ],
+    );
+
+    // The client doesn't wait for the response to send its first data, like a TLS client sending its ClientHello
+    let mut client = connect_to_proxy(CAPTURE_PROXY_ADDR);
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n{}",
+        CAPTURE_SERVER_ADDR, CAPTURE_SERVER_ADDR, CLIENT_DATA
+    );
+    client.write_all(request.as_bytes()).unwrap();
+    assert_eq!(
+        read_response_head(&mut client),
+        "HTTP/1.1 200 Connection Established\r\n\r\n"
+    );
+
+    let (mut server_stream, _) = server.accept().unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server_stream.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    // Captures are written by the interceptor thread, after the data is relayed
+    let is_captured = || {
+        fs::read(&capture)
+            .map(|content| {
+                content
+                    .windows(CLIENT_DATA.len())
+                    .any(|window| window == CLIENT_DATA.as_bytes())
+            })
+            .unwrap_or(false)
+    };
+    for _ in 0..500 {
+        if is_captured() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("The data sent with the request is not in the capture");
+}
@@ -0,0 +1,332 @@
+mod common;
+
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use lazy_static::lazy_static;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+use common::{connect_to_proxy, run_proxy, send_jet_request};
+
+const PROXY_ADDR: &str = "127.0.0.1:8075";
+const HTTP_SERVER_ADDR: &str = "127.0.0.1:10075";
+const SERVER_DATA: &str = "Server Response";
+const CLIENT_DATA: &str = "Client Request";
+
+lazy_static! {
+    // Only one proxy can run at a time since they all use the same listener and http server port
+    static ref PROXY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn http_request(method: &str, path: &str, body: &str) -> String {
+    loop {
+        match TcpStream::connect(HTTP_SERVER_ADDR) {
+            Ok(mut stream) => {
+                let request = format!(
+                    "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    method,
+                    path,
+                    HTTP_SERVER_ADDR,
+                    body.len(),
+                    body
+                );
+                stream.write_all(request.as_bytes()).unwrap();
+
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                return response;
+            }
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Request of a server or client for the association `uuid`
+fn send_association_request(stream: &mut TcpStream, method: JetMethod, version: u8, uuid: Uuid) -> JetPacket {
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(method));
+    jet_packet.set_version(Some(version));
+    jet_packet.set_association(Some(uuid));
+    send_jet_request(stream, &jet_packet)
+}
+
+/// Accept request of a server adding a connection to its pool
+fn send_pool_accept_request(stream: &mut TcpStream, uuid: Uuid, pool_token: Option<Uuid>, timeout: u32) -> JetPacket {
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(2));
+    jet_packet.set_association(Some(uuid));
+    jet_packet.set_pool_token(pool_token);
+    jet_packet.set_timeout(Some(timeout));
+    send_jet_request(stream, &jet_packet)
+}
+
+#[test]
+fn pre_provisioned_association() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    let uuid = Uuid::new_v4();
+    let response = http_request(
+        "POST",
+        "/associations",
+        &format!(r#"{{"id":"{}","timeout":60,"metadata":{{"owner":"broker"}}}}"#, uuid),
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 201"),
+        "Unexpected response: {}",
+        response
+    );
+    assert!(response.contains(r#""state":"waiting""#));
+
+    // Server (method = Accept) uses the association created by the broker
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let response = send_association_request(&mut server, JetMethod::ACCEPT, 0, uuid);
+    assert_eq!(response.association(), Some(uuid));
+
+    // Client (method = Connect)
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let mut buffer = [0u8; 1024];
+    let n = server.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..n], CLIENT_DATA.as_bytes());
+
+    server.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let n = client.read(&mut buffer).unwrap();
+    assert_eq!(&buffer[..n], SERVER_DATA.as_bytes());
+
+    let response = http_request("GET", &format!("/associations/{}", uuid), "");
+    assert!(
+        response.starts_with("HTTP/1.1 200"),
+        "Unexpected response: {}",
+        response
+    );
+    assert!(response.contains(r#""state":"connected""#));
+
+    // A second server can't accept on an association that has already been used
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_association(Some(uuid));
+    let mut v: Vec<u8> = Vec::new();
+    jet_packet.write_to(&mut v).unwrap();
+    server.write_all(&v).unwrap();
+    let n = server.read(&mut buffer).unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..n]).contains("400 Bad Request"));
+}
+
+#[test]
+fn connection_pool() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    // The first accept request creates the association, the next ones add connections to its pool
+    let mut first_server = connect_to_proxy(PROXY_ADDR);
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(2));
+    let response = send_jet_request(&mut first_server, &jet_packet);
+    let uuid = response.association().unwrap();
+
+    let mut second_server = connect_to_proxy(PROXY_ADDR);
+    let response = send_pool_accept_request(&mut second_server, uuid, response.pool_token(), 60);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+
+    // Each connect request consumes one server connection
+    for server in [&mut first_server, &mut second_server].iter_mut() {
+        let mut client = connect_to_proxy(PROXY_ADDR);
+        let response = send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+        assert_eq!(
+            response.response_status_code(),
+            Some(&ResponseStatusCode::StatusCode200)
+        );
+
+        client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The server is told how many connections are left in the pool before receiving the client data
+        let mut buffer = [0u8; 1024];
+        let n = server.read(&mut buffer).unwrap();
+        let mut received: &[u8] = &buffer[..n];
+        let notification = JetPacket::read_from(&mut received).unwrap();
+        assert_eq!(notification.method(), Some(&JetMethod::POOL_LOW));
+        assert!(notification.pool_remaining().unwrap() <= 1);
+        assert_eq!(received, CLIENT_DATA.as_bytes());
+    }
+
+    // The pool is empty
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode400)
+    );
+}
+
+#[test]
+fn connection_pool_belongs_to_its_server() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(2));
+    let response = send_jet_request(&mut server, &jet_packet);
+    let uuid = response.association().unwrap();
+    let pool_token = response.pool_token();
+    assert!(pool_token.is_some());
+
+    // Knowing the association isn't enough to park a connection in the pool
+    for wrong_token in [None, Some(Uuid::new_v4())].iter() {
+        let mut intruder = connect_to_proxy(PROXY_ADDR);
+        let response = send_pool_accept_request(&mut intruder, uuid, *wrong_token, 60);
+        assert_eq!(
+            response.response_status_code(),
+            Some(&ResponseStatusCode::StatusCode400)
+        );
+    }
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+
+    // The server can refill the pool of the used association, even after a parked connection expired
+    let mut expiring_server = connect_to_proxy(PROXY_ADDR);
+    let response = send_pool_accept_request(&mut expiring_server, uuid, pool_token, 1);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    let mut buffer = [0u8; 1024];
+    let n = expiring_server.read(&mut buffer).unwrap();
+    let response = JetPacket::read_from(&mut &buffer[..n]).unwrap();
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode408)
+    );
+
+    let mut pooled_server = connect_to_proxy(PROXY_ADDR);
+    let response = send_pool_accept_request(&mut pooled_server, uuid, pool_token, 60);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+}
+
+#[test]
+fn accept_timeout() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    // Server (method = Accept) requests a short timeout
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(0));
+    jet_packet.set_timeout(Some(1));
+    let response = send_jet_request(&mut server, &jet_packet);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    assert_eq!(response.timeout(), Some(1));
+
+    // No client connects, the server is notified before the connection is closed
+    let mut buffer = [0u8; 1024];
+    let n = server.read(&mut buffer).unwrap();
+    let response = JetPacket::read_from(&mut &buffer[..n]).unwrap();
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode408)
+    );
+    assert_eq!(server.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn accept_timeout_of_zero_is_rejected() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    // The association would expire as soon as it is accepted
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(0));
+    jet_packet.set_timeout(Some(0));
+    let mut v: Vec<u8> = Vec::new();
+    jet_packet.write_to(&mut v).unwrap();
+    server.write_all(&v).unwrap();
+
+    let mut buffer = [0u8; 1024];
+    let n = server.read(&mut buffer).unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..n]).contains("400 Bad Request"));
+}
+
+#[test]
+fn connected_notification() {
+    let _lock = PROXY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    // The association is created in advance, the server supports the Connected notification
+    let uuid = Uuid::new_v4();
+    let response = http_request("POST", "/associations", &format!(r#"{{"id":"{}"}}"#, uuid));
+    assert!(response.starts_with("HTTP/1.1 201"));
+
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    send_association_request(&mut server, JetMethod::ACCEPT, 3, uuid);
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let response = send_association_request(&mut client, JetMethod::CONNECT, 0, uuid);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // The server learns who connected before receiving the client data
+    let mut buffer = [0u8; 1024];
+    let n = server.read(&mut buffer).unwrap();
+    let mut received: &[u8] = &buffer[..n];
+    let notification = JetPacket::read_from(&mut received).unwrap();
+    assert_eq!(notification.method(), Some(&JetMethod::CONNECTED));
+    assert_eq!(notification.association(), Some(uuid));
+    assert_eq!(
+        notification.client_address(),
+        Some(client.local_addr().unwrap().to_string().as_str())
+    );
+
+    // The server didn't ask for a pool, so it isn't told that it is empty
+    assert_eq!(received, CLIENT_DATA.as_bytes());
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use net2::TcpBuilder;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use uuid::Uuid;
+
+use common::{connect_to_proxy, run_proxy_instance, send_jet_request};
+
+const FIRST_INSTANCE_ADDR: &str = "127.0.0.1:8112";
+const SECOND_INSTANCE_ADDR: &str = "127.0.0.1:8113";
+const SPOOFED_CLIENT_ADDR: &str = "10.1.2.3:4567";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Parks a server connection on the second instance and returns its association
+fn accept_on_second_instance(server: &mut TcpStream) -> Uuid {
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::ACCEPT));
+    jet_packet.set_version(Some(3));
+    let response = send_jet_request(server, &jet_packet);
+    assert_eq!(response.jet_instance(), Some("jet-b"));
+    response.association().unwrap()
+}
+
+fn connect_request(uuid: Uuid, client_address: Option<&str>) -> JetPacket {
+    let mut jet_packet = JetPacket::new(0, 0);
+    jet_packet.set_method(Some(JetMethod::CONNECT));
+    jet_packet.set_association(Some(uuid));
+    jet_packet.set_jet_instance(Some("jet-b".to_string()));
+    jet_packet.set_client_address(client_address.map(str::to_string));
+    jet_packet
+}
+
+/// Reads the Connected notification received by the server and returns the client address it carries
+fn read_client_address(server: &mut TcpStream) -> String {
+    let mut buffer = [0u8; 1024];
+    let n = server.read(&mut buffer).unwrap();
+    let notification = JetPacket::read_from(&mut &buffer[..n]).unwrap();
+    assert_eq!(notification.method(), Some(&JetMethod::CONNECTED));
+    notification.client_address().unwrap().to_string()
+}
+
+#[test]
+fn forwarded_headers_are_only_trusted_from_peers() {
+    //Spawn both instances and wait for them to come online
+    let _first_instance = run_proxy_instance(
+        FIRST_INSTANCE_ADDR,
+        "jet-a",
+        &["--peer", &format!("jet-b=tcp://{}", SECOND_INSTANCE_ADDR)],
+    );
+    let _second_instance = run_proxy_instance(
+        SECOND_INSTANCE_ADDR,
+        "jet-b",
+        &["--peer", &format!("jet-a=tcp://{}", FIRST_INSTANCE_ADDR)],
+    );
+
+    // The first instance forwards the connect request to the instance of the server, with the address of its client
+    let mut server = connect_to_proxy(SECOND_INSTANCE_ADDR);
+    let uuid = accept_on_second_instance(&mut server);
+
+    let mut client = connect_to_proxy(FIRST_INSTANCE_ADDR);
+    let response = send_jet_request(&mut client, &connect_request(uuid, Some(SPOOFED_CLIENT_ADDR)));
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    assert_eq!(
+        read_client_address(&mut server),
+        client.local_addr().unwrap().to_string()
+    );
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    // A client that isn't a peer can't claim to be forwarded to choose its address
+    let mut server = connect_to_proxy(SECOND_INSTANCE_ADDR);
+    let uuid = accept_on_second_instance(&mut server);
+
+    let mut client = TcpBuilder::new_v4()
+        .unwrap()
+        .bind("127.0.0.2:0")
+        .unwrap()
+        .connect(SECOND_INSTANCE_ADDR)
+        .unwrap();
+    let response = send_jet_request(&mut client, &connect_request(uuid, Some(SPOOFED_CLIENT_ADDR)));
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    assert_eq!(
+        read_client_address(&mut server),
+        client.local_addr().unwrap().to_string()
+    );
+}
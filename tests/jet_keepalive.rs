@@ -0,0 +1,106 @@
+mod common;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use common::{connect_to_proxy, run_proxy_with_args};
+
+const PROXY_ADDR: &str = "127.0.0.1:8078";
+const SERVER_DATA: &str = "Server Response";
+
+fn send_packet(stream: &mut TcpStream, jet_packet: &JetPacket) {
+    let mut v: Vec<u8> = Vec::new();
+    jet_packet.write_to(&mut v).unwrap();
+    stream.write_all(&v).unwrap();
+    stream.flush().unwrap();
+}
+
+/// Reads exactly one JetPacket, leaving the following bytes in the socket.
+fn read_packet(stream: &mut TcpStream) -> JetPacket {
+    let mut data = vec![0u8; jet_proto::JET_MSG_HEADER_SIZE as usize];
+    stream.read_exact(&mut data).unwrap();
+    let size = (&data[4..6]).read_u16::<BigEndian>().unwrap() as usize;
+    data.resize(size, 0);
+    stream
+        .read_exact(&mut data[jet_proto::JET_MSG_HEADER_SIZE as usize..])
+        .unwrap();
+    JetPacket::read_from(&mut data.as_slice()).unwrap()
+}
+
+fn accept_with_keepalive(server: &mut TcpStream) -> JetPacket {
+    let mut accept = JetPacket::new(0, 0);
+    accept.set_method(Some(JetMethod::ACCEPT));
+    accept.set_version(Some(2));
+    accept.set_keepalive(Some(true));
+    send_packet(server, &accept);
+    read_packet(server)
+}
+
+fn pong() -> JetPacket {
+    let mut pong = JetPacket::new(0, 0);
+    pong.set_method(Some(JetMethod::PONG));
+    pong
+}
+
+#[test]
+fn keepalive() {
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        None,
+        None,
+        &["--keepalive_interval", "1", "--keepalive_max_missed_pongs", "2"],
+    );
+
+    // A ping sent before the request is answered
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut ping = JetPacket::new(0, 0);
+    ping.set_method(Some(JetMethod::PING));
+    send_packet(&mut server, &ping);
+    assert!(read_packet(&mut server).is_pong());
+
+    let response = accept_with_keepalive(&mut server);
+    assert_eq!(response.keepalive_interval(), Some(1));
+    let uuid = response.association().unwrap();
+
+    // The parked connection is pinged and can still be used after answering
+    for _ in 0..2 {
+        assert!(read_packet(&mut server).is_ping());
+        send_packet(&mut server, &pong());
+    }
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let mut connect = JetPacket::new(0, 0);
+    connect.set_method(Some(JetMethod::CONNECT));
+    connect.set_version(Some(2));
+    connect.set_association(Some(uuid));
+    send_packet(&mut client, &connect);
+    assert_eq!(
+        read_packet(&mut client).response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+
+    server.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; SERVER_DATA.len()];
+    client.read_exact(&mut data).unwrap();
+    assert_eq!(data, SERVER_DATA.as_bytes());
+
+    // A server that doesn't answer is removed and its association expires
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let uuid = accept_with_keepalive(&mut server).association().unwrap();
+    assert!(read_packet(&mut server).is_ping());
+    let mut buffer = [0u8; 1024];
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(server.read(&mut buffer).unwrap(), 0);
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    connect.set_association(Some(uuid));
+    send_packet(&mut client, &connect);
+    assert_eq!(
+        read_packet(&mut client).response_status_code(),
+        Some(&ResponseStatusCode::StatusCode400)
+    );
+}
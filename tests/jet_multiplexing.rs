@@ -0,0 +1,161 @@
+mod common;
+
+use jet_proto::mux::{MuxFrame, MuxFrameType};
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use common::{connect_to_proxy, run_proxy, send_jet_request};
+
+const PROXY_ADDR: &str = "127.0.0.1:8077";
+const CONCURRENT_PROXY_ADDR: &str = "127.0.0.1:8118";
+
+fn encode(frame: &MuxFrame) -> Vec<u8> {
+    let mut v: Vec<u8> = Vec::new();
+    frame.write_to(&mut v).unwrap();
+    v
+}
+
+fn send_frame(stream: &mut TcpStream, frame: &MuxFrame) -> Vec<u8> {
+    let v = encode(frame);
+    stream.write_all(&v).unwrap();
+    v
+}
+
+fn read_exact(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).unwrap();
+    data
+}
+
+/// Parks a multiplexed server connection and hands it to a client. Returns the server and client connections.
+fn start_multiplexed_session(proxy_addr: &str) -> (TcpStream, TcpStream) {
+    let mut server = connect_to_proxy(proxy_addr);
+    let mut accept = JetPacket::new(0, 0);
+    accept.set_method(Some(JetMethod::ACCEPT));
+    accept.set_version(Some(2));
+    accept.set_multiplexed(Some(true));
+    let uuid = send_jet_request(&mut server, &accept).association().unwrap();
+
+    let mut client = connect_to_proxy(proxy_addr);
+    let mut connect = JetPacket::new(0, 0);
+    connect.set_method(Some(JetMethod::CONNECT));
+    connect.set_version(Some(2));
+    connect.set_association(Some(uuid));
+    connect.set_multiplexed(Some(true));
+    let response = send_jet_request(&mut client, &connect);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    (server, client)
+}
+
+#[test]
+fn multiplexed_session() {
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(PROXY_ADDR, None, None);
+
+    // Server (method = Accept) parks a multiplexed connection
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut accept = JetPacket::new(0, 0);
+    accept.set_method(Some(JetMethod::ACCEPT));
+    accept.set_version(Some(2));
+    accept.set_multiplexed(Some(true));
+    let response = send_jet_request(&mut server, &accept);
+    assert!(response.multiplexed());
+    let uuid = response.association().unwrap();
+
+    // A client that doesn't use multiplexing can't take it
+    let mut connect = JetPacket::new(0, 0);
+    connect.set_method(Some(JetMethod::CONNECT));
+    connect.set_version(Some(2));
+    connect.set_association(Some(uuid));
+    let response = send_jet_request(&mut connect_to_proxy(PROXY_ADDR), &connect);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode400)
+    );
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    connect.set_multiplexed(Some(true));
+    let response = send_jet_request(&mut client, &connect);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    assert!(response.multiplexed());
+
+    // Frames are forwarded unchanged in both directions
+    let sent = send_frame(&mut client, &MuxFrame::new(1, MuxFrameType::Open, b"rdp".to_vec()));
+    assert_eq!(read_exact(&mut server, sent.len()), sent);
+    let sent = send_frame(
+        &mut client,
+        &MuxFrame::new(1, MuxFrameType::Data, b"Client Request".to_vec()),
+    );
+    assert_eq!(read_exact(&mut server, sent.len()), sent);
+    let sent = send_frame(
+        &mut server,
+        &MuxFrame::new(1, MuxFrameType::Data, b"Server Response".to_vec()),
+    );
+    assert_eq!(read_exact(&mut client, sent.len()), sent);
+
+    // After the client closed the channel, the server can still send its last data until it closes it too
+    let sent = send_frame(&mut client, &MuxFrame::new(1, MuxFrameType::Close, Vec::new()));
+    assert_eq!(read_exact(&mut server, sent.len()), sent);
+    let sent = send_frame(
+        &mut server,
+        &MuxFrame::new(1, MuxFrameType::Data, b"Server Response".to_vec()),
+    );
+    assert_eq!(read_exact(&mut client, sent.len()), sent);
+    let sent = send_frame(&mut server, &MuxFrame::window_update(1, 1024));
+    assert_eq!(read_exact(&mut client, sent.len()), sent);
+    let sent = send_frame(&mut server, &MuxFrame::new(1, MuxFrameType::Close, Vec::new()));
+    assert_eq!(read_exact(&mut client, sent.len()), sent);
+
+    // Data on a channel that is not open ends the session
+    send_frame(
+        &mut client,
+        &MuxFrame::new(2, MuxFrameType::Data, b"Client Request".to_vec()),
+    );
+    let mut buffer = [0u8; 1024];
+    server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(server.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn channels_opened_at_the_same_time() {
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy(CONCURRENT_PROXY_ADDR, None, None);
+    let (mut server, mut client) = start_multiplexed_session(CONCURRENT_PROXY_ADDR);
+
+    // The server opens even channels and the client odd ones, so they can open channels at the same time
+    let server_open = send_frame(&mut server, &MuxFrame::new(2, MuxFrameType::Open, b"server".to_vec()));
+    let client_open = send_frame(&mut client, &MuxFrame::new(1, MuxFrameType::Open, b"client".to_vec()));
+    assert_eq!(read_exact(&mut client, server_open.len()), server_open);
+    assert_eq!(read_exact(&mut server, client_open.len()), client_open);
+
+    // The client opening an ID of the server only gets that channel closed, the server channel is opened
+    let server_open = send_frame(&mut server, &MuxFrame::new(4, MuxFrameType::Open, Vec::new()));
+    send_frame(&mut client, &MuxFrame::new(4, MuxFrameType::Open, Vec::new()));
+    let rejection = encode(&MuxFrame::new(4, MuxFrameType::Close, Vec::new()));
+    let received = read_exact(&mut client, server_open.len() + rejection.len());
+    let (first, second) = received.split_at(server_open.len());
+    assert!(
+        (first == server_open.as_slice() && second == rejection.as_slice())
+            || (first == rejection.as_slice() && second == server_open.as_slice())
+    );
+
+    // The session goes on, the rejected Open frame was not forwarded to the server
+    let sent = send_frame(
+        &mut client,
+        &MuxFrame::new(1, MuxFrameType::Data, b"Client Request".to_vec()),
+    );
+    assert_eq!(read_exact(&mut server, sent.len()), sent);
+    let sent = send_frame(
+        &mut server,
+        &MuxFrame::new(4, MuxFrameType::Data, b"Server Response".to_vec()),
+    );
+    assert_eq!(read_exact(&mut client, sent.len()), sent);
+}
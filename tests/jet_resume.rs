@@ -0,0 +1,108 @@
+mod common;
+
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use uuid::Uuid;
+
+use common::{connect_to_proxy, run_proxy_with_args};
+
+const PROXY_ADDR: &str = "127.0.0.1:8076";
+const SERVER_DATA: &str = "Server Response";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Sends a request and returns the response with the data received after it.
+fn send_jet_request(stream: &mut TcpStream, jet_packet: &JetPacket) -> (JetPacket, Vec<u8>) {
+    let mut v: Vec<u8> = Vec::new();
+    jet_packet.write_to(&mut v).unwrap();
+    stream.write_all(&v).unwrap();
+    stream.flush().unwrap();
+
+    let mut buffer = [0u8; 1024];
+    let n = stream.read(&mut buffer).unwrap();
+    let mut received: &[u8] = &buffer[..n];
+    let response = JetPacket::read_from(&mut received).unwrap();
+    (response, received.to_vec())
+}
+
+fn read_exact(stream: &mut TcpStream, mut data: Vec<u8>, len: usize) -> Vec<u8> {
+    let mut buffer = [0u8; 1024];
+    while data.len() < len {
+        let n = stream.read(&mut buffer).unwrap();
+        assert_ne!(n, 0);
+        data.extend_from_slice(&buffer[..n]);
+    }
+    data
+}
+
+#[test]
+fn resume_client() {
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(PROXY_ADDR, None, None, &["--resume_grace_period", "10"]);
+
+    // Server (method = Accept) asks for a resumable session
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut accept = JetPacket::new(0, 0);
+    accept.set_method(Some(JetMethod::ACCEPT));
+    accept.set_version(Some(2));
+    accept.set_resumable(Some(true));
+    let (response, _) = send_jet_request(&mut server, &accept);
+    assert!(response.resume_token().is_some());
+    let uuid = response.association().unwrap();
+
+    // Client (method = Connect) asks for a resumable session too
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let mut connect = JetPacket::new(0, 0);
+    connect.set_method(Some(JetMethod::CONNECT));
+    connect.set_version(Some(2));
+    connect.set_association(Some(uuid));
+    connect.set_resumable(Some(true));
+    let (response, _) = send_jet_request(&mut client, &connect);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    let client_token = response.resume_token().unwrap();
+
+    server.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let data = read_exact(&mut client, Vec::new(), SERVER_DATA.len());
+    assert_eq!(data, SERVER_DATA.as_bytes());
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let data = read_exact(&mut server, Vec::new(), CLIENT_DATA.len());
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    // An unknown token is refused
+    let mut resume = JetPacket::new(0, 0);
+    resume.set_method(Some(JetMethod::RESUME));
+    resume.set_association(Some(uuid));
+    resume.set_resume_token(Some(Uuid::new_v4()));
+    resume.set_sequence(Some(0));
+    let (response, _) = send_jet_request(&mut connect_to_proxy(PROXY_ADDR), &resume);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode400)
+    );
+
+    // The client reconnects, pretending it didn't receive the server data. The proxy tells how many bytes it received
+    // from the client and sends the server data again.
+    let mut resumed_client = connect_to_proxy(PROXY_ADDR);
+    resume.set_resume_token(Some(client_token));
+    let (response, data) = send_jet_request(&mut resumed_client, &resume);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+    assert_eq!(response.sequence(), Some(CLIENT_DATA.len() as u64));
+    let data = read_exact(&mut resumed_client, data, SERVER_DATA.len());
+    assert_eq!(data, SERVER_DATA.as_bytes());
+
+    // The session continues on the new connection
+    server.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let data = read_exact(&mut resumed_client, Vec::new(), SERVER_DATA.len());
+    assert_eq!(data, SERVER_DATA.as_bytes());
+
+    resumed_client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let data = read_exact(&mut server, Vec::new(), CLIENT_DATA.len());
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+}
@@ -0,0 +1,40 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use common::{connect_to_proxy, run_proxy_with_args};
+
+const PROXY_ADDR: &str = "127.0.0.1:8087";
+const DOWN_SERVER: &str = "tcp://127.0.0.1:8088";
+const UP_SERVER_ADDR: &str = "127.0.0.1:8089";
+const CLIENT_DATA: &str = "Client Request";
+
+#[test]
+fn failover() {
+    let up_server = TcpListener::bind(UP_SERVER_ADDR).unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        Some(DOWN_SERVER),
+        None,
+        &[
+            "--routing_url",
+            &format!("tcp://{}", UP_SERVER_ADDR),
+            "--load_balancing",
+            "first-available",
+        ],
+    );
+
+    // Every session reaches the target that is up, the other one is out of rotation after the first failure
+    for _ in 0..3 {
+        let mut client = connect_to_proxy(PROXY_ADDR);
+        client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+
+        let (mut server, _) = up_server.accept().unwrap();
+        let mut data = vec![0u8; CLIENT_DATA.len()];
+        server.read_exact(&mut data).unwrap();
+        assert_eq!(data, CLIENT_DATA.as_bytes());
+    }
+}
@@ -0,0 +1,63 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use common::{connect_to_proxy, run_proxy_with_args, run_proxy_with_listener};
+
+const SERVER_DATA: &str = "Server Response";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Relays a connection to `server_addr` through an instance listening with the `proxy_scheme` listener, and exchanges
+/// data in both directions.
+fn relay_through_proxy(proxy_scheme: &str, proxy_addr: &str, relay_addr: &str, server_addr: &str) {
+    let server = TcpListener::bind(server_addr).unwrap();
+
+    //Spawn the outbound proxy and wait for it to come online
+    let _outbound_proxy = run_proxy_with_listener(
+        &format!("{}://{}", proxy_scheme, proxy_addr),
+        None,
+        None,
+        &[
+            "--proxy_listener_allow",
+            server_addr,
+            "--proxy_listener_user",
+            "alice:secret",
+        ],
+    );
+    drop(connect_to_proxy(proxy_addr));
+
+    let upstream_proxy = match proxy_scheme {
+        "socks5" => format!("socks5://alice:secret@{}", proxy_addr),
+        _ => format!("http://alice:secret@{}", proxy_addr),
+    };
+    let _relay = run_proxy_with_args(
+        relay_addr,
+        Some(&format!("tcp://{}", server_addr)),
+        None,
+        &["--upstream_proxy", &upstream_proxy],
+    );
+
+    let mut client = connect_to_proxy(relay_addr);
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let (mut server_stream, _) = server.accept().unwrap();
+
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server_stream.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    server_stream.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; SERVER_DATA.len()];
+    client.read_exact(&mut data).unwrap();
+    assert_eq!(data, SERVER_DATA.as_bytes());
+}
+
+#[test]
+fn routing_url_through_socks5_proxy() {
+    relay_through_proxy("socks5", "127.0.0.1:8098", "127.0.0.1:8099", "127.0.0.1:8100");
+}
+
+#[test]
+fn routing_url_through_http_connect_proxy() {
+    relay_through_proxy("http-connect", "127.0.0.1:8101", "127.0.0.1:8102", "127.0.0.1:8103");
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use common::{connect_to_proxy, run_proxy_with_args};
+
+const PROXY_ADDR: &str = "127.0.0.1:8106";
+const ROUTING_ADDR: &str = "127.0.0.1:8107";
+const PCAPNG_PROXY_ADDR: &str = "127.0.0.1:8108";
+const PCAPNG_ROUTING_ADDR: &str = "127.0.0.1:8109";
+const ROTATION_PROXY_ADDR: &str = "127.0.0.1:8110";
+const ROTATION_ROUTING_ADDR: &str = "127.0.0.1:8111";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Captures are written by the interceptor thread, after the data is relayed
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    for _ in 0..500 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn every_session_gets_its_own_capture() {
+    let server = TcpListener::bind(ROUTING_ADDR).unwrap();
+    let captures = tempfile::tempdir().expect("Failed to create a temporary directory");
+    let template = captures.path().join("{date}").join("{session_id}_{client_port}.pcap");
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        Some(&format!("tcp://{}", ROUTING_ADDR)),
+        None,
+        &["--pcap_file", template.to_str().unwrap()],
+    );
+
+    for _ in 0..2 {
+        let mut client = connect_to_proxy(PROXY_ADDR);
+        client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+
+        let (mut routed, _) = server.accept().unwrap();
+        let mut data = vec![0u8; CLIENT_DATA.len()];
+        routed.read_exact(&mut data).unwrap();
+        assert_eq!(data, CLIENT_DATA.as_bytes());
+    }
+
+    // Both captures are in the directory of the day
+    let days = fs::read_dir(captures.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(days.len(), 1);
+    let sessions = fs::read_dir(&days[0]).unwrap().count();
+    assert_eq!(sessions, 2);
+}
+
+#[test]
+fn pcapng_captures_describe_the_session() {
+    let server = TcpListener::bind(PCAPNG_ROUTING_ADDR).unwrap();
+    let capture = tempfile::NamedTempFile::new().expect("Failed to create a named temporary file");
+    let routing_url = format!("tcp://{}", PCAPNG_ROUTING_ADDR);
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PCAPNG_PROXY_ADDR,
+        Some(&routing_url),
+        None,
+        &[
+            "--pcap_file",
+            capture.path().to_str().unwrap(),
+            "--pcap_shared",
+            "--pcap_format",
+            "pcapng",
+        ],
+    );
+
+    let mut client = connect_to_proxy(PCAPNG_PROXY_ADDR);
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let (mut routed, _) = server.accept().unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    routed.read_exact(&mut data).unwrap();
+
+    let contains = |needle: &[u8]| {
+        let capture = fs::read(capture.path()).unwrap();
+        capture.windows(needle.len()).any(|window| window == needle)
+    };
+    assert!(wait_until(|| contains(CLIENT_DATA.as_bytes())));
+    assert_eq!(fs::read(capture.path()).unwrap()[..4], [0x0A, 0x0D, 0x0D, 0x0A]);
+    assert!(contains(format!("listener=tcp://{}", PCAPNG_PROXY_ADDR).as_bytes()));
+    assert!(contains(format!("route={}", routing_url).as_bytes()));
+    assert!(contains(b"Client leg of session"));
+}
+
+#[test]
+fn captures_are_rotated_between_messages() {
+    let server = TcpListener::bind(ROTATION_ROUTING_ADDR).unwrap();
+    let captures = tempfile::tempdir().expect("Failed to create a temporary directory");
+    let capture = captures.path().join("capture.pcap");
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        ROTATION_PROXY_ADDR,
+        Some(&format!("tcp://{}", ROTATION_ROUTING_ADDR)),
+        None,
+        &["--pcap_file", capture.to_str().unwrap(), "--pcap_rotate_interval", "0"],
+    );
+
+    let mut client = connect_to_proxy(ROTATION_PROXY_ADDR);
+    let (mut routed, _) = server.accept().unwrap();
+    for _ in 0..2 {
+        client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+        let mut data = vec![0u8; CLIENT_DATA.len()];
+        routed.read_exact(&mut data).unwrap();
+    }
+
+    // Every message starts a new file, the rotated ones hold the data
+    let rotated = || {
+        fs::read_dir(captures.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| *path != capture)
+            .collect::<Vec<_>>()
+    };
+    assert!(wait_until(|| rotated().len() == 2));
+    let rotated = rotated();
+    assert!(rotated.iter().all(|path| path.extension().unwrap() == "pcap"));
+    assert!(rotated.iter().any(|path| {
+        let data = fs::read(path).unwrap();
+        data.windows(CLIENT_DATA.len())
+            .any(|window| window == CLIENT_DATA.as_bytes())
+    }));
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use jet_proto::{JetMethod, JetPacket, ResponseStatusCode};
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+use common::{connect_to_proxy, run_proxy_with_listener, send_jet_request};
+
+const PROXY_ADDR: &str = "127.0.0.1:8079";
+const TLS_SERVER_ADDR: &str = "127.0.0.1:8083";
+const CLIENT_DATA: &str = "Client Request";
+
+// Beginning of a ClientHello for "jet", the proxy only looks at the first record
+const CLIENT_HELLO: [u8; 64] = [
+    0x16, 0x03, 0x01, 0x00, 0x3B, 0x01, 0x00, 0x00, 0x37, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x08, 0x00,
+    0x06, 0x00, 0x00, 0x03, b'j', b'e', b't',
+];
+
+#[test]
+fn single_port() {
+    let tls_server = TcpListener::bind(TLS_SERVER_ADDR).unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_listener(
+        &format!("sniff://{}", PROXY_ADDR),
+        None,
+        None,
+        &["--tls_forward_url", &format!("tcp://{}", TLS_SERVER_ADDR)],
+    );
+
+    // JET messages are handled by the JET client
+    let mut server = connect_to_proxy(PROXY_ADDR);
+    let mut accept = JetPacket::new(0, 0);
+    accept.set_method(Some(JetMethod::ACCEPT));
+    accept.set_version(Some(2));
+    let uuid = send_jet_request(&mut server, &accept).association().unwrap();
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    let mut connect = JetPacket::new(0, 0);
+    connect.set_method(Some(JetMethod::CONNECT));
+    connect.set_version(Some(2));
+    connect.set_association(Some(uuid));
+    let response = send_jet_request(&mut client, &connect);
+    assert_eq!(
+        response.response_status_code(),
+        Some(&ResponseStatusCode::StatusCode200)
+    );
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    // TLS connections are forwarded without being terminated
+    let mut tls_client = connect_to_proxy(PROXY_ADDR);
+    tls_client.write_all(&CLIENT_HELLO).unwrap();
+    let (mut tls_server_stream, _) = tls_server.accept().unwrap();
+    let mut data = vec![0u8; CLIENT_HELLO.len()];
+    tls_server_stream.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_HELLO.as_ref());
+
+    // Anything else is rejected
+    let mut garbage_client = connect_to_proxy(PROXY_ADDR);
+    garbage_client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    garbage_client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut buffer = [0u8; 1024];
+    assert_eq!(garbage_client.read(&mut buffer).unwrap(), 0);
+}
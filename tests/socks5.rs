@@ -0,0 +1,70 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use common::{connect_to_proxy, run_proxy_with_listener};
+
+const PROXY_ADDR: &str = "127.0.0.1:8092";
+const SERVER_ADDR: &str = "127.0.0.1:8093";
+const SERVER_PORT: u16 = 8093;
+const DENIED_PORT: u16 = 8094;
+const SERVER_DATA: &str = "Server Response";
+const CLIENT_DATA: &str = "Client Request";
+
+/// Authenticates as alice and requests a connection to 127.0.0.1:<port>. Returns the reply code.
+fn request_connection(stream: &mut TcpStream, port: u16) -> u8 {
+    stream.write_all(&[0x05, 0x01, 0x02]).unwrap();
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).unwrap();
+    assert_eq!(method, [0x05, 0x02]);
+
+    stream.write_all(b"\x01\x05alice\x06secret").unwrap();
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).unwrap();
+    assert_eq!(status, [0x01, 0x00]);
+
+    let port = port.to_be_bytes();
+    stream
+        .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]])
+        .unwrap();
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+#[test]
+fn socks5_listener() {
+    let server = TcpListener::bind(SERVER_ADDR).unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_listener(
+        &format!("socks5://{}", PROXY_ADDR),
+        None,
+        None,
+        &[
+            "--proxy_listener_allow",
+            SERVER_ADDR,
+            "--proxy_listener_user",
+            "alice:secret",
+        ],
+    );
+
+    // Destinations that are not allowed are refused by the ruleset
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    assert_eq!(request_connection(&mut client, DENIED_PORT), 0x02);
+
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    assert_eq!(request_connection(&mut client, SERVER_PORT), 0x00);
+    let (mut server_stream, _) = server.accept().unwrap();
+
+    client.write_all(CLIENT_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; CLIENT_DATA.len()];
+    server_stream.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_DATA.as_bytes());
+
+    server_stream.write_all(SERVER_DATA.as_bytes()).unwrap();
+    let mut data = vec![0u8; SERVER_DATA.len()];
+    client.read_exact(&mut data).unwrap();
+    assert_eq!(data, SERVER_DATA.as_bytes());
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::run_proxy_with_args;
+
+const PROXY_ADDR: &str = "127.0.0.1:8104";
+const ROUTING_ADDR: &str = "127.0.0.1:8105";
+const CLIENT_DATA: &str = "Client Request";
+
+#[test]
+fn denied_clients_are_closed_before_routing() {
+    let server = TcpListener::bind(ROUTING_ADDR).unwrap();
+    server.set_nonblocking(true).unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        Some(&format!("tcp://{}", ROUTING_ADDR)),
+        None,
+        &["--source_allow", "0.0.0.0/0", "--source_deny", "127.0.0.0/8"],
+    );
+
+    let mut client = loop {
+        match TcpStream::connect(PROXY_ADDR) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // The connection is closed without being relayed
+    let _ = client.write_all(CLIENT_DATA.as_bytes());
+    let mut buffer = [0u8; 16];
+    match client.read(&mut buffer) {
+        Ok(0) => {}
+        Ok(n) => panic!("{} bytes received from a denied connection", n),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            panic!("Denied connection still open")
+        }
+        Err(_) => {}
+    }
+
+    match server.accept() {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+        _ => panic!("Denied connection relayed to the routing url"),
+    }
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+
+use common::{connect_to_proxy, run_proxy_with_args};
+
+const PROXY_ADDR: &str = "127.0.0.1:8084";
+const ROUTED_SERVER_ADDR: &str = "127.0.0.1:8085";
+const JET_SERVER: &str = "tls://127.0.0.1:8086";
+
+// Beginning of a ClientHello for "jet", the proxy only looks at the first record
+const CLIENT_HELLO: [u8; 64] = [
+    0x16, 0x03, 0x01, 0x00, 0x3B, 0x01, 0x00, 0x00, 0x37, 0x03, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00, 0x00, 0x0C, 0x00, 0x00, 0x00, 0x08, 0x00,
+    0x06, 0x00, 0x00, 0x03, b'j', b'e', b't',
+];
+
+#[test]
+fn passthrough_route() {
+    let routed_server = TcpListener::bind(ROUTED_SERVER_ADDR).unwrap();
+
+    let mut routes_file = tempfile::NamedTempFile::new().expect("Failed to create a named temporary file");
+    write!(
+        routes_file,
+        r#"[{{ "server_name": "JET", "destination": "tcp://{}", "passthrough": true }}]"#,
+        ROUTED_SERVER_ADDR
+    )
+    .unwrap();
+
+    //Spawn our proxy and wait for it to come online
+    let _proxy = run_proxy_with_args(
+        PROXY_ADDR,
+        Some(JET_SERVER),
+        None,
+        &[
+            "--tls_routes_file",
+            routes_file
+                .path()
+                .to_str()
+                .expect("Failed to get path to a temporary file"),
+        ],
+    );
+
+    // The ClientHello is forwarded unchanged to the destination of its server name
+    let mut client = connect_to_proxy(PROXY_ADDR);
+    client.write_all(&CLIENT_HELLO).unwrap();
+    let (mut server, _) = routed_server.accept().unwrap();
+    let mut data = vec![0u8; CLIENT_HELLO.len()];
+    server.read_exact(&mut data).unwrap();
+    assert_eq!(data, CLIENT_HELLO.as_ref());
+}